@@ -6,17 +6,31 @@
 //!
 //! Intel 64 and IA-32 Architectures Optimization Reference Manual
 //! Example 2-1. Class 0 Pseudo-code Snippet
+//!
+//! Runs the snippet on the CPU given as the first argument (default: 0) and
+//! asserts that ITD classifies it as class 0.
+
+use std::{arch::asm, io, time::Duration};
 
-use std::arch::asm;
+use intel_hfi::classify;
 
-fn main() {
-    loop {
-        unsafe {
+fn main() -> io::Result<()> {
+    let cpu = std::env::args()
+        .nth(1)
+        .map_or(0, |cpu| cpu.parse().expect("invalid CPU number"));
+    let options = classify::Options::default();
+    let histogram = classify::classify(cpu, &options, || {
+        classify::repeat_for(Duration::from_secs(5), || unsafe {
             asm!("xor {x}, {x}",
                     "add {x}, 5",
                     "inc {x}",
                     x = out(reg) _,
             );
-        }
-    }
+        })
+    })?;
+
+    println!("ITD Classes (CPU {cpu}):");
+    println!("{histogram}");
+    assert_eq!(histogram.dominant(), Some(0));
+    Ok(())
 }
@@ -6,12 +6,21 @@
 //!
 //! Intel 64 and IA-32 Architectures Optimization Reference Manual
 //! Example 2-2. Class 1 Pseudo-code Snippet
+//!
+//! Runs the snippet on the CPU given as the first argument (default: 0) and
+//! asserts that ITD classifies it as class 1.
+
+use std::{arch::asm, io, time::Duration};
 
-use std::arch::asm;
+use intel_hfi::classify;
 
-fn main() {
-    loop {
-        unsafe {
+fn main() -> io::Result<()> {
+    let cpu = std::env::args()
+        .nth(1)
+        .map_or(0, |cpu| cpu.parse().expect("invalid CPU number"));
+    let options = classify::Options::default();
+    let histogram = classify::classify(cpu, &options, || {
+        classify::repeat_for(Duration::from_secs(5), || unsafe {
             let x1 = 0;
             let x2 = 0;
             let x3 = 0;
@@ -44,6 +53,11 @@ fn main() {
                     x9 = in(ymm_reg) x9,
                     x10 = in(ymm_reg) x10,
             );
-        }
-    }
+        })
+    })?;
+
+    println!("ITD Classes (CPU {cpu}):");
+    println!("{histogram}");
+    assert_eq!(histogram.dominant(), Some(1));
+    Ok(())
 }
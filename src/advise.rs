@@ -0,0 +1,476 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Capability-ranked CPU placement
+
+use std::{cmp::Ordering, collections::BTreeMap, fmt, io, str::FromStr};
+
+use crate::{
+    cpuid::CoreType,
+    ehfi::{EhfiTable, NUM_CLASSES},
+    hfi::{HfiInfo, HfiTable},
+    itd::ItdInfo,
+    topology::CpuTopology,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Objective {
+    Performance,
+    Efficiency,
+    /// Weighted mix with the given weight of performance in `0.0..=1.0`
+    Mix(f64),
+}
+
+impl Objective {
+    pub fn score(&self, capability: &Capability) -> f64 {
+        let perf = capability.perf as f64;
+        let ee = capability.ee as f64;
+        match self {
+            Self::Performance => perf,
+            Self::Efficiency => ee,
+            Self::Mix(weight) => weight * perf + (1.0 - weight) * ee,
+        }
+    }
+
+    /// Capability used to break ties in the score
+    fn tie_breaker(&self, capability: &Capability) -> u8 {
+        match self {
+            Self::Performance => capability.ee,
+            Self::Efficiency | Self::Mix(_) => capability.perf,
+        }
+    }
+}
+
+impl FromStr for Objective {
+    type Err = String;
+
+    /// Parses `perf`, `ee` or `mix:<weight of performance>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "perf" | "performance" => Ok(Self::Performance),
+            "ee" | "efficiency" => Ok(Self::Efficiency),
+            _ => {
+                let weight = s
+                    .strip_prefix("mix:")
+                    .and_then(|weight| weight.parse::<f64>().ok())
+                    .filter(|weight| (0.0..=1.0).contains(weight))
+                    .ok_or_else(|| format!("invalid objective: {s}"))?;
+                Ok(Self::Mix(weight))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Objective {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Performance => write!(f, "perf"),
+            Self::Efficiency => write!(f, "ee"),
+            Self::Mix(weight) => write!(f, "mix:{weight}"),
+        }
+    }
+}
+
+/// How SMT siblings are ordered in the ranking
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Smt {
+    /// Rank one CPU of every core before any of their siblings.
+    #[default]
+    Spread,
+    /// Keep the siblings of a core next to each other.
+    Pack,
+}
+
+/// Performance and energy efficiency capability of a CPU
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capability {
+    pub cpu: usize,
+    pub perf: u8,
+    pub ee: u8,
+}
+
+impl Capability {
+    /// A capability of 0 requests the OS not to use the CPU.
+    pub fn is_zero(&self) -> bool {
+        self.perf == 0 || self.ee == 0
+    }
+}
+
+/// Reads the HFI row of every present CPU, or the EHFI row for `class`.
+pub fn read_capabilities<const NUM_CPUS: usize>(
+    hfi_info: &HfiInfo,
+    class: Option<usize>,
+) -> io::Result<Vec<Capability>> {
+    let cpus = hfi_info.present_cpus(NUM_CPUS)?;
+    match class {
+        None => {
+            let mut table = HfiTable::<NUM_CPUS>::new();
+            table.read(hfi_info)?;
+            let entries = table.entries;
+            Ok(cpus
+                .into_iter()
+                .map(|cpu| Capability {
+                    cpu,
+                    perf: entries[cpu].perf_cap(),
+                    ee: entries[cpu].ee_cap(),
+                })
+                .collect())
+        }
+        Some(class) => {
+            if class >= NUM_CLASSES {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("ITD class {class} is out of range"),
+                ));
+            }
+            if !hfi_info.has_itd() || !ItdInfo::new(hfi_info).itd_enabled() {
+                return Err(io::Error::other("EHFI capability is not enabled"));
+            }
+            let mut table = EhfiTable::<NUM_CPUS>::new();
+            table.read(hfi_info)?;
+            Ok(cpus
+                .into_iter()
+                .map(|cpu| Capability {
+                    cpu,
+                    perf: table.entries[cpu].perf_cap(class),
+                    ee: table.entries[cpu].ee_cap(class),
+                })
+                .collect())
+        }
+    }
+}
+
+/// Criteria for choosing a set of CPUs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Selection {
+    pub objective: Objective,
+    /// ITD class whose EHFI capabilities are used instead of HFI
+    pub class: Option<usize>,
+    pub count: usize,
+    pub smt: Smt,
+    /// Restricts the choice to CPUs of this core type
+    pub core_type: Option<CoreType>,
+}
+
+impl Default for Selection {
+    fn default() -> Self {
+        Self {
+            objective: Objective::Performance,
+            class: None,
+            count: 1,
+            smt: Smt::Spread,
+            core_type: None,
+        }
+    }
+}
+
+impl Selection {
+    /// Sets the criterion `key` (`prefer`, `class`, `count`, `smt` or `core`)
+    /// from `value`. Returns `false` if `key` is not a criterion.
+    pub fn parse_option(&mut self, key: &str, value: &str) -> Result<bool, String> {
+        let invalid = || format!("invalid {key}: {value}");
+        match key {
+            "prefer" => self.objective = value.parse()?,
+            "class" => self.class = Some(value.parse().map_err(|_| invalid())?),
+            "count" => self.count = value.parse().map_err(|_| invalid())?,
+            "smt" => {
+                self.smt = match value {
+                    "spread" => Smt::Spread,
+                    "pack" => Smt::Pack,
+                    _ => return Err(invalid()),
+                }
+            }
+            "core" => self.core_type = Some(value.parse()?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Returns the chosen CPUs sorted by CPU number.
+    pub fn select(&self, capabilities: &[Capability], topology: &[CpuTopology]) -> Vec<usize> {
+        let capabilities: Vec<Capability> = match self.core_type {
+            Some(core_type) => capabilities
+                .iter()
+                .filter(|c| {
+                    topology
+                        .iter()
+                        .any(|t| t.cpu == c.cpu && t.core_type == core_type)
+                })
+                .copied()
+                .collect(),
+            None => capabilities.to_vec(),
+        };
+        let mut cpus = rank(&capabilities, topology, self.objective, self.smt).best(self.count);
+        cpus.sort_unstable();
+        cpus
+    }
+
+    /// Selects CPUs from the current table.
+    pub fn read<const NUM_CPUS: usize>(
+        &self,
+        hfi_info: &HfiInfo,
+        topology: &[CpuTopology],
+    ) -> io::Result<Vec<usize>> {
+        let capabilities = read_capabilities::<NUM_CPUS>(hfi_info, self.class)?;
+        Ok(self.select(&capabilities, topology))
+    }
+}
+
+impl fmt::Display for Selection {
+    /// Formats the criteria as accepted by [`Selection::parse_option`].
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "prefer={},count={}", self.objective, self.count)?;
+        if let Some(class) = self.class {
+            write!(f, ",class={class}")?;
+        }
+        if self.smt == Smt::Pack {
+            write!(f, ",smt=pack")?;
+        }
+        match self.core_type {
+            Some(CoreType::Core) => write!(f, ",core=core"),
+            Some(CoreType::Atom) => write!(f, ",core=atom"),
+            Some(CoreType::Unknown) | None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RankedCpu {
+    pub capability: Capability,
+    pub score: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Advice {
+    /// Usable CPUs, best first
+    pub ranked: Vec<RankedCpu>,
+    /// CPUs with zero capability, which are never advised
+    pub excluded: Vec<usize>,
+}
+
+impl Advice {
+    /// Returns up to `count` of the best CPUs.
+    pub fn best(&self, count: usize) -> Vec<usize> {
+        self.ranked
+            .iter()
+            .take(count)
+            .map(|ranked| ranked.capability.cpu)
+            .collect()
+    }
+}
+
+impl fmt::Display for Advice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (rank, ranked) in self.ranked.iter().enumerate() {
+            let capability = &ranked.capability;
+            writeln!(
+                f,
+                "  #{rank}: CPU {} (score: {:.1}, perf: {}, ee: {})",
+                capability.cpu, ranked.score, capability.perf, capability.ee
+            )?;
+        }
+        write!(f, "  Excluded: {:?}", self.excluded)
+    }
+}
+
+/// Ranks `capabilities` by `objective`.
+///
+/// CPUs with zero capability are excluded. Equal scores are ordered by the
+/// other capability (energy efficiency for performance, performance
+/// otherwise) and then by the lower CPU number. CPUs missing from `topology`
+/// are treated as separate cores.
+pub fn rank(
+    capabilities: &[Capability],
+    topology: &[CpuTopology],
+    objective: Objective,
+    smt: Smt,
+) -> Advice {
+    let compare = |a: &RankedCpu, b: &RankedCpu| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| {
+                objective
+                    .tie_breaker(&b.capability)
+                    .cmp(&objective.tie_breaker(&a.capability))
+            })
+            .then_with(|| a.capability.cpu.cmp(&b.capability.cpu))
+    };
+
+    let mut advice = Advice::default();
+    let mut cores = BTreeMap::<(u32, u32, usize), Vec<RankedCpu>>::new();
+    for capability in capabilities {
+        if capability.is_zero() {
+            advice.excluded.push(capability.cpu);
+            continue;
+        }
+        let core = match topology.iter().find(|t| t.cpu == capability.cpu) {
+            Some(topology) => (topology.package, topology.core, 0),
+            None => (u32::MAX, u32::MAX, capability.cpu),
+        };
+        cores.entry(core).or_default().push(RankedCpu {
+            capability: *capability,
+            score: objective.score(capability),
+        });
+    }
+
+    let mut cores: Vec<Vec<RankedCpu>> = cores.into_values().collect();
+    for threads in &mut cores {
+        threads.sort_by(compare);
+    }
+    cores.sort_by(|a, b| compare(&a[0], &b[0]));
+
+    match smt {
+        Smt::Pack => advice.ranked = cores.into_iter().flatten().collect(),
+        Smt::Spread => {
+            let max_threads = cores.iter().map(Vec::len).max().unwrap_or(0);
+            for thread in 0..max_threads {
+                advice
+                    .ranked
+                    .extend(cores.iter().filter_map(|threads| threads.get(thread)));
+            }
+        }
+    }
+    advice.excluded.sort_unstable();
+    advice
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::install_fake;
+
+    fn capabilities(caps: &[(u8, u8)]) -> Vec<Capability> {
+        caps.iter()
+            .enumerate()
+            .map(|(cpu, (perf, ee))| Capability {
+                cpu,
+                perf: *perf,
+                ee: *ee,
+            })
+            .collect()
+    }
+
+    /// CPU 0-3 are two Core cores with two threads each, CPU 4-5 are Atom cores.
+    fn topology() -> Vec<CpuTopology> {
+        (0..6)
+            .map(|cpu| CpuTopology {
+                cpu,
+                package: 0,
+                core: match cpu < 4 {
+                    true => cpu as u32 / 2,
+                    false => cpu as u32,
+                },
+                core_type: match cpu < 4 {
+                    true => CoreType::Core,
+                    false => CoreType::Atom,
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn objectives() {
+        assert_eq!("perf".parse(), Ok(Objective::Performance));
+        assert_eq!("efficiency".parse(), Ok(Objective::Efficiency));
+        assert_eq!("mix:0.25".parse(), Ok(Objective::Mix(0.25)));
+        assert!("mix:1.5".parse::<Objective>().is_err());
+        assert!("fast".parse::<Objective>().is_err());
+        assert_eq!(Objective::Mix(0.25).to_string(), "mix:0.25");
+
+        let capability = Capability {
+            cpu: 0,
+            perf: 200,
+            ee: 100,
+        };
+        assert_eq!(Objective::Performance.score(&capability), 200.0);
+        assert_eq!(Objective::Efficiency.score(&capability), 100.0);
+        assert_eq!(Objective::Mix(0.25).score(&capability), 125.0);
+    }
+
+    #[test]
+    fn spread_and_pack() {
+        let caps = capabilities(&[
+            (200, 100),
+            (190, 100),
+            (210, 100),
+            (180, 100),
+            (120, 200),
+            (0, 200),
+        ]);
+        let advice = rank(&caps, &topology(), Objective::Performance, Smt::Spread);
+        assert_eq!(advice.best(6), [2, 0, 4, 3, 1]);
+        assert_eq!(advice.excluded, [5]);
+
+        let advice = rank(&caps, &topology(), Objective::Performance, Smt::Pack);
+        assert_eq!(advice.best(6), [2, 3, 0, 1, 4]);
+        assert_eq!(advice.best(2), [2, 3]);
+
+        let advice = rank(&caps, &topology(), Objective::Efficiency, Smt::Spread);
+        assert_eq!(advice.best(1), [4]);
+    }
+
+    #[test]
+    fn ties() {
+        // Equal performance is decided by efficiency, and then by CPU number.
+        let caps = capabilities(&[(200, 100), (200, 110), (200, 100)]);
+        let advice = rank(&caps, &[], Objective::Performance, Smt::Spread);
+        assert_eq!(advice.best(3), [1, 0, 2]);
+        assert_eq!(
+            advice.to_string(),
+            "  #0: CPU 1 (score: 200.0, perf: 200, ee: 110)\n  \
+             #1: CPU 0 (score: 200.0, perf: 200, ee: 100)\n  \
+             #2: CPU 2 (score: 200.0, perf: 200, ee: 100)\n  \
+             Excluded: []"
+        );
+    }
+
+    #[test]
+    fn selection() {
+        let mut selection = Selection::default();
+        for (key, value) in [("prefer", "ee"), ("count", "2"), ("smt", "pack")] {
+            assert_eq!(selection.parse_option(key, value), Ok(true));
+        }
+        assert_eq!(selection.parse_option("name", "nginx"), Ok(false));
+        assert!(selection.parse_option("smt", "tight").is_err());
+        assert!(selection.parse_option("count", "many").is_err());
+        assert_eq!(selection.to_string(), "prefer=ee,count=2,smt=pack");
+
+        let caps = capabilities(&[
+            (200, 100),
+            (190, 100),
+            (210, 100),
+            (180, 100),
+            (120, 200),
+            (130, 190),
+        ]);
+        assert_eq!(selection.select(&caps, &topology()), [4, 5]);
+
+        selection.parse_option("prefer", "perf").unwrap();
+        selection.parse_option("core", "atom").unwrap();
+        assert_eq!(selection.select(&caps, &topology()), [4, 5]);
+        selection.parse_option("count", "1").unwrap();
+        assert_eq!(selection.select(&caps, &topology()), [5]);
+        assert_eq!(
+            selection.to_string(),
+            "prefer=perf,count=1,smt=pack,core=atom"
+        );
+    }
+
+    #[test]
+    fn absent_cpus() {
+        install_fake();
+        let hfi_info = HfiInfo::new(0).unwrap();
+        // The table is wider than the 32 CPUs of the fake backend.
+        for class in [None, Some(0)] {
+            let caps = read_capabilities::<64>(&hfi_info, class).unwrap();
+            assert_eq!(caps.len(), 32);
+            assert!(caps.iter().enumerate().all(|(cpu, cap)| cap.cpu == cpu));
+        }
+        let topology = CpuTopology::read_present(64).unwrap();
+        assert_eq!(topology.len(), 32);
+        let caps = read_capabilities::<64>(&hfi_info, None).unwrap();
+        let advice = rank(&caps, &topology, Objective::Performance, Smt::Spread);
+        assert_eq!(advice.ranked.len() + advice.excluded.len(), 32);
+    }
+}
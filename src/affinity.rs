@@ -0,0 +1,89 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! CPU affinity of threads
+
+use std::{fs, io, path::Path};
+
+/// Returns the CPUs thread `tid` may run on. `tid` 0 is the calling thread.
+pub fn get(tid: i32) -> io::Result<Vec<usize>> {
+    let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+    let ret =
+        unsafe { libc::sched_getaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &mut set) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((0..libc::CPU_SETSIZE as usize)
+        .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) })
+        .collect())
+}
+
+/// Restricts thread `tid` to `cpus`. `tid` 0 is the calling thread.
+pub fn set(tid: i32, cpus: &[usize]) -> io::Result<()> {
+    let mut set = unsafe { std::mem::zeroed::<libc::cpu_set_t>() };
+    for cpu in cpus {
+        if *cpu >= libc::CPU_SETSIZE as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("CPU {cpu} is out of range"),
+            ));
+        }
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+    let ret = unsafe { libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns the thread IDs of process `pid` under `procfs`.
+pub fn threads(procfs: &Path, pid: i32) -> io::Result<Vec<i32>> {
+    let mut tids = Vec::new();
+    for entry in fs::read_dir(procfs.join(pid.to_string()).join("task"))? {
+        if let Ok(tid) = entry?.file_name().to_string_lossy().parse() {
+            tids.push(tid);
+        }
+    }
+    tids.sort_unstable();
+    Ok(tids)
+}
+
+/// Restricts every thread of process `pid` to `cpus`, skipping threads that
+/// exit in the meantime.
+pub fn set_process(procfs: &Path, pid: i32, cpus: &[usize]) -> io::Result<()> {
+    for tid in threads(procfs, pid)? {
+        match set(tid, cpus) {
+            Err(err) if err.raw_os_error() == Some(libc::ESRCH) => continue,
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn current_thread() {
+        let cpus = get(0).unwrap();
+        assert!(!cpus.is_empty());
+        set(0, &cpus).unwrap();
+        assert_eq!(get(0).unwrap(), cpus);
+        let err = set(0, &[libc::CPU_SETSIZE as usize]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn list_threads() {
+        let procfs = TempDir::new();
+        for tid in ["12", "3", "10"] {
+            procfs.write(&format!("3/task/{tid}/comm"), "worker\n");
+        }
+        procfs.write("3/task/self/comm", "");
+        assert_eq!(threads(procfs.path(), 3).unwrap(), [3, 10, 12]);
+        assert!(threads(procfs.path(), 4).is_err());
+    }
+}
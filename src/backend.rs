@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Hardware access backends
+
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::OnceLock,
+};
+
+use crate::iomem::{self, Region};
+
+/// Source of CPUID leaves, MSRs and physical memory.
+pub trait Backend: Send + Sync {
+    fn cpuid(&self, cpu: usize, eax: u32, ecx: u32) -> io::Result<[u32; 4]>;
+    fn read_msr(&self, cpu: usize, addr: u32) -> io::Result<u64>;
+    fn write_msr(&self, cpu: usize, addr: u32, value: u64) -> io::Result<()>;
+    fn read_mem(&self, addr: u64, buf: &mut [u8]) -> io::Result<()>;
+    /// Checks that `[addr, addr + size)` is safe to read with `read_mem`.
+    fn check_mem(&self, _addr: u64, _size: u64) -> io::Result<()> {
+        Ok(())
+    }
+    /// Executes HRESET with `bits` on the CPU the calling thread is running on.
+    ///
+    /// HRESET raises #GP outside CPL 0, so only backends that do not execute
+    /// it natively from user space can provide it.
+    fn hreset(&self, bits: u32) -> io::Result<()>;
+}
+
+/// Accesses the hardware through `/dev/cpu/*/{cpuid,msr}` and `/dev/mem`.
+pub struct NativeBackend;
+
+impl Backend for NativeBackend {
+    fn cpuid(&self, cpu: usize, eax: u32, ecx: u32) -> io::Result<[u32; 4]> {
+        let mut fd = File::open(format!("/dev/cpu/{cpu}/cpuid"))?;
+        let mut buf = [0u8; 16];
+        let pos = ((ecx as u64) << 32) | (eax as u64);
+        fd.seek(SeekFrom::Start(pos))?;
+        fd.read_exact(buf.as_mut())?;
+        let b1 = [buf[0], buf[1], buf[2], buf[3]];
+        let b2 = [buf[4], buf[5], buf[6], buf[7]];
+        let b3 = [buf[8], buf[9], buf[10], buf[11]];
+        let b4 = [buf[12], buf[13], buf[14], buf[15]];
+        Ok([
+            u32::from_le_bytes(b1),
+            u32::from_le_bytes(b2),
+            u32::from_le_bytes(b3),
+            u32::from_le_bytes(b4),
+        ])
+    }
+
+    fn read_msr(&self, cpu: usize, addr: u32) -> io::Result<u64> {
+        let mut fd = File::open(format!("/dev/cpu/{cpu}/msr"))?;
+        let mut buf = [0u8; 8];
+        fd.seek(SeekFrom::Start(addr as u64))?;
+        fd.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn write_msr(&self, cpu: usize, addr: u32, value: u64) -> io::Result<()> {
+        let mut fd = File::options()
+            .write(true)
+            .open(format!("/dev/cpu/{cpu}/msr"))?;
+        let buf = value.to_le_bytes();
+        fd.seek(SeekFrom::Start(addr as u64))?;
+        fd.write_all(&buf)?;
+        Ok(())
+    }
+
+    fn read_mem(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut fd = File::open("/dev/mem")?;
+        fd.seek(SeekFrom::Start(addr))?;
+        fd.read_exact(buf)?;
+        Ok(())
+    }
+
+    /// Accepts only ranges backed by firmware-reserved memory or RAM in
+    /// `/proc/iomem`, so that a bogus pointer cannot make us read device memory.
+    fn check_mem(&self, addr: u64, size: u64) -> io::Result<()> {
+        static REGIONS: OnceLock<Vec<Region>> = OnceLock::new();
+        let regions = match REGIONS.get() {
+            Some(regions) => regions,
+            None => {
+                let regions = iomem::read(Path::new("/proc"))?;
+                REGIONS.get_or_init(|| regions)
+            }
+        };
+        iomem::check(regions, addr, size)?;
+        Ok(())
+    }
+
+    /// Executing HRESET here would crash with SIGSEGV, and the kernel
+    /// provides no interface to issue it on our behalf.
+    fn hreset(&self, _bits: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "HRESET can only be executed at CPL 0",
+        ))
+    }
+}
+
+static BACKEND: OnceLock<Box<dyn Backend>> = OnceLock::new();
+
+/// Installs the process-wide backend. Must be called before the first access.
+pub fn install(backend: Box<dyn Backend>) {
+    if BACKEND.set(backend).is_err() {
+        panic!("backend is already installed");
+    }
+}
+
+/// Returns the process-wide backend, defaulting to [`NativeBackend`].
+pub fn get() -> &'static dyn Backend {
+    BACKEND.get_or_init(|| Box::new(NativeBackend)).as_ref()
+}
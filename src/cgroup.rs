@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! cgroup v2 cpuset steering
+
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    advise::{self, Selection},
+    cpulist,
+    hfi::HfiInfo,
+    topology::CpuTopology,
+};
+
+/// cgroup whose `cpuset.cpus` is set to the CPUs chosen by a selection
+#[derive(Clone, Debug, PartialEq)]
+pub struct Assignment {
+    /// Path relative to the cgroup root
+    pub cgroup: PathBuf,
+    pub selection: Selection,
+}
+
+impl FromStr for Assignment {
+    type Err = String;
+
+    /// Parses `<cgroup>:<key>=<value>,...`, e.g. `latency:prefer=perf,count=4`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (cgroup, options) = s.split_once(':').unwrap_or((s, ""));
+        let cgroup = PathBuf::from(cgroup);
+        let is_normal = |component: Component| matches!(component, Component::Normal(_));
+        if cgroup.as_os_str().is_empty() || !cgroup.components().all(is_normal) {
+            return Err(format!("invalid cgroup path: {}", cgroup.display()));
+        }
+
+        let mut selection = Selection::default();
+        for pair in options.split(',').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("invalid assignment: {s}"))?;
+            if !selection.parse_option(key, value)? {
+                return Err(format!("unknown key in assignment: {key}"));
+            }
+        }
+        Ok(Self { cgroup, selection })
+    }
+}
+
+fn cpuset_path(root: &Path, cgroup: &Path) -> io::Result<PathBuf> {
+    let dir = root.join(cgroup);
+    if !dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("cgroup {} does not exist", dir.display()),
+        ));
+    }
+    let path = dir.join("cpuset.cpus");
+    if !path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "{} does not exist; is the cpuset controller enabled in the parent's cgroup.subtree_control?",
+                path.display()
+            ),
+        ));
+    }
+    Ok(path)
+}
+
+/// Returns the CPUs configured for `cgroup` under `root`.
+pub fn read_cpus(root: &Path, cgroup: &Path) -> io::Result<Vec<usize>> {
+    cpulist::parse(&fs::read_to_string(cpuset_path(root, cgroup)?)?)
+}
+
+/// Restricts `cgroup` under `root` to `cpus`.
+pub fn write_cpus(root: &Path, cgroup: &Path, cpus: &[usize]) -> io::Result<()> {
+    fs::write(
+        cpuset_path(root, cgroup)?,
+        format!("{}\n", cpulist::format(cpus)),
+    )
+}
+
+/// Applies `assignments` under `root` from the current table, writing only
+/// the cgroups whose CPUs change. With `dry_run`, nothing is written.
+pub fn apply<const NUM_CPUS: usize>(
+    hfi_info: &HfiInfo,
+    topology: &[CpuTopology],
+    root: &Path,
+    assignments: &[Assignment],
+    dry_run: bool,
+) -> io::Result<()> {
+    for assignment in assignments {
+        let selection = &assignment.selection;
+        let capabilities = advise::read_capabilities::<NUM_CPUS>(hfi_info, selection.class)?;
+        let cpus = selection.select(&capabilities, topology);
+        if cpus.is_empty() {
+            eprintln!(
+                "{}: no usable CPU, leaving cpuset.cpus unchanged",
+                assignment.cgroup.display()
+            );
+            continue;
+        }
+        let current = read_cpus(root, &assignment.cgroup)?;
+        if current == cpus {
+            continue;
+        }
+        println!(
+            "{}: cpuset.cpus {} -> {}",
+            assignment.cgroup.display(),
+            cpulist::format(&current),
+            cpulist::format(&cpus)
+        );
+        if !dry_run {
+            write_cpus(root, &assignment.cgroup, &cpus)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        advise::Objective,
+        testutil::{install_fake, TempDir},
+    };
+
+    #[test]
+    fn parse_assignments() {
+        let assignment: Assignment = "system.slice/db.service:prefer=ee,count=2".parse().unwrap();
+        assert_eq!(assignment.cgroup, Path::new("system.slice/db.service"));
+        assert_eq!(assignment.selection.objective, Objective::Efficiency);
+        assert_eq!(assignment.selection.count, 2);
+
+        let assignment: Assignment = "latency".parse().unwrap();
+        assert_eq!(assignment.selection, Selection::default());
+
+        for assignment in [
+            "",
+            ":count=1",
+            "/abs:count=1",
+            "../up",
+            "a/../b",
+            "a:x=1",
+            "a:count",
+        ] {
+            assert!(assignment.parse::<Assignment>().is_err(), "{assignment}");
+        }
+    }
+
+    #[test]
+    fn cpus() {
+        let root = TempDir::new();
+        root.write("latency/cpuset.cpus", "0-3\n");
+        let cgroup = Path::new("latency");
+        assert_eq!(read_cpus(root.path(), cgroup).unwrap(), [0, 1, 2, 3]);
+        write_cpus(root.path(), cgroup, &[4, 5, 7]).unwrap();
+        assert_eq!(root.read("latency/cpuset.cpus"), "4-5,7\n");
+    }
+
+    #[test]
+    fn missing_cpuset() {
+        let root = TempDir::new();
+        root.write("batch/cgroup.procs", "");
+        let err = read_cpus(root.path(), Path::new("batch")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("subtree_control"));
+        let err = write_cpus(root.path(), Path::new("missing"), &[0]).unwrap_err();
+        assert!(err.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn apply_once() {
+        install_fake();
+        let hfi_info = HfiInfo::new(0).unwrap();
+        let topology = CpuTopology::read_present(32).unwrap();
+        let root = TempDir::new();
+        root.write("latency/cpuset.cpus", "0-31\n");
+        let assignments = ["latency:prefer=perf,count=2".parse().unwrap()];
+
+        apply::<32>(&hfi_info, &topology, root.path(), &assignments, true).unwrap();
+        assert_eq!(root.read("latency/cpuset.cpus"), "0-31\n");
+
+        apply::<32>(&hfi_info, &topology, root.path(), &assignments, false).unwrap();
+        let cpus = root.read("latency/cpuset.cpus");
+        assert_ne!(cpus, "0-31\n");
+        assert_eq!(cpulist::parse(&cpus).unwrap().len(), 2);
+
+        // The same table leaves the cpuset alone, so a marker survives.
+        root.write("latency/cpuset.cpus", &cpus.replace('\n', " \n"));
+        apply::<32>(&hfi_info, &topology, root.path(), &assignments, false).unwrap();
+        assert_eq!(root.read("latency/cpuset.cpus"), cpus.replace('\n', " \n"));
+    }
+}
@@ -0,0 +1,282 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Nagios-style health check of the HFI table
+
+use std::fmt;
+
+use crate::{cpulist, hfi::HfiTable};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl Status {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok => write!(f, "OK"),
+            Self::Warning => write!(f, "WARNING"),
+            Self::Critical => write!(f, "CRITICAL"),
+            Self::Unknown => write!(f, "UNKNOWN"),
+        }
+    }
+}
+
+/// Capabilities below these values raise a warning or a critical status.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Thresholds {
+    pub warn_perf: Option<u8>,
+    pub crit_perf: Option<u8>,
+    pub warn_ee: Option<u8>,
+    pub crit_ee: Option<u8>,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub status: Status,
+    pub problems: Vec<String>,
+    pub num_cpus: usize,
+    pub min_perf: u8,
+    pub min_ee: u8,
+    thresholds: Thresholds,
+}
+
+impl Report {
+    pub fn unknown(reason: impl fmt::Display) -> Self {
+        Self {
+            status: Status::Unknown,
+            problems: vec![reason.to_string()],
+            num_cpus: 0,
+            min_perf: 0,
+            min_ee: 0,
+            thresholds: Thresholds::default(),
+        }
+    }
+
+    fn raise(&mut self, status: Status, problem: String) {
+        self.status = self.status.max(status);
+        self.problems.push(problem);
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HFI {} - ", self.status)?;
+        match self.problems.is_empty() {
+            true => write!(f, "{} CPUs available", self.num_cpus)?,
+            false => write!(f, "{}", self.problems.join("; "))?,
+        }
+        if self.status == Status::Unknown {
+            return Ok(());
+        }
+        let threshold = |value: Option<u8>| value.map(|v| v.to_string()).unwrap_or_default();
+        write!(
+            f,
+            " | min_perf={};{};{};0;255 min_ee={};{};{};0;255",
+            self.min_perf,
+            threshold(self.thresholds.warn_perf),
+            threshold(self.thresholds.crit_perf),
+            self.min_ee,
+            threshold(self.thresholds.warn_ee),
+            threshold(self.thresholds.crit_ee),
+        )
+    }
+}
+
+/// CPUs whose capability is below `threshold`
+fn below(caps: &[(usize, u8)], threshold: Option<u8>) -> Vec<usize> {
+    match threshold {
+        Some(threshold) => caps
+            .iter()
+            .filter(|(_, cap)| *cap < threshold)
+            .map(|(cpu, _)| *cpu)
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Evaluates the rows of `cpus`, the CPUs present on the system. Rows of
+/// other CPUs are left zeroed by [`HfiTable::read`] and are not checked.
+pub fn evaluate<const NUM_CPUS: usize>(
+    table: &HfiTable<NUM_CPUS>,
+    cpus: &[usize],
+    thresholds: Thresholds,
+) -> Report {
+    let entries = table.entries;
+    let perf: Vec<(usize, u8)> = cpus
+        .iter()
+        .map(|&cpu| (cpu, entries[cpu].perf_cap()))
+        .collect();
+    let ee: Vec<(usize, u8)> = cpus
+        .iter()
+        .map(|&cpu| (cpu, entries[cpu].ee_cap()))
+        .collect();
+    let min = |caps: &[(usize, u8)]| caps.iter().map(|(_, cap)| *cap).min().unwrap_or(0);
+    let mut report = Report {
+        status: Status::Ok,
+        problems: Vec::new(),
+        num_cpus: cpus.len(),
+        min_perf: min(&perf),
+        min_ee: min(&ee),
+        thresholds,
+    };
+
+    if table.header.idle_requested() {
+        report.raise(Status::Critical, "idle requested".to_string());
+    }
+
+    let mut zero = below(&perf, Some(1));
+    zero.extend(below(&ee, Some(1)));
+    zero.sort_unstable();
+    zero.dedup();
+    if !zero.is_empty() {
+        report.raise(
+            Status::Critical,
+            format!("zero capability on CPU {}", cpulist::format(&zero)),
+        );
+    }
+
+    let checks = [
+        ("perf", &perf, thresholds.crit_perf, thresholds.warn_perf),
+        ("ee", &ee, thresholds.crit_ee, thresholds.warn_ee),
+    ];
+    for (name, caps, crit, warn) in checks {
+        // CPUs with zero capability are already reported above.
+        let crit_cpus: Vec<usize> = below(caps, crit)
+            .into_iter()
+            .filter(|cpu| !zero.contains(cpu))
+            .collect();
+        let warn_cpus: Vec<usize> = below(caps, warn)
+            .into_iter()
+            .filter(|cpu| !zero.contains(cpu) && !crit_cpus.contains(cpu))
+            .collect();
+        if let (false, Some(crit)) = (crit_cpus.is_empty(), crit) {
+            report.raise(
+                Status::Critical,
+                format!("{name} below {crit} on CPU {}", cpulist::format(&crit_cpus)),
+            );
+        }
+        if let (false, Some(warn)) = (warn_cpus.is_empty(), warn) {
+            report.raise(
+                Status::Warning,
+                format!("{name} below {warn} on CPU {}", cpulist::format(&warn_cpus)),
+            );
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hfi::{HfiEntry, HfiInfo},
+        testutil::install_fake,
+    };
+
+    const CPUS: [usize; 4] = [0, 1, 2, 3];
+
+    fn table(caps: [(u8, u8); 4]) -> HfiTable<4> {
+        let mut table = HfiTable::<4>::new();
+        for (entry, (perf, ee)) in table.entries.iter_mut().zip(caps) {
+            *entry = HfiEntry::new(perf, ee);
+        }
+        table
+    }
+
+    #[test]
+    fn ok() {
+        let report = evaluate(&table([(200, 100); 4]), &CPUS, Thresholds::default());
+        assert_eq!(report.status, Status::Ok);
+        assert_eq!(
+            report.to_string(),
+            "HFI OK - 4 CPUs available | min_perf=200;;;0;255 min_ee=100;;;0;255"
+        );
+    }
+
+    #[test]
+    fn zero_capability() {
+        let report = evaluate(
+            &table([(200, 100), (0, 100), (200, 0), (200, 100)]),
+            &CPUS,
+            Thresholds {
+                crit_perf: Some(50),
+                ..Default::default()
+            },
+        );
+        assert_eq!(report.status, Status::Critical);
+        assert_eq!(report.status.code(), 2);
+        assert_eq!(report.problems, ["zero capability on CPU 1-2"]);
+    }
+
+    #[test]
+    fn thresholds() {
+        let thresholds = Thresholds {
+            warn_perf: Some(150),
+            crit_perf: Some(100),
+            warn_ee: Some(90),
+            crit_ee: None,
+        };
+        let report = evaluate(
+            &table([(200, 100), (120, 100), (80, 100), (200, 80)]),
+            &CPUS,
+            thresholds,
+        );
+        assert_eq!(report.status, Status::Critical);
+        assert_eq!(
+            report.to_string(),
+            "HFI CRITICAL - perf below 100 on CPU 2; perf below 150 on CPU 1; \
+             ee below 90 on CPU 3 | min_perf=80;150;100;0;255 min_ee=80;90;;0;255"
+        );
+
+        let report = evaluate(
+            &table([(200, 100), (120, 100), (200, 100), (200, 100)]),
+            &CPUS,
+            thresholds,
+        );
+        assert_eq!(report.status, Status::Warning);
+    }
+
+    #[test]
+    fn unknown() {
+        let report = Report::unknown("HFI is not supported");
+        assert_eq!(report.status.code(), 3);
+        assert_eq!(report.to_string(), "HFI UNKNOWN - HFI is not supported");
+    }
+
+    #[test]
+    fn absent_cpus() {
+        // Rows beyond the CPUs present stay zeroed.
+        let report = evaluate(
+            &table([(200, 100), (180, 90), (0, 0), (0, 0)]),
+            &[0, 1],
+            Thresholds::default(),
+        );
+        assert_eq!(report.status, Status::Ok);
+        assert_eq!(
+            report.to_string(),
+            "HFI OK - 2 CPUs available | min_perf=180;;;0;255 min_ee=90;;;0;255"
+        );
+
+        install_fake();
+        let hfi_info = HfiInfo::new(0).unwrap();
+        let mut wide = HfiTable::<64>::new();
+        wide.read(&hfi_info).unwrap();
+        let cpus = hfi_info.present_cpus(64).unwrap();
+        let report = evaluate(&wide, &cpus, Thresholds::default());
+        assert_eq!(report.num_cpus, 32);
+        assert_eq!(report.status, Status::Ok);
+        assert_ne!(report.min_perf, 0);
+    }
+}
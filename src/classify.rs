@@ -0,0 +1,175 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! ITD classification of user-supplied workloads
+
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    affinity,
+    hfi::HfiInfo,
+    hreset::{self, ITD_HISTORY},
+    itd::ItdInfo,
+    msr::{self, Msr},
+    sample::ClassHistogram,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// Clear the ITD history before running the workload, which needs a
+    /// backend that can execute HRESET
+    pub hreset: bool,
+    /// Interval between readings of the thread feedback characteristics
+    pub interval: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            hreset: false,
+            interval: Duration::from_millis(10),
+        }
+    }
+}
+
+/// Runs `body` repeatedly until `duration` has elapsed.
+pub fn repeat_for<F: FnMut()>(duration: Duration, mut body: F) {
+    const BATCH: usize = 1000;
+
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        for _ in 0..BATCH {
+            body();
+        }
+    }
+}
+
+/// Sets the flag when dropped, so that the sampler also stops if the workload panics.
+struct StopOnDrop<'a>(&'a AtomicBool);
+
+impl Drop for StopOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// Restores the affinity of the calling thread when dropped, so that it is
+/// also restored if the workload panics.
+struct RestoreAffinity(Option<Vec<usize>>);
+
+impl RestoreAffinity {
+    fn restore(mut self) -> io::Result<()> {
+        match self.0.take() {
+            Some(cpus) => affinity::set(0, &cpus),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for RestoreAffinity {
+    fn drop(&mut self) {
+        if let Some(cpus) = self.0.take() {
+            let _ = affinity::set(0, &cpus);
+        }
+    }
+}
+
+/// Runs `workload` on `cpu` while sampling the thread feedback characteristics
+/// of that CPU from another thread, and returns the observed class distribution.
+///
+/// The calling thread is pinned to `cpu` during the call and its original
+/// affinity is restored afterwards, even if `workload` panics.
+pub fn classify<F: FnOnce()>(
+    cpu: usize,
+    options: &Options,
+    workload: F,
+) -> io::Result<ClassHistogram> {
+    let hfi_info = HfiInfo::new(cpu)?;
+    if !hfi_info.has_itd() {
+        return Err(io::Error::other("ITD is not supported"));
+    }
+    let itd_info = ItdInfo::new(&hfi_info);
+    if !itd_info.itd_enabled() {
+        return Err(io::Error::other("ITD is not enabled"));
+    }
+
+    let original = affinity::get(0)?;
+    let others: Vec<usize> = original.iter().copied().filter(|c| *c != cpu).collect();
+    if others.is_empty() {
+        return Err(io::Error::other("no CPU is left for the sampler"));
+    }
+    let restore = RestoreAffinity(Some(original));
+    affinity::set(0, &[cpu])?;
+    if options.hreset {
+        hreset::hreset(cpu, ITD_HISTORY)?;
+    }
+
+    let done = AtomicBool::new(false);
+    let result = thread::scope(|scope| {
+        let sampler = scope.spawn(|| -> io::Result<ClassHistogram> {
+            // Keep the sampler off the CPU under test.
+            affinity::set(0, &others)?;
+            let mut histogram = ClassHistogram::default();
+            while !done.load(Ordering::Acquire) {
+                histogram.record(msr::ThreadFeedbackChar::read(cpu)?);
+                thread::sleep(options.interval);
+            }
+            Ok(histogram)
+        });
+
+        let stop = StopOnDrop(&done);
+        workload();
+        drop(stop);
+
+        sampler
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("sampler panicked")))
+    });
+
+    restore.restore()?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::install_fake;
+
+    #[test]
+    fn histogram() {
+        install_fake();
+        let original = affinity::get(0).unwrap();
+        let options = Options {
+            interval: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let result = classify(original[0], &options, || {
+            repeat_for(Duration::from_millis(50), || {})
+        });
+        assert_eq!(affinity::get(0).unwrap(), original);
+        // The sampler needs a CPU other than the one under test.
+        match original.len() {
+            1 => assert_eq!(
+                result.unwrap_err().to_string(),
+                "no CPU is left for the sampler"
+            ),
+            _ => assert!(result.unwrap().total() > 0),
+        }
+    }
+
+    #[test]
+    fn workload_panics() {
+        install_fake();
+        let original = affinity::get(0).unwrap();
+        let result = std::panic::catch_unwind(|| {
+            classify(original[0], &Options::default(), || panic!("workload"))
+        });
+        assert_eq!(result.is_err(), original.len() > 1);
+        assert_eq!(affinity::get(0).unwrap(), original);
+    }
+}
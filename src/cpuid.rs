@@ -1,13 +1,12 @@
 // SPDX-License-Identifier: MIT
 // Copyright (C) 2023 Akira Moroo
 
-use std::{
-    fs::File,
-    io::{self, Read, Seek, SeekFrom},
-};
+use std::io;
 
 use bitfield_struct::bitfield;
 
+use crate::backend;
+
 pub trait Cpuid<const EAX: u32, const ECX: u32> {
     const EAX: u32 = EAX;
     const ECX: u32 = ECX;
@@ -16,24 +15,29 @@ pub trait Cpuid<const EAX: u32, const ECX: u32> {
     where
         Self: Sized + From<[u32; 4]>,
     {
-        let mut fd = File::open(format!("/dev/cpu/{cpu}/cpuid"))?;
-        let mut buf = [0u8; 16];
-        let pos = ((Self::ECX as u64) << 32) | (Self::EAX as u64);
-        fd.seek(SeekFrom::Start(pos))?;
-        fd.read_exact(buf.as_mut())?;
-        let b1 = [buf[0], buf[1], buf[2], buf[3]];
-        let b2 = [buf[4], buf[5], buf[6], buf[7]];
-        let b3 = [buf[8], buf[9], buf[10], buf[11]];
-        let b4 = [buf[12], buf[13], buf[14], buf[15]];
-        Ok(Self::from([
-            u32::from_le_bytes(b1),
-            u32::from_le_bytes(b2),
-            u32::from_le_bytes(b3),
-            u32::from_le_bytes(b4),
-        ]))
+        Ok(Self::from(backend::get().cpuid(
+            cpu,
+            Self::EAX,
+            Self::ECX,
+        )?))
     }
 }
 
+/// Leaves and subleaves read by this module
+pub const LEAVES: [(u32, u32); 11] = [
+    (0x00, 0x0),
+    (0x01, 0x0),
+    (0x06, 0x0),
+    (0x07, 0x1),
+    (0x0b, 0x0),
+    (0x0b, 0x1),
+    (0x1a, 0x0),
+    (0x20, 0x0),
+    (0x8000_0002, 0x0),
+    (0x8000_0003, 0x0),
+    (0x8000_0004, 0x0),
+];
+
 #[bitfield(u32)]
 struct ReservedCpuidExx {
     #[bits(32)]
@@ -150,7 +154,7 @@ impl From<[u32; 4]> for NativeModelIdCpuid {
 
 impl Cpuid<0x1a, 0x0> for NativeModelIdCpuid {}
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum CoreType {
     Unknown,
     Atom,
@@ -167,8 +171,256 @@ impl From<u32> for CoreType {
     }
 }
 
+impl std::str::FromStr for CoreType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "core" | "p" => Ok(Self::Core),
+            "atom" | "e" => Ok(Self::Atom),
+            _ => Err(format!("invalid core type: {s}")),
+        }
+    }
+}
+
 impl NativeModelIdCpuid {
     pub fn core_type(&self) -> CoreType {
         CoreType::from(self.eax.core_type())
     }
 }
+
+#[bitfield(u32)]
+struct ExtendedTopologyEax {
+    #[bits(5)]
+    shift: u32,
+    #[bits(27)]
+    _reserved: u32,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct ExtendedTopologyCpuid<const LEVEL: u32> {
+    eax: ExtendedTopologyEax,
+    ebx: ReservedCpuidExx,
+    ecx: ReservedCpuidExx,
+    edx: u32,
+}
+
+impl<const LEVEL: u32> From<[u32; 4]> for ExtendedTopologyCpuid<LEVEL> {
+    fn from(value: [u32; 4]) -> Self {
+        let eax = ExtendedTopologyEax::from(value[0]);
+        let ebx = ReservedCpuidExx::from(value[1]);
+        let ecx = ReservedCpuidExx::from(value[2]);
+        let edx = value[3];
+        Self { eax, ebx, ecx, edx }
+    }
+}
+
+impl<const LEVEL: u32> Cpuid<0x0b, LEVEL> for ExtendedTopologyCpuid<LEVEL> {}
+
+impl<const LEVEL: u32> ExtendedTopologyCpuid<LEVEL> {
+    /// Number of x2APIC ID bits to shift right to get the next level ID
+    pub fn shift(&self) -> u32 {
+        self.eax.shift()
+    }
+    pub fn x2apic_id(&self) -> u32 {
+        self.edx
+    }
+}
+
+/// SMT level of the extended topology enumeration
+pub type SmtTopologyCpuid = ExtendedTopologyCpuid<0>;
+/// Core level of the extended topology enumeration
+pub type CoreTopologyCpuid = ExtendedTopologyCpuid<1>;
+
+#[bitfield(u32)]
+struct ExtendedFeature1Eax {
+    #[bits(22)]
+    _reserved: u32,
+    has_hreset: bool,
+    #[bits(9)]
+    _reserved: u32,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct ExtendedFeature1Cpuid {
+    eax: ExtendedFeature1Eax,
+    ebx: ReservedCpuidExx,
+    ecx: ReservedCpuidExx,
+    edx: ReservedCpuidExx,
+}
+
+impl From<[u32; 4]> for ExtendedFeature1Cpuid {
+    fn from(value: [u32; 4]) -> Self {
+        let eax = ExtendedFeature1Eax::from(value[0]);
+        let ebx = ReservedCpuidExx::from(value[1]);
+        let ecx = ReservedCpuidExx::from(value[2]);
+        let edx = ReservedCpuidExx::from(value[3]);
+        Self { eax, ebx, ecx, edx }
+    }
+}
+
+impl Cpuid<0x07, 0x1> for ExtendedFeature1Cpuid {}
+
+impl ExtendedFeature1Cpuid {
+    pub fn has_hreset(&self) -> bool {
+        self.eax.has_hreset()
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct HresetCpuid {
+    eax: ReservedCpuidExx,
+    ebx: u32,
+    ecx: ReservedCpuidExx,
+    edx: ReservedCpuidExx,
+}
+
+impl From<[u32; 4]> for HresetCpuid {
+    fn from(value: [u32; 4]) -> Self {
+        let eax = ReservedCpuidExx::from(value[0]);
+        let ebx = value[1];
+        let ecx = ReservedCpuidExx::from(value[2]);
+        let edx = ReservedCpuidExx::from(value[3]);
+        Self { eax, ebx, ecx, edx }
+    }
+}
+
+impl Cpuid<0x20, 0x0> for HresetCpuid {}
+
+impl HresetCpuid {
+    /// Bitmap of the histories that HRESET can reset
+    pub fn supported_history(&self) -> u32 {
+        self.ebx
+    }
+}
+
+#[derive(Debug)]
+pub struct VendorCpuid {
+    max_leaf: u32,
+    vendor: [u32; 3],
+}
+
+impl From<[u32; 4]> for VendorCpuid {
+    fn from(value: [u32; 4]) -> Self {
+        // The vendor string is in EBX, EDX and ECX, in that order.
+        Self {
+            max_leaf: value[0],
+            vendor: [value[1], value[3], value[2]],
+        }
+    }
+}
+
+impl Cpuid<0x00, 0x0> for VendorCpuid {}
+
+impl VendorCpuid {
+    pub fn max_leaf(&self) -> u32 {
+        self.max_leaf
+    }
+    pub fn vendor(&self) -> String {
+        let bytes: Vec<u8> = self
+            .vendor
+            .iter()
+            .flat_map(|reg| reg.to_le_bytes())
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+    pub fn is_intel(&self) -> bool {
+        self.vendor() == "GenuineIntel"
+    }
+}
+
+#[bitfield(u32)]
+struct VersionEax {
+    #[bits(4)]
+    stepping: u32,
+    #[bits(4)]
+    model: u32,
+    #[bits(4)]
+    family: u32,
+    #[bits(4)]
+    _reserved: u32,
+    #[bits(4)]
+    extended_model: u32,
+    #[bits(8)]
+    extended_family: u32,
+    #[bits(4)]
+    _reserved: u32,
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct VersionCpuid {
+    eax: VersionEax,
+    ebx: ReservedCpuidExx,
+    ecx: ReservedCpuidExx,
+    edx: ReservedCpuidExx,
+}
+
+impl From<[u32; 4]> for VersionCpuid {
+    fn from(value: [u32; 4]) -> Self {
+        let eax = VersionEax::from(value[0]);
+        let ebx = ReservedCpuidExx::from(value[1]);
+        let ecx = ReservedCpuidExx::from(value[2]);
+        let edx = ReservedCpuidExx::from(value[3]);
+        Self { eax, ebx, ecx, edx }
+    }
+}
+
+impl Cpuid<0x01, 0x0> for VersionCpuid {}
+
+impl VersionCpuid {
+    /// Family including the extended family for family 0xf
+    pub fn family(&self) -> u32 {
+        match self.eax.family() {
+            0xf => 0xf + self.eax.extended_family(),
+            family => family,
+        }
+    }
+    /// Model including the extended model for families 0x6 and 0xf
+    pub fn model(&self) -> u32 {
+        match self.eax.family() {
+            0x6 | 0xf => self.eax.extended_model() << 4 | self.eax.model(),
+            _ => self.eax.model(),
+        }
+    }
+    pub fn stepping(&self) -> u32 {
+        self.eax.stepping()
+    }
+}
+
+/// Part of the processor brand string
+#[derive(Debug)]
+pub struct BrandStringCpuid<const LEAF: u32> {
+    regs: [u32; 4],
+}
+
+impl<const LEAF: u32> From<[u32; 4]> for BrandStringCpuid<LEAF> {
+    fn from(value: [u32; 4]) -> Self {
+        Self { regs: value }
+    }
+}
+
+impl<const LEAF: u32> Cpuid<LEAF, 0x0> for BrandStringCpuid<LEAF> {}
+
+impl<const LEAF: u32> BrandStringCpuid<LEAF> {
+    pub fn bytes(&self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        for (chunk, reg) in bytes.chunks_mut(4).zip(self.regs) {
+            chunk.copy_from_slice(&reg.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// Reads the processor brand string from CPUID 0x80000002-0x80000004.
+pub fn brand_string(cpu: usize) -> io::Result<String> {
+    let mut bytes = Vec::with_capacity(48);
+    bytes.extend(BrandStringCpuid::<0x8000_0002>::read(cpu)?.bytes());
+    bytes.extend(BrandStringCpuid::<0x8000_0003>::read(cpu)?.bytes());
+    bytes.extend(BrandStringCpuid::<0x8000_0004>::read(cpu)?.bytes());
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    Ok(String::from_utf8_lossy(&bytes[..end]).trim().to_string())
+}
@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! CPU list format (e.g. `0-3,8,10-11`) as used by Linux
+
+use std::io;
+
+/// Upper bound of CPU numbers, the largest `NR_CPUS` Linux can be built with
+pub const MAX_CPUS: usize = 8192;
+
+/// Parses a CPU list into sorted, deduplicated CPU numbers.
+///
+/// CPU numbers must be below [`MAX_CPUS`], so that a typo cannot make us
+/// allocate a huge range.
+pub fn parse(list: &str) -> io::Result<Vec<usize>> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid CPU list: {list}"),
+        )
+    };
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start.trim().parse().map_err(|_| invalid())?;
+                let end: usize = end.trim().parse().map_err(|_| invalid())?;
+                if start > end || end >= MAX_CPUS {
+                    return Err(invalid());
+                }
+                cpus.extend(start..=end);
+            }
+            None => {
+                let cpu: usize = range.trim().parse().map_err(|_| invalid())?;
+                if cpu >= MAX_CPUS {
+                    return Err(invalid());
+                }
+                cpus.push(cpu);
+            }
+        }
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/// Formats `cpus` as a CPU list, merging consecutive CPUs into ranges.
+pub fn format(cpus: &[usize]) -> String {
+    let mut cpus = cpus.to_vec();
+    cpus.sort_unstable();
+    cpus.dedup();
+
+    let mut ranges = Vec::new();
+    let mut iter = cpus.into_iter().peekable();
+    while let Some(start) = iter.next() {
+        let mut end = start;
+        while iter.peek() == Some(&(end + 1)) {
+            end = iter.next().unwrap();
+        }
+        match start == end {
+            true => ranges.push(format!("{start}")),
+            false => ranges.push(format!("{start}-{end}")),
+        }
+    }
+    ranges.join(",")
+}
+
+/// Formats `cpus` as a hexadecimal CPU mask (e.g. `0x10f`) as used by `taskset`.
+pub fn mask(cpus: &[usize]) -> String {
+    let num_digits = cpus.iter().max().map_or(1, |max| max / 4 + 1);
+    let mut digits = vec![0u8; num_digits];
+    for cpu in cpus {
+        digits[cpu / 4] |= 1 << (cpu % 4);
+    }
+    let hex: String = digits
+        .iter()
+        .rev()
+        .map(|digit| char::from_digit(*digit as u32, 16).unwrap())
+        .collect();
+    format!("0x{hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lists() {
+        assert_eq!(parse("0-3,8,10-11").unwrap(), [0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse(" 3,1-2,2 ,\n").unwrap(), [1, 2, 3]);
+        assert_eq!(parse("").unwrap(), Vec::<usize>::new());
+        assert_eq!(parse("8191").unwrap(), [8191]);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for list in ["a", "1-", "-1", "3-1", "1-2-3", "0-4294967295", "8192"] {
+            let err = parse(list).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{list}");
+        }
+    }
+
+    #[test]
+    fn format_lists() {
+        assert_eq!(format(&[11, 0, 1, 2, 3, 8, 10, 3]), "0-3,8,10-11");
+        assert_eq!(format(&[5]), "5");
+        assert_eq!(format(&[]), "");
+        let cpus = [0, 2, 3, 4, 9];
+        assert_eq!(parse(&format(&cpus)).unwrap(), cpus);
+    }
+
+    #[test]
+    fn masks() {
+        assert_eq!(mask(&[0, 1, 2, 3, 8]), "0x10f");
+        assert_eq!(mask(&[4]), "0x10");
+        assert_eq!(mask(&[]), "0x0");
+    }
+}
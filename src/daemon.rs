@@ -0,0 +1,347 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Affinity daemon re-pinning registered processes on HFI table updates
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, io,
+    path::PathBuf,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    advise::{self, Capability, Objective, Selection},
+    affinity, cpulist,
+    hfi::HfiInfo,
+    process::{self, Process},
+    topology::CpuTopology,
+    watch::TableWatcher,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Matcher {
+    Pid(i32),
+    /// Process name as in `/proc/<pid>/comm`
+    Name(String),
+    /// cgroup v2 path, matching the cgroup and its descendants
+    Cgroup(String),
+}
+
+impl Matcher {
+    pub fn matches(&self, process: &Process) -> bool {
+        match self {
+            Self::Pid(pid) => process.pid == *pid,
+            Self::Name(name) => process.comm == *name,
+            Self::Cgroup(path) => process.cgroup.as_ref().is_some_and(|cgroup| {
+                let path = path.trim_end_matches('/');
+                cgroup == path || cgroup.starts_with(&format!("{path}/"))
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pid(pid) => write!(f, "pid={pid}"),
+            Self::Name(name) => write!(f, "name={name}"),
+            Self::Cgroup(path) => write!(f, "cgroup={path}"),
+        }
+    }
+}
+
+/// Processes to place and the CPUs they prefer
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub matcher: Matcher,
+    pub selection: Selection,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    /// Parses comma-separated `key=value` pairs, e.g.
+    /// `name=nginx,prefer=perf,count=4,class=1,smt=pack,core=atom`. Exactly
+    /// one of `pid`, `name` and `cgroup` is required.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut matcher = None;
+        let mut selection = Selection::default();
+        for pair in s.split(',') {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("invalid rule: {s}"))?;
+            let parsed = match key {
+                "pid" => Matcher::Pid(
+                    value
+                        .parse()
+                        .map_err(|_| format!("invalid pid in rule: {value}"))?,
+                ),
+                "name" => Matcher::Name(value.to_string()),
+                "cgroup" => Matcher::Cgroup(value.to_string()),
+                _ if selection.parse_option(key, value)? => continue,
+                _ => return Err(format!("unknown key in rule: {key}")),
+            };
+            if matcher.replace(parsed).is_some() {
+                return Err(format!("more than one matcher in rule: {s}"));
+            }
+        }
+        let matcher = matcher.ok_or_else(|| format!("no pid, name or cgroup in rule: {s}"))?;
+        Ok(Self { matcher, selection })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub rules: Vec<Rule>,
+    /// Interval between polls of the table and the process list
+    pub interval: Duration,
+    /// Minimum relative score improvement to move processes, e.g. 0.1 for 10%
+    pub hysteresis: f64,
+    /// Minimum time between two moves of the same rule
+    pub min_dwell: Duration,
+    pub procfs: PathBuf,
+}
+
+/// Average score of `cpus` for `objective`, or `None` if there are no CPUs
+/// or any of them is unusable.
+pub fn score(cpus: &[usize], capabilities: &[Capability], objective: Objective) -> Option<f64> {
+    if cpus.is_empty() {
+        return None;
+    }
+    let mut total = 0.0;
+    for cpu in cpus {
+        let capability = capabilities.iter().find(|c| c.cpu == *cpu)?;
+        if capability.is_zero() {
+            return None;
+        }
+        total += objective.score(capability);
+    }
+    Some(total / cpus.len() as f64)
+}
+
+/// Decides whether to move from the `current` to the `proposed` CPUs.
+///
+/// A move away from CPUs with zero capability is always made. Otherwise,
+/// the proposed CPUs must score better than the current ones by more than
+/// `hysteresis`, and `dwelled` must indicate that the current CPUs have been
+/// in use for long enough.
+pub fn should_move(
+    current: &[usize],
+    proposed: &[usize],
+    capabilities: &[Capability],
+    objective: Objective,
+    hysteresis: f64,
+    dwelled: bool,
+) -> bool {
+    if proposed.is_empty() || current == proposed {
+        return false;
+    }
+    if current.is_empty() {
+        return true;
+    }
+    let Some(current) = score(current, capabilities, objective) else {
+        return true;
+    };
+    let Some(proposed) = score(proposed, capabilities, objective) else {
+        return false;
+    };
+    dwelled && proposed > current * (1.0 + hysteresis)
+}
+
+#[derive(Clone, Debug, Default)]
+struct RuleState {
+    cpus: Vec<usize>,
+    moved_at: Option<Instant>,
+    /// A move was held back by the dwell time and is retried once it passes.
+    pending: bool,
+    pinned: BTreeMap<i32, Vec<usize>>,
+}
+
+pub fn run<const NUM_CPUS: usize>(hfi_info: &HfiInfo, config: &Config) -> io::Result<()> {
+    let topology = CpuTopology::read_present(NUM_CPUS)?;
+    let mut watcher = TableWatcher::<NUM_CPUS>::new();
+    let mut states = vec![RuleState::default(); config.rules.len()];
+    let mut timestamp = 0;
+
+    loop {
+        let change = watcher.poll(hfi_info)?;
+        if let Some(change) = &change {
+            println!("table update: {change}");
+            timestamp = change.timestamp;
+        }
+        for (rule, state) in config.rules.iter().zip(&mut states) {
+            let dwelled = state
+                .moved_at
+                .is_none_or(|moved_at| moved_at.elapsed() >= config.min_dwell);
+            if change.is_none() && !(state.pending && dwelled) {
+                continue;
+            }
+            state.pending = false;
+            let selection = &rule.selection;
+            let capabilities = advise::read_capabilities::<NUM_CPUS>(hfi_info, selection.class)?;
+            let proposed = selection.select(&capabilities, &topology);
+            let objective = selection.objective;
+            let should_move = |dwelled| {
+                should_move(
+                    &state.cpus,
+                    &proposed,
+                    &capabilities,
+                    objective,
+                    config.hysteresis,
+                    dwelled,
+                )
+            };
+            if !should_move(dwelled) {
+                state.pending = should_move(true);
+                continue;
+            }
+            let format_score = |cpus: &[usize]| match score(cpus, &capabilities, objective) {
+                Some(score) => format!("{score:.1}"),
+                None => "-".to_string(),
+            };
+            println!(
+                "rule {}: CPUs {} -> {} (score {} -> {}, timestamp {timestamp})",
+                rule.matcher,
+                match state.cpus.is_empty() {
+                    true => "-".to_string(),
+                    false => cpulist::format(&state.cpus),
+                },
+                cpulist::format(&proposed),
+                format_score(&state.cpus),
+                format_score(&proposed),
+            );
+            state.cpus = proposed;
+            state.moved_at = Some(Instant::now());
+        }
+
+        let processes = process::list(&config.procfs)?;
+        let mut assigned = BTreeSet::new();
+        for (rule, state) in config.rules.iter().zip(&mut states) {
+            state
+                .pinned
+                .retain(|pid, _| processes.iter().any(|p| p.pid == *pid));
+            if state.cpus.is_empty() {
+                continue;
+            }
+            for process in &processes {
+                // The first matching rule wins.
+                if !rule.matcher.matches(process) || !assigned.insert(process.pid) {
+                    continue;
+                }
+                if state.pinned.get(&process.pid) == Some(&state.cpus) {
+                    continue;
+                }
+                // Failed pins are retried on the next iteration.
+                match affinity::set_process(&config.procfs, process.pid, &state.cpus) {
+                    Ok(()) => {
+                        println!(
+                            "rule {}: pinned pid {} ({}) to CPUs {}",
+                            rule.matcher,
+                            process.pid,
+                            process.comm,
+                            cpulist::format(&state.cpus)
+                        );
+                        state.pinned.insert(process.pid, state.cpus.clone());
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => eprintln!(
+                        "rule {}: failed to pin pid {} ({}): {err}",
+                        rule.matcher, process.pid, process.comm
+                    ),
+                }
+            }
+        }
+
+        thread::sleep(config.interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities(caps: &[(u8, u8)]) -> Vec<Capability> {
+        caps.iter()
+            .enumerate()
+            .map(|(cpu, (perf, ee))| Capability {
+                cpu,
+                perf: *perf,
+                ee: *ee,
+            })
+            .collect()
+    }
+
+    fn process(pid: i32, comm: &str, cgroup: Option<&str>) -> Process {
+        Process {
+            pid,
+            comm: comm.to_string(),
+            cgroup: cgroup.map(str::to_string),
+            kernel_thread: false,
+        }
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rule: Rule = "name=nginx,prefer=ee,count=4,smt=pack".parse().unwrap();
+        assert_eq!(rule.matcher, Matcher::Name("nginx".to_string()));
+        assert_eq!(rule.selection.objective, Objective::Efficiency);
+        assert_eq!(rule.selection.count, 4);
+        assert_eq!(rule.selection.smt, advise::Smt::Pack);
+
+        let rule: Rule = "pid=42".parse().unwrap();
+        assert_eq!(rule.matcher, Matcher::Pid(42));
+        assert_eq!(rule.selection, Selection::default());
+
+        for rule in [
+            "prefer=perf",
+            "pid=1,name=init",
+            "pid=x",
+            "name",
+            "name=a,speed=3",
+            "name=a,count=x",
+        ] {
+            assert!(rule.parse::<Rule>().is_err(), "{rule}");
+        }
+    }
+
+    #[test]
+    fn matchers() {
+        let nginx = process(7, "nginx", Some("/system.slice/nginx.service"));
+        assert!(Matcher::Pid(7).matches(&nginx));
+        assert!(!Matcher::Pid(8).matches(&nginx));
+        assert!(Matcher::Name("nginx".to_string()).matches(&nginx));
+        assert!(Matcher::Cgroup("/system.slice/".to_string()).matches(&nginx));
+        assert!(Matcher::Cgroup("/system.slice/nginx.service".to_string()).matches(&nginx));
+        assert!(!Matcher::Cgroup("/system".to_string()).matches(&nginx));
+        assert!(!Matcher::Cgroup("/".to_string()).matches(&process(1, "init", None)));
+        assert_eq!(Matcher::Cgroup("/a".to_string()).to_string(), "cgroup=/a");
+    }
+
+    #[test]
+    fn scores() {
+        let caps = capabilities(&[(200, 100), (100, 200), (0, 100)]);
+        assert_eq!(score(&[0, 1], &caps, Objective::Performance), Some(150.0));
+        assert_eq!(score(&[], &caps, Objective::Performance), None);
+        assert_eq!(score(&[0, 2], &caps, Objective::Performance), None);
+        assert_eq!(score(&[3], &caps, Objective::Performance), None);
+    }
+
+    #[test]
+    fn moves() {
+        let caps = capabilities(&[(200, 100), (215, 100), (230, 100), (0, 100)]);
+        let perf = Objective::Performance;
+        assert!(should_move(&[], &[0], &caps, perf, 0.1, false));
+        assert!(!should_move(&[0], &[0], &caps, perf, 0.1, true));
+        assert!(!should_move(&[0], &[], &caps, perf, 0.1, true));
+        // Within the hysteresis
+        assert!(!should_move(&[0], &[1], &caps, perf, 0.1, true));
+        assert!(should_move(&[0], &[2], &caps, perf, 0.1, true));
+        assert!(!should_move(&[0], &[2], &caps, perf, 0.1, false));
+        // Zero capability is left regardless of the dwell time.
+        assert!(should_move(&[3], &[0], &caps, perf, 0.1, false));
+        assert!(!should_move(&[0], &[3], &caps, perf, 0.1, true));
+    }
+}
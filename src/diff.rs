@@ -0,0 +1,432 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Comparison of snapshots
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use crate::{
+    cpuid::{CoreType, ExtendedFeature1Cpuid, HresetCpuid, NativeModelIdCpuid, ThermalCpuid},
+    cpulist,
+    ehfi::{EhfiEntry, NUM_CLASSES},
+    msr,
+    snapshot::Snapshot,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Section {
+    Platform,
+    Cpuid,
+    Msr,
+    Table,
+    Capability,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Platform => write!(f, "Platform"),
+            Self::Cpuid => write!(f, "CPUID"),
+            Self::Msr => write!(f, "MSRs"),
+            Self::Table => write!(f, "Table"),
+            Self::Capability => write!(f, "Capabilities"),
+        }
+    }
+}
+
+/// Difference of an item between two snapshots. Items missing from a
+/// snapshot are `-`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub section: Section,
+    pub cpu: Option<usize>,
+    pub item: String,
+    pub old: String,
+    pub new: String,
+}
+
+fn missing() -> String {
+    "-".to_string()
+}
+
+/// Decoded CPUID features of `cpu`
+fn features(snapshot: &Snapshot, cpu: usize) -> BTreeMap<String, String> {
+    let mut features = BTreeMap::new();
+    let mut add = |name: &str, value: String| features.insert(name.to_string(), value);
+    if let Some(regs) = snapshot.cpuid.get(&(cpu, 0x06, 0x0)) {
+        let thermal = ThermalCpuid::from(*regs);
+        add("HFI", thermal.has_hfi().to_string());
+        add(
+            "HFI performance capability",
+            thermal.has_perf_cap().to_string(),
+        );
+        add(
+            "HFI efficiency capability",
+            thermal.has_ee_cap().to_string(),
+        );
+        add("HFI table pages", thermal.hfi_size().to_string());
+        add("HFI row index", thermal.hfi_row_index().to_string());
+        add("ITD", thermal.has_itd().to_string());
+        add("ITD classes", thermal.num_itd_classes().to_string());
+    }
+    if let Some(regs) = snapshot.cpuid.get(&(cpu, 0x07, 0x1)) {
+        add(
+            "HRESET",
+            ExtendedFeature1Cpuid::from(*regs).has_hreset().to_string(),
+        );
+    }
+    if let Some(regs) = snapshot.cpuid.get(&(cpu, 0x20, 0x0)) {
+        let history = HresetCpuid::from(*regs).supported_history();
+        add("HRESET history", format!("{history:#x}"));
+    }
+    if let Some(core_type) = core_type(snapshot, cpu) {
+        add("core type", format!("{core_type:?}"));
+    }
+    features
+}
+
+fn core_type(snapshot: &Snapshot, cpu: usize) -> Option<CoreType> {
+    let regs = snapshot.cpuid.get(&(cpu, 0x1a, 0x0))?;
+    Some(NativeModelIdCpuid::from(*regs).core_type())
+}
+
+fn msr_name(addr: u32) -> String {
+    match addr {
+        msr::IA32_THERM_INTERRUPT => "IA32_THERM_INTERRUPT".to_string(),
+        msr::MSR_TEMPERATURE_TARGET => "MSR_TEMPERATURE_TARGET".to_string(),
+        msr::IA32_PACKAGE_THERM_INTERRUPT => "IA32_PACKAGE_THERM_INTERRUPT".to_string(),
+        msr::IA32_HW_FEEDBACK_PTR => "IA32_HW_FEEDBACK_PTR".to_string(),
+        msr::IA32_HW_FEEDBACK_CONFIG => "IA32_HW_FEEDBACK_CONFIG".to_string(),
+        msr::IA32_HW_FEEDBACK_THREAD_CONFIG => "IA32_HW_FEEDBACK_THREAD_CONFIG".to_string(),
+        msr::IA32_HRESET_ENABLE => "IA32_HRESET_ENABLE".to_string(),
+        _ => format!("MSR {addr:#x}"),
+    }
+}
+
+/// (performance, energy efficiency) capability of every class in the row of
+/// `cpu` given by its captured CPUID leaf 6
+fn capabilities(snapshot: &Snapshot, cpu: usize) -> Option<Vec<(u8, u8)>> {
+    let (_, table) = snapshot.table()?;
+    let thermal = ThermalCpuid::from(*snapshot.cpuid.get(&(cpu, 0x06, 0x0))?);
+    let entry = EhfiEntry::from_table(table, thermal.hfi_row_index())?;
+    let classes = if thermal.has_itd() { NUM_CLASSES } else { 1 };
+    Some(
+        (0..classes)
+            .map(|class| (entry.perf_cap(class), entry.ee_cap(class)))
+            .collect(),
+    )
+}
+
+fn compare<K: Ord, V: PartialEq>(
+    old: &BTreeMap<K, V>,
+    new: &BTreeMap<K, V>,
+    mut change: impl FnMut(&K, Option<&V>, Option<&V>),
+) {
+    let keys: BTreeSet<&K> = old.keys().chain(new.keys()).collect();
+    for key in keys {
+        let (old, new) = (old.get(key), new.get(key));
+        if old != new {
+            change(key, old, new);
+        }
+    }
+}
+
+/// Compares snapshot `old` with `new`.
+///
+/// The capture time, the thread feedback characteristics and thermal status
+/// MSRs and the table timestamp are left out since they change all the time.
+/// The microcode revision MSR is left out since the platform section already
+/// reports it. Capability changes of less than `min_delta` are ignored.
+pub fn diff(old: &Snapshot, new: &Snapshot, min_delta: u8) -> Vec<Change> {
+    let mut changes = Vec::new();
+    compare(&old.meta, &new.meta, |key, old, new| {
+        if key == "captured" {
+            return;
+        }
+        changes.push(Change {
+            section: Section::Platform,
+            cpu: None,
+            item: key.clone(),
+            old: old.cloned().unwrap_or_else(missing),
+            new: new.cloned().unwrap_or_else(missing),
+        });
+    });
+    let cpus: BTreeSet<usize> = old
+        .cpuid
+        .keys()
+        .chain(new.cpuid.keys())
+        .map(|(cpu, _, _)| *cpu)
+        .chain(old.msrs.keys().chain(new.msrs.keys()).map(|(cpu, _)| *cpu))
+        .collect();
+
+    for &cpu in &cpus {
+        compare(
+            &features(old, cpu),
+            &features(new, cpu),
+            |name, old, new| {
+                changes.push(Change {
+                    section: Section::Cpuid,
+                    cpu: Some(cpu),
+                    item: name.clone(),
+                    old: old.cloned().unwrap_or_else(missing),
+                    new: new.cloned().unwrap_or_else(missing),
+                })
+            },
+        );
+    }
+    compare(&old.cpuid, &new.cpuid, |(cpu, leaf, subleaf), old, new| {
+        for (i, reg) in ["EAX", "EBX", "ECX", "EDX"].iter().enumerate() {
+            let (old, new) = (old.map(|regs| regs[i]), new.map(|regs| regs[i]));
+            if old == new {
+                continue;
+            }
+            changes.push(Change {
+                section: Section::Cpuid,
+                cpu: Some(*cpu),
+                item: format!("leaf {leaf:#x}/{subleaf:#x} {reg}"),
+                old: old.map_or_else(missing, |value| format!("{value:#010x}")),
+                new: new.map_or_else(missing, |value| format!("{value:#010x}")),
+            });
+        }
+    });
+
+    compare(&old.msrs, &new.msrs, |(cpu, addr), old, new| {
+        if matches!(
+            *addr,
+            msr::IA32_BIOS_SIGN_ID
+                | msr::IA32_THREAD_FEEDBACK_CHAR
+                | msr::IA32_THERM_STATUS
+                | msr::IA32_PACKAGE_THERM_STATUS
+        ) {
+            return;
+        }
+        changes.push(Change {
+            section: Section::Msr,
+            cpu: Some(*cpu),
+            item: msr_name(*addr),
+            old: old.map_or_else(missing, |value| format!("{value:#x}")),
+            new: new.map_or_else(missing, |value| format!("{value:#x}")),
+        });
+    });
+
+    let (old_table, new_table) = (old.table(), new.table());
+    let address =
+        |table: Option<(u64, &[u8])>| table.map_or_else(missing, |(addr, _)| format!("{addr:#x}"));
+    let size = |table: Option<(u64, &[u8])>| {
+        table.map_or_else(missing, |(_, bytes)| format!("{:#x}", bytes.len()))
+    };
+    for (item, old, new) in [
+        ("address", address(old_table), address(new_table)),
+        ("size", size(old_table), size(new_table)),
+    ] {
+        if old != new {
+            changes.push(Change {
+                section: Section::Table,
+                cpu: None,
+                item: item.to_string(),
+                old,
+                new,
+            });
+        }
+    }
+
+    let min_delta = min_delta.max(1);
+    for &cpu in &cpus {
+        let old = capabilities(old, cpu).unwrap_or_default();
+        let new = capabilities(new, cpu).unwrap_or_default();
+        for class in 0..old.len().max(new.len()) {
+            let (old, new) = (old.get(class), new.get(class));
+            let changed = match (old, new) {
+                (Some(old), Some(new)) => {
+                    old.0.abs_diff(new.0) >= min_delta || old.1.abs_diff(new.1) >= min_delta
+                }
+                _ => true,
+            };
+            if !changed {
+                continue;
+            }
+            let format = |cap: Option<&(u8, u8)>| {
+                cap.map_or_else(missing, |(perf, ee)| format!("perf {perf} ee {ee}"))
+            };
+            changes.push(Change {
+                section: Section::Capability,
+                cpu: Some(cpu),
+                item: format!("class {class}"),
+                old: format(old),
+                new: format(new),
+            });
+        }
+    }
+    changes
+}
+
+/// Changes grouped by section and core type for display
+pub struct Report {
+    pub changes: Vec<Change>,
+    /// Core type of every CPU, taken from the new snapshot if present
+    pub core_types: BTreeMap<usize, CoreType>,
+}
+
+impl Report {
+    pub fn new(old: &Snapshot, new: &Snapshot, min_delta: u8) -> Self {
+        let changes = diff(old, new, min_delta);
+        let core_types = changes
+            .iter()
+            .filter_map(|change| change.cpu)
+            .filter_map(|cpu| {
+                let core_type = core_type(new, cpu).or_else(|| core_type(old, cpu))?;
+                Some((cpu, core_type))
+            })
+            .collect();
+        Self {
+            changes,
+            core_types,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    /// Lists the changes of CPUs of the same core type together, merging the
+    /// CPUs with the same change into one line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.changes.is_empty() {
+            return write!(f, "no differences");
+        }
+        let mut sections = BTreeMap::<Section, Vec<&Change>>::new();
+        for change in &self.changes {
+            sections.entry(change.section).or_default().push(change);
+        }
+        let mut first = true;
+        for (section, changes) in sections {
+            if !first {
+                writeln!(f)?;
+            }
+            first = false;
+            write!(f, "{section}:")?;
+
+            let mut groups = BTreeMap::<Option<CoreType>, BTreeMap<_, Vec<usize>>>::new();
+            for change in changes {
+                let Some(cpu) = change.cpu else {
+                    write!(f, "\n  {}: {} -> {}", change.item, change.old, change.new)?;
+                    continue;
+                };
+                let core_type = self.core_types.get(&cpu).copied();
+                groups
+                    .entry(core_type)
+                    .or_default()
+                    .entry((&change.item, &change.old, &change.new))
+                    .or_default()
+                    .push(cpu);
+            }
+            for (core_type, changes) in groups.iter().rev() {
+                match core_type {
+                    Some(core_type) => write!(f, "\n  {core_type:?}:")?,
+                    None => write!(f, "\n  Unknown core type:")?,
+                }
+                let mut lines: Vec<_> = changes.iter().collect();
+                lines.sort_by(|a, b| a.1.cmp(b.1).then(a.0.cmp(b.0)));
+                for ((item, old, new), cpus) in lines {
+                    write!(
+                        f,
+                        "\n    CPU {}: {item}: {old} -> {new}",
+                        cpulist::format(cpus)
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CPU 0 is a Core CPU in row 0 and CPU 1 an Atom CPU in row 5, both
+    /// with HFI and ITD and a one-page table at 0x1000.
+    fn snapshot(microcode: u64, caps: [(u8, u8); 2]) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        snapshot
+            .meta
+            .insert("cpu.microcode".to_string(), format!("{microcode:#x}"));
+        snapshot
+            .meta
+            .insert("captured".to_string(), microcode.to_string());
+        let mut table = vec![0u8; 4096];
+        for (cpu, core_type, row) in [(0, 0x40, 0), (1, 0x20, 5)] {
+            snapshot.cpuid.insert(
+                (cpu, 0x06, 0x0),
+                [1 << 19 | 1 << 23, 0, 4 << 8, 0x3 | (row as u32) << 16],
+            );
+            snapshot
+                .cpuid
+                .insert((cpu, 0x1a, 0x0), [core_type << 24, 0, 0, 0]);
+            snapshot
+                .msrs
+                .insert((cpu, msr::IA32_BIOS_SIGN_ID), microcode << 32);
+            snapshot
+                .msrs
+                .insert((cpu, msr::IA32_HW_FEEDBACK_PTR), 0x1001);
+            snapshot
+                .msrs
+                .insert((cpu, msr::IA32_THERM_STATUS), microcode);
+            let offset = 16 + 8 * row;
+            table[offset] = caps[cpu].0;
+            table[offset + 1] = caps[cpu].1;
+        }
+        snapshot.mem.insert(0x1000, table);
+        snapshot
+    }
+
+    #[test]
+    fn no_differences() {
+        let old = snapshot(0x2c, [(200, 100), (100, 200)]);
+        let new = snapshot(0x2c, [(200, 100), (100, 200)]);
+        assert_eq!(diff(&old, &new, 0), []);
+        assert_eq!(Report::new(&old, &new, 0).to_string(), "no differences");
+    }
+
+    #[test]
+    fn microcode_once() {
+        let old = snapshot(0x2c, [(200, 100), (100, 200)]);
+        let new = snapshot(0x2e, [(200, 100), (100, 200)]);
+        assert_eq!(
+            diff(&old, &new, 0),
+            [Change {
+                section: Section::Platform,
+                cpu: None,
+                item: "cpu.microcode".to_string(),
+                old: "0x2c".to_string(),
+                new: "0x2e".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn capabilities() {
+        let old = snapshot(0x2c, [(200, 100), (100, 200)]);
+        let new = snapshot(0x2c, [(204, 100), (80, 200)]);
+        let changes = diff(&old, &new, 5);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].cpu, Some(1));
+        assert_eq!(changes[0].item, "class 0");
+        assert_eq!(changes[0].new, "perf 80 ee 200");
+
+        let mut new = snapshot(0x2c, [(204, 100), (80, 200)]);
+        new.msrs.insert((1, msr::IA32_HW_FEEDBACK_CONFIG), 0x1);
+        assert_eq!(
+            Report::new(&old, &new, 0).to_string(),
+            "MSRs:\n  \
+               Atom:\n    \
+                 CPU 1: IA32_HW_FEEDBACK_CONFIG: - -> 0x1\n\
+             Capabilities:\n  \
+               Core:\n    \
+                 CPU 0: class 0: perf 200 ee 100 -> perf 204 ee 100\n  \
+               Atom:\n    \
+                 CPU 1: class 0: perf 100 ee 200 -> perf 80 ee 200"
+        );
+    }
+}
@@ -0,0 +1,427 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Environment preflight checks
+
+use std::{
+    collections::BTreeSet,
+    fmt,
+    fs::{self, File},
+    io,
+    path::PathBuf,
+};
+
+use crate::{
+    backend,
+    cpuid::{CoreType, Cpuid, NativeModelIdCpuid, ThermalCpuid, VendorCpuid},
+    hfi::HfiInfo,
+    iomem,
+    itd::ItdInfo,
+    msr::{HwFeedbackConfig, HwFeedbackPtr, Msr},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+    /// Not applicable, or not possible because an earlier check failed
+    Skip,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pass => write!(f, "PASS"),
+            Self::Warn => write!(f, "WARN"),
+            Self::Fail => write!(f, "FAIL"),
+            Self::Skip => write!(f, "SKIP"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+    /// How to fix a warning or failure
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.hint = Some(hint.into());
+        self
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.status, self.name, self.detail)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n       hint: {hint}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Paths and CPUs the checks look at
+#[derive(Clone, Debug)]
+pub struct Environment {
+    pub sysfs: PathBuf,
+    pub procfs: PathBuf,
+    pub devfs: PathBuf,
+    pub cpu: usize,
+    pub num_cpus: usize,
+}
+
+fn root() -> Check {
+    match unsafe { libc::geteuid() } {
+        0 => Check::new("root", Status::Pass, "running as root"),
+        uid => Check::new("root", Status::Fail, format!("running as uid {uid}"))
+            .hint("run intel-hfi as root, e.g. with sudo"),
+    }
+}
+
+fn device(env: &Environment, module: &str, names: [&'static str; 2]) -> Vec<Check> {
+    let [name, node] = names;
+    let path = env.devfs.join(format!("cpu/{}/{module}", env.cpu));
+    let loaded = env.sysfs.join("module").join(module).exists() || path.exists();
+    let module_check = match loaded {
+        true => Check::new(name, Status::Pass, format!("{module} driver is available")),
+        false => Check::new(name, Status::Fail, format!("{module} driver is not loaded"))
+            .hint(format!("modprobe {module}")),
+    };
+    let display = path.display();
+    let node_check = match File::open(&path) {
+        Ok(_) => Check::new(node, Status::Pass, format!("{display} is readable")),
+        Err(err) if err.kind() == io::ErrorKind::NotFound && !loaded => {
+            Check::new(node, Status::Skip, format!("{display} does not exist"))
+        }
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            Check::new(node, Status::Fail, format!("{display}: {err}"))
+                .hint("run as root; reading MSRs also needs CAP_SYS_RAWIO")
+        }
+        Err(err) => Check::new(node, Status::Fail, format!("{display}: {err}")),
+    };
+    vec![module_check, node_check]
+}
+
+fn msr_writes(env: &Environment) -> Check {
+    let path = env.sysfs.join("module/msr/parameters/allow_writes");
+    match fs::read_to_string(&path) {
+        Ok(value) if value.trim() == "on" => {
+            Check::new("msr.allow_writes", Status::Pass, "MSR writes are allowed")
+        }
+        Ok(value) => Check::new(
+            "msr.allow_writes",
+            Status::Warn,
+            format!("MSR writes are {}", value.trim()),
+        )
+        .hint(format!(
+            "echo on > {}, or boot with msr.allow_writes=on; only needed by commands that write MSRs",
+            path.display()
+        )),
+        Err(_) => Check::new(
+            "msr.allow_writes",
+            Status::Skip,
+            "the msr driver has no allow_writes parameter",
+        ),
+    }
+}
+
+fn lockdown(env: &Environment) -> Check {
+    let path = env.sysfs.join("kernel/security/lockdown");
+    let Ok(lockdown) = fs::read_to_string(&path) else {
+        return Check::new("lockdown", Status::Skip, "kernel lockdown is not supported");
+    };
+    let mode = lockdown
+        .split_whitespace()
+        .find_map(|mode| mode.strip_prefix('[')?.strip_suffix(']'))
+        .unwrap_or("unknown");
+    match mode {
+        "none" => Check::new("lockdown", Status::Pass, "kernel is not locked down"),
+        _ => Check::new("lockdown", Status::Fail, format!("kernel lockdown is {mode}"))
+            .hint("lockdown blocks /dev/mem and MSR access; disable Secure Boot or boot with lockdown=none"),
+    }
+}
+
+fn vendor(env: &Environment) -> Check {
+    match VendorCpuid::read(env.cpu) {
+        Ok(cpuid) if cpuid.is_intel() => Check::new("CPU vendor", Status::Pass, cpuid.vendor()),
+        Ok(cpuid) => Check::new("CPU vendor", Status::Fail, cpuid.vendor())
+            .hint("HFI is only available on Intel processors"),
+        Err(err) => Check::new(
+            "CPU vendor",
+            Status::Skip,
+            format!("cannot read CPUID: {err}"),
+        ),
+    }
+}
+
+fn hybrid(env: &Environment) -> Check {
+    let core_types: BTreeSet<CoreType> = (0..env.num_cpus)
+        .filter_map(|cpu| NativeModelIdCpuid::read(cpu).ok())
+        .map(|cpuid| cpuid.core_type())
+        .collect();
+    match core_types.contains(&CoreType::Core) && core_types.contains(&CoreType::Atom) {
+        true => Check::new("hybrid", Status::Pass, "Core and Atom CPUs are present"),
+        false => Check::new(
+            "hybrid",
+            Status::Warn,
+            format!("core types: {core_types:?}"),
+        )
+        .hint("ITD classes and EHFI capabilities are most useful on hybrid processors"),
+    }
+}
+
+fn hfi(env: &Environment) -> Vec<Check> {
+    let thermal = match ThermalCpuid::read(env.cpu) {
+        Ok(thermal) => thermal,
+        Err(err) => {
+            return vec![Check::new(
+                "HFI",
+                Status::Skip,
+                format!("cannot read CPUID: {err}"),
+            )]
+        }
+    };
+    if !thermal.has_hfi() {
+        return vec![Check::new("HFI", Status::Fail, "HFI is not supported")];
+    }
+    let enabled = HwFeedbackPtr::read(env.cpu).is_ok_and(|ptr| ptr.valid())
+        && HwFeedbackConfig::read(env.cpu).is_ok_and(|config| config.enable());
+    let mut checks = vec![match enabled {
+        true => Check::new("HFI", Status::Pass, "HFI is supported and enabled"),
+        false => Check::new("HFI", Status::Fail, "HFI is supported but not enabled")
+            .hint("load the kernel intel_hfi driver (CONFIG_INTEL_HFI_THERMAL), which enables HFI"),
+    }];
+    checks.push(match HfiInfo::new(env.cpu) {
+        Ok(info) if !thermal.has_itd() => Check::new("ITD", Status::Warn, "ITD is not supported")
+            .hint(format!(
+                "HFI table at {:#x} is usable without ITD",
+                info.addr
+            )),
+        Ok(info) => match ItdInfo::new(&info).itd_enabled() {
+            true => Check::new("ITD", Status::Pass, "ITD is supported and enabled"),
+            false => Check::new("ITD", Status::Warn, "ITD is supported but not enabled")
+                .hint("ITD is enabled by the kernel on hybrid processors with intel_hfi"),
+        },
+        Err(_) => Check::new("ITD", Status::Skip, "HFI is not usable"),
+    });
+    checks
+}
+
+fn table(env: &Environment) -> Vec<Check> {
+    // The table is located without HfiInfo, which refuses bogus locations.
+    let location = ThermalCpuid::read(env.cpu)
+        .ok()
+        .filter(|thermal| thermal.has_hfi())
+        .zip(HwFeedbackPtr::read(env.cpu).ok().filter(|ptr| ptr.valid()));
+    let Some((thermal, ptr)) = location else {
+        return vec![
+            Check::new("table region", Status::Skip, "HFI is not usable"),
+            Check::new("/dev/mem", Status::Skip, "HFI is not usable"),
+        ];
+    };
+    let addr = ptr.addr() << HfiInfo::PAGE_SHIFT;
+    let size = (HfiInfo::PAGE_SIZE * thermal.hfi_size()) as u64;
+    let checked = iomem::read(&env.procfs).and_then(|regions| {
+        let region = iomem::check(&regions, addr, size)?;
+        Ok(format!(
+            "table {addr:#x}-{:#x} is in {} {:#x}-{:#x}",
+            addr + size - 1,
+            region.name,
+            region.start,
+            region.end
+        ))
+    });
+    let region = match &checked {
+        Ok(detail) => Check::new("table region", Status::Pass, detail.clone()),
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            Check::new("table region", Status::Fail, err.to_string())
+                .hint("run as root to see physical addresses")
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            Check::new("table region", Status::Skip, format!("/proc/iomem: {err}"))
+        }
+        Err(err) => Check::new("table region", Status::Fail, err.to_string())
+            .hint("the table pointer looks bogus; check for a BIOS update"),
+    };
+    if checked.is_err() {
+        let mem = Check::new(
+            "/dev/mem",
+            Status::Skip,
+            "the table location is not trusted",
+        );
+        return vec![region, mem];
+    }
+    let mut buf = [0u8; 16];
+    let mem = match backend::get().read_mem(addr, &mut buf) {
+        Ok(()) => Check::new(
+            "/dev/mem",
+            Status::Pass,
+            format!("table at {addr:#x} is readable"),
+        ),
+        Err(err) => Check::new(
+            "/dev/mem",
+            Status::Fail,
+            format!("reading {addr:#x}: {err}"),
+        )
+        .hint("boot with iomem=relaxed or use a kernel without CONFIG_STRICT_DEVMEM"),
+    };
+    vec![region, mem]
+}
+
+/// Runs all checks in order.
+pub fn run(env: &Environment) -> Vec<Check> {
+    let mut checks = vec![root()];
+    checks.extend(device(env, "cpuid", ["cpuid module", "cpuid device"]));
+    checks.extend(device(env, "msr", ["msr module", "msr device"]));
+    checks.push(msr_writes(env));
+    checks.push(lockdown(env));
+    checks.push(vendor(env));
+    checks.push(hybrid(env));
+    checks.extend(hfi(env));
+    checks.extend(table(env));
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fake::FakeBackend,
+        testutil::{install_fake, TempDir},
+    };
+
+    fn environment(root: &TempDir) -> Environment {
+        Environment {
+            sysfs: root.path().join("sys"),
+            procfs: root.path().join("proc"),
+            devfs: root.path().join("dev"),
+            cpu: 0,
+            num_cpus: FakeBackend::NUM_CPUS,
+        }
+    }
+
+    fn statuses(checks: &[Check]) -> Vec<(&'static str, Status)> {
+        checks
+            .iter()
+            .map(|check| (check.name, check.status))
+            .collect()
+    }
+
+    #[test]
+    fn devices() {
+        let root = TempDir::new();
+        let env = environment(&root);
+        assert_eq!(
+            statuses(&device(&env, "msr", ["msr module", "msr device"])),
+            [("msr module", Status::Fail), ("msr device", Status::Skip)]
+        );
+        root.write("sys/module/msr/refcnt", "0\n");
+        assert_eq!(
+            statuses(&device(&env, "msr", ["msr module", "msr device"])),
+            [("msr module", Status::Pass), ("msr device", Status::Fail)]
+        );
+        root.write("dev/cpu/0/msr", "");
+        assert_eq!(
+            statuses(&device(&env, "msr", ["msr module", "msr device"])),
+            [("msr module", Status::Pass), ("msr device", Status::Pass)]
+        );
+    }
+
+    #[test]
+    fn kernel_settings() {
+        let root = TempDir::new();
+        let env = environment(&root);
+        assert_eq!(msr_writes(&env).status, Status::Skip);
+        assert_eq!(lockdown(&env).status, Status::Skip);
+        root.write("sys/module/msr/parameters/allow_writes", "off\n");
+        root.write(
+            "sys/kernel/security/lockdown",
+            "none [integrity] confidentiality\n",
+        );
+        let check = msr_writes(&env);
+        assert_eq!(
+            (check.status, check.detail.as_str()),
+            (Status::Warn, "MSR writes are off")
+        );
+        let check = lockdown(&env);
+        assert_eq!(check.status, Status::Fail);
+        assert_eq!(check.detail, "kernel lockdown is integrity");
+        root.write("sys/module/msr/parameters/allow_writes", "on\n");
+        root.write(
+            "sys/kernel/security/lockdown",
+            "[none] integrity confidentiality\n",
+        );
+        assert_eq!(msr_writes(&env).status, Status::Pass);
+        assert_eq!(lockdown(&env).status, Status::Pass);
+    }
+
+    #[test]
+    fn processor() {
+        install_fake();
+        let root = TempDir::new();
+        let env = environment(&root);
+        assert_eq!(vendor(&env).status, Status::Pass);
+        assert_eq!(hybrid(&env).status, Status::Pass);
+        assert_eq!(
+            statuses(&hfi(&env)),
+            [("HFI", Status::Pass), ("ITD", Status::Pass)]
+        );
+    }
+
+    #[test]
+    fn table_region() {
+        install_fake();
+        let root = TempDir::new();
+        let env = environment(&root);
+        assert_eq!(
+            statuses(&table(&env)),
+            [("table region", Status::Skip), ("/dev/mem", Status::Skip)]
+        );
+
+        root.write(
+            "proc/iomem",
+            "00000000-00000000 : Reserved\n00000000-00000000 : System RAM\n",
+        );
+        let checks = table(&env);
+        assert_eq!(
+            statuses(&checks),
+            [("table region", Status::Fail), ("/dev/mem", Status::Skip)]
+        );
+        assert_eq!(
+            checks[0].hint.as_deref(),
+            Some("run as root to see physical addresses")
+        );
+
+        root.write("proc/iomem", "3ff00000-400007ff : Reserved\n");
+        assert_eq!(table(&env)[0].status, Status::Fail);
+
+        root.write(
+            "proc/iomem",
+            "00100000-7fffffff : System RAM\n  40000000-40000fff : Reserved\n",
+        );
+        let checks = table(&env);
+        assert_eq!(
+            statuses(&checks),
+            [("table region", Status::Pass), ("/dev/mem", Status::Pass)]
+        );
+        assert_eq!(
+            checks[0].detail,
+            "table 0x40000000-0x40000fff is in Reserved 0x40000000-0x40000fff"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Detection of the kernel `intel_hfi` thermal driver
+
+use std::{collections::BTreeMap, fmt, io, path::Path};
+
+use crate::{
+    hfi::HfiInfo,
+    msr::{HwFeedbackConfig, HwFeedbackPtr, Msr},
+    netlink, provenance,
+    topology::CpuTopology,
+};
+
+/// Generic netlink family of the thermal framework
+pub const THERMAL_FAMILY: &str = "thermal";
+
+/// Evidence of the kernel driver owning the HFI table
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KernelDriver {
+    /// Whether `intel_hfi` is listed as a module
+    pub module: bool,
+    /// Whether the thermal netlink family is registered, if it can be queried
+    pub netlink: Option<bool>,
+    /// Whether every CPU that could be read has HFI enabled
+    pub enabled: bool,
+    /// Whether the CPUs of every package point to the same table
+    pub consistent: bool,
+    /// Table address of every package
+    pub tables: BTreeMap<u32, u64>,
+}
+
+impl KernelDriver {
+    pub fn detect(num_cpus: usize, sysfs: &Path, procfs: &Path) -> Self {
+        let netlink = match netlink::Socket::open().and_then(|socket| socket.family(THERMAL_FAMILY))
+        {
+            Ok(family) => Some(family.is_some()),
+            Err(_) => None,
+        };
+
+        let (mut enabled, mut consistent, mut read) = (true, true, false);
+        let mut tables = BTreeMap::new();
+        for cpu in 0..num_cpus {
+            let state = (|| -> io::Result<_> {
+                let package = CpuTopology::read(cpu)?.package;
+                let ptr = HwFeedbackPtr::read(cpu)?;
+                let config = HwFeedbackConfig::read(cpu)?;
+                Ok((package, ptr, config))
+            })();
+            // Offline and missing CPUs cannot be queried.
+            let Ok((package, ptr, config)) = state else {
+                continue;
+            };
+            read = true;
+            enabled &= ptr.valid() && config.enable();
+            let addr = ptr.addr() << HfiInfo::PAGE_SHIFT;
+            consistent &= *tables.entry(package).or_insert(addr) == addr;
+        }
+
+        Self {
+            module: provenance::hfi_driver_loaded(sysfs, procfs),
+            netlink,
+            enabled: read && enabled,
+            consistent: read && consistent,
+            tables,
+        }
+    }
+
+    /// Whether the kernel is considered to own HFI.
+    ///
+    /// The driver is usually built in, so it is not always listed as a module.
+    /// Nothing else enables HFI consistently on every CPU of a package, and the
+    /// driver always registers the thermal netlink family.
+    pub fn owns_hfi(&self) -> bool {
+        self.module || (self.enabled && self.consistent && self.netlink != Some(false))
+    }
+}
+
+impl fmt::Display for KernelDriver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  Owns HFI: {}", self.owns_hfi())?;
+        writeln!(f, "  Module loaded: {}", self.module)?;
+        match self.netlink {
+            Some(registered) => writeln!(f, "  Thermal netlink family: {registered}")?,
+            None => writeln!(f, "  Thermal netlink family: unknown")?,
+        }
+        writeln!(f, "  HFI enabled on all CPUs: {}", self.enabled)?;
+        write!(f, "  Table pointers consistent: {}", self.consistent)?;
+        for (package, addr) in &self.tables {
+            write!(f, "\n  Package {package} table: {addr:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Fails unless `force` is set or the kernel does not own HFI.
+pub fn check_unowned(driver: &KernelDriver, force: bool) -> io::Result<()> {
+    if force || !driver.owns_hfi() {
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::ResourceBusy,
+        "the kernel intel_hfi driver owns HFI; pass --force to change the state anyway",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fake::FakeBackend,
+        testutil::{install_fake, TempDir},
+    };
+
+    fn driver(module: bool, netlink: Option<bool>, enabled: bool) -> KernelDriver {
+        KernelDriver {
+            module,
+            netlink,
+            enabled,
+            consistent: true,
+            tables: BTreeMap::from([(0, 0x4000_0000)]),
+        }
+    }
+
+    #[test]
+    fn ownership() {
+        assert!(driver(true, Some(false), false).owns_hfi());
+        assert!(driver(false, Some(true), true).owns_hfi());
+        assert!(driver(false, None, true).owns_hfi());
+        assert!(!driver(false, Some(false), true).owns_hfi());
+        assert!(!driver(false, Some(true), false).owns_hfi());
+        let inconsistent = KernelDriver {
+            consistent: false,
+            ..driver(false, Some(true), true)
+        };
+        assert!(!inconsistent.owns_hfi());
+
+        let owned = driver(true, None, true);
+        let err = check_unowned(&owned, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        assert!(check_unowned(&owned, true).is_ok());
+        assert!(check_unowned(&driver(false, Some(false), true), false).is_ok());
+    }
+
+    #[test]
+    fn detect() {
+        install_fake();
+        let root = TempDir::new();
+        root.write("sys/module/intel_hfi/refcnt", "1\n");
+        let sysfs = root.path().join("sys");
+        let procfs = root.path().join("proc");
+        let driver = KernelDriver::detect(FakeBackend::NUM_CPUS + 2, &sysfs, &procfs);
+        assert!(driver.module);
+        assert!(driver.enabled);
+        assert!(driver.consistent);
+        assert_eq!(
+            driver.tables,
+            BTreeMap::from([(0, FakeBackend::TABLE_ADDR)])
+        );
+        // Nothing could be read.
+        let driver = KernelDriver::detect(0, &sysfs, &procfs);
+        assert!(!driver.enabled && !driver.consistent);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            driver(false, None, true).to_string(),
+            "  Owns HFI: true\n  \
+             Module loaded: false\n  \
+             Thermal netlink family: unknown\n  \
+             HFI enabled on all CPUs: true\n  \
+             Table pointers consistent: true\n  \
+             Package 0 table: 0x40000000"
+        );
+    }
+}
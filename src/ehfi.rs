@@ -1,18 +1,14 @@
 // SPDX-License-Identifier: MIT
 // Copyright (C) 2023 Akira Moroo
 
-use std::{
-    fmt,
-    fs::File,
-    io::{self, Read, Seek, SeekFrom},
-};
+use std::{fmt, io};
 
 use bitfield_struct::bitfield;
 
-use crate::hfi::HfiInfo;
+use crate::{backend, hfi::HfiInfo};
 
 const NUM_CAPS: usize = 2;
-const NUM_CLASSES: usize = 4;
+pub const NUM_CLASSES: usize = 4;
 
 #[derive(Debug)]
 #[repr(C)]
@@ -34,13 +30,20 @@ impl<const NUM_CPUS: usize> EhfiTable<NUM_CPUS> {
     pub fn read(&mut self, info: &HfiInfo) -> io::Result<()> {
         self.header.read(info)?;
         for cpu in 0..Self::NUM_CPUS {
-            let info = HfiInfo::new(cpu)?;
-            self.entries[cpu].read(&info)?;
+            if let Some(info) = info.sibling(cpu)? {
+                self.entries[cpu].read(&info)?;
+            }
         }
         Ok(())
     }
 }
 
+impl<const NUM_CPUS: usize> Default for EhfiTable<NUM_CPUS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<const NUM_CPUS: usize> fmt::Display for EhfiTable<NUM_CPUS> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "{}", self.header)?;
@@ -78,9 +81,7 @@ impl EhfiHeader {
 
     fn read(&mut self, info: &HfiInfo) -> io::Result<()> {
         let mut buf = [0u8; Self::SIZE];
-        let mut fd = File::open("/dev/mem")?;
-        fd.seek(SeekFrom::Start(info.addr as u64))?;
-        fd.read_exact(&mut buf)?;
+        backend::get().read_mem(info.addr as u64, &mut buf)?;
         let header = unsafe { std::mem::transmute::<[u8; Self::SIZE], Self>(buf) };
         *self = header;
         Ok(())
@@ -117,15 +118,27 @@ pub struct EhfiEntry {
 impl EhfiEntry {
     const SIZE: usize = std::mem::size_of::<Self>();
 
+    pub fn perf_cap(&self, class: usize) -> u8 {
+        self.caps[class].cap[0]
+    }
+
+    pub fn ee_cap(&self, class: usize) -> u8 {
+        self.caps[class].cap[1]
+    }
+
+    /// Returns the entry in `row` of the raw table `table`, if it is long enough.
+    pub fn from_table(table: &[u8], row: usize) -> Option<Self> {
+        let offset = std::mem::size_of::<EhfiHeader>() + Self::SIZE * row;
+        let buf: [u8; Self::SIZE] = table.get(offset..offset + Self::SIZE)?.try_into().ok()?;
+        Some(unsafe { std::mem::transmute::<[u8; Self::SIZE], Self>(buf) })
+    }
+
     fn read(&mut self, info: &HfiInfo) -> io::Result<()> {
         let mut buf = [0u8; Self::SIZE];
-        let mut fd = File::open("/dev/mem")?;
-        fd.seek(SeekFrom::Start(
-            info.addr as u64
-                + std::mem::size_of::<EhfiHeader>() as u64
-                + std::mem::size_of::<Self>() as u64 * info.cpu as u64,
-        ))?;
-        fd.read_exact(&mut buf)?;
+        let addr = info.addr as u64
+            + std::mem::size_of::<EhfiHeader>() as u64
+            + std::mem::size_of::<Self>() as u64 * info.index() as u64;
+        backend::get().read_mem(addr, &mut buf)?;
         let entry = unsafe { std::mem::transmute::<[u8; Self::SIZE], Self>(buf) };
         *self = entry;
         Ok(())
@@ -0,0 +1,382 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Energy-performance preference (EPP) tuning from HFI capabilities
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{advise::Capability, cpulist};
+
+const PREFERENCE: &str = "energy_performance_preference";
+
+/// Preference names accepted by `intel_pstate` and their EPP values
+const NAMES: [(&str, u8); 4] = [
+    ("performance", 0),
+    ("balance_performance", 128),
+    ("balance_power", 192),
+    ("power", 255),
+];
+
+/// Returns the preference name closest to the EPP value `epp`.
+pub fn name(epp: u8) -> &'static str {
+    NAMES
+        .iter()
+        .min_by_key(|(_, value)| value.abs_diff(epp))
+        .map(|(name, _)| *name)
+        .unwrap()
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Profile {
+    Performance,
+    #[default]
+    Balanced,
+    Powersave,
+}
+
+impl Profile {
+    /// EPP values of the most performant and the most efficient CPUs
+    fn range(&self) -> (u8, u8) {
+        match self {
+            Self::Performance => (0, 128),
+            Self::Balanced => (64, 192),
+            Self::Powersave => (128, 255),
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "performance" => Ok(Self::Performance),
+            "balanced" => Ok(Self::Balanced),
+            "powersave" => Ok(Self::Powersave),
+            _ => Err(format!("invalid profile: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Performance => write!(f, "performance"),
+            Self::Balanced => write!(f, "balanced"),
+            Self::Powersave => write!(f, "powersave"),
+        }
+    }
+}
+
+/// Maps the capabilities of every CPU to an EPP value within the range of
+/// `profile`.
+///
+/// CPUs that are relatively more efficient than performant get a value
+/// closer to the efficient end of the range, and CPUs with zero capability
+/// get the most efficient value.
+pub fn preferences(capabilities: &[Capability], profile: Profile) -> BTreeMap<usize, u8> {
+    let (low, high) = profile.range();
+    let max_perf = capabilities.iter().map(|c| c.perf).max().unwrap_or(0) as f64;
+    let max_ee = capabilities.iter().map(|c| c.ee).max().unwrap_or(0) as f64;
+    capabilities
+        .iter()
+        .map(|capability| {
+            if capability.is_zero() {
+                return (capability.cpu, high);
+            }
+            let perf = capability.perf as f64 / max_perf;
+            let ee = capability.ee as f64 / max_ee;
+            let lean = ee / (perf + ee);
+            let epp = low as f64 + lean * (high - low) as f64;
+            (capability.cpu, epp.round() as u8)
+        })
+        .collect()
+}
+
+/// cpufreq policy directory and the CPUs it covers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Policy {
+    pub path: PathBuf,
+    pub cpus: Vec<usize>,
+}
+
+impl Policy {
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    pub fn read_preference(&self) -> io::Result<String> {
+        Ok(fs::read_to_string(self.path.join(PREFERENCE))?
+            .trim()
+            .to_string())
+    }
+
+    pub fn write_preference(&self, preference: &str) -> io::Result<()> {
+        fs::write(self.path.join(PREFERENCE), format!("{preference}\n"))
+    }
+}
+
+/// Lists the cpufreq policies with an energy-performance preference under
+/// `sysfs`.
+pub fn policies(sysfs: &Path) -> io::Result<Vec<Policy>> {
+    let dir = sysfs.join("devices/system/cpu/cpufreq");
+    let mut policies = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        let is_policy = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("policy"));
+        if !is_policy || !path.join(PREFERENCE).exists() {
+            continue;
+        }
+        let cpus = fs::read_to_string(path.join("affected_cpus"))?
+            .split_whitespace()
+            .map(|cpu| cpu.parse())
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid affected_cpus in {}", path.display()),
+                )
+            })?;
+        policies.push(Policy { path, cpus });
+    }
+    if policies.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no cpufreq policy with {PREFERENCE} in {}; is intel_pstate in active mode?",
+                dir.display()
+            ),
+        ));
+    }
+    policies.sort_by(|a, b| a.cpus.cmp(&b.cpus));
+    Ok(policies)
+}
+
+/// Preference change of a policy
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub policy: String,
+    pub cpus: Vec<usize>,
+    pub from: String,
+    pub to: String,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (CPUs {}): {} -> {}",
+            self.policy,
+            cpulist::format(&self.cpus),
+            self.from,
+            self.to
+        )
+    }
+}
+
+/// Writes the preferences of a profile and remembers the original ones.
+pub struct Tuner {
+    policies: Vec<Policy>,
+    profile: Profile,
+    /// Write raw EPP values instead of preference names
+    raw: bool,
+    dry_run: bool,
+    /// Preference of each policy before the first change
+    original: BTreeMap<PathBuf, String>,
+}
+
+impl Tuner {
+    pub fn new(sysfs: &Path, profile: Profile, raw: bool, dry_run: bool) -> io::Result<Self> {
+        Ok(Self {
+            policies: policies(sysfs)?,
+            profile,
+            raw,
+            dry_run,
+            original: BTreeMap::new(),
+        })
+    }
+
+    fn write(&self, policy: &Policy, to: String) -> io::Result<Option<Change>> {
+        let from = policy.read_preference()?;
+        if from == to {
+            return Ok(None);
+        }
+        if !self.dry_run {
+            policy.write_preference(&to)?;
+        }
+        Ok(Some(Change {
+            policy: policy.name(),
+            cpus: policy.cpus.clone(),
+            from,
+            to,
+        }))
+    }
+
+    /// Writes the preferences for `capabilities` and returns the changes. The
+    /// preference of a policy covering several CPUs is their average. In
+    /// dry-run mode, nothing is written.
+    pub fn apply(&mut self, capabilities: &[Capability]) -> io::Result<Vec<Change>> {
+        let preferences = preferences(capabilities, self.profile);
+        let mut changes = Vec::new();
+        for policy in self.policies.clone() {
+            let values: Vec<u32> = policy
+                .cpus
+                .iter()
+                .filter_map(|cpu| preferences.get(cpu))
+                .map(|epp| *epp as u32)
+                .collect();
+            if values.is_empty() {
+                continue;
+            }
+            let epp = (values.iter().sum::<u32>() / values.len() as u32) as u8;
+            let to = match self.raw {
+                true => epp.to_string(),
+                false => name(epp).to_string(),
+            };
+            if let Some(change) = self.write(&policy, to)? {
+                if !self.dry_run {
+                    self.original
+                        .entry(policy.path.clone())
+                        .or_insert_with(|| change.from.clone());
+                }
+                changes.push(change);
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Writes back the preferences from before the first change.
+    pub fn restore(&mut self) -> io::Result<Vec<Change>> {
+        let mut changes = Vec::new();
+        for policy in &self.policies {
+            if let Some(original) = self.original.remove(&policy.path) {
+                changes.extend(self.write(policy, original)?);
+            }
+        }
+        Ok(changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn capabilities() -> Vec<Capability> {
+        vec![
+            Capability {
+                cpu: 0,
+                perf: 200,
+                ee: 100,
+            },
+            Capability {
+                cpu: 1,
+                perf: 100,
+                ee: 200,
+            },
+            Capability {
+                cpu: 2,
+                perf: 0,
+                ee: 0,
+            },
+        ]
+    }
+
+    /// policy0 covers CPU 0, policy1 covers CPU 1-2 and policy2 has no EPP.
+    fn sysfs() -> TempDir {
+        let sysfs = TempDir::new();
+        let dir = "devices/system/cpu/cpufreq";
+        sysfs.write(&format!("{dir}/policy0/affected_cpus"), "0\n");
+        sysfs.write(
+            &format!("{dir}/policy0/{PREFERENCE}"),
+            "balance_performance\n",
+        );
+        sysfs.write(&format!("{dir}/policy1/affected_cpus"), "1 2\n");
+        sysfs.write(
+            &format!("{dir}/policy1/{PREFERENCE}"),
+            "balance_performance\n",
+        );
+        sysfs.write(&format!("{dir}/policy2/affected_cpus"), "3\n");
+        sysfs
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(name(0), "performance");
+        assert_eq!(name(100), "balance_performance");
+        assert_eq!(name(170), "balance_power");
+        assert_eq!(name(255), "power");
+        assert_eq!("powersave".parse(), Ok(Profile::Powersave));
+        assert!("fast".parse::<Profile>().is_err());
+    }
+
+    #[test]
+    fn preference_values() {
+        let balanced = preferences(&capabilities(), Profile::Balanced);
+        assert_eq!(balanced, BTreeMap::from([(0, 107), (1, 149), (2, 192)]));
+        let performance = preferences(&capabilities(), Profile::Performance);
+        assert_eq!(performance[&0], 43);
+        assert_eq!(performance[&2], 128);
+    }
+
+    #[test]
+    fn list_policies() {
+        let sysfs = sysfs();
+        let listed = policies(sysfs.path()).unwrap();
+        let names: Vec<String> = listed.iter().map(Policy::name).collect();
+        assert_eq!(names, ["policy0", "policy1"]);
+        assert_eq!(listed[1].cpus, [1, 2]);
+
+        let empty = TempDir::new();
+        empty.write("devices/system/cpu/cpufreq/policy0/affected_cpus", "0\n");
+        let err = policies(empty.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn apply_and_restore() {
+        let sysfs = sysfs();
+        let mut tuner = Tuner::new(sysfs.path(), Profile::Balanced, false, false).unwrap();
+        let changes = tuner.apply(&capabilities()).unwrap();
+        // policy1 averages 149 and 192.
+        assert_eq!(
+            changes,
+            [Change {
+                policy: "policy1".to_string(),
+                cpus: vec![1, 2],
+                from: "balance_performance".to_string(),
+                to: "balance_power".to_string(),
+            }]
+        );
+        let path = "devices/system/cpu/cpufreq/policy1/energy_performance_preference";
+        assert_eq!(sysfs.read(path), "balance_power\n");
+        assert_eq!(tuner.apply(&capabilities()).unwrap(), []);
+
+        let changes = tuner.restore().unwrap();
+        assert_eq!(changes[0].to, "balance_performance");
+        assert_eq!(sysfs.read(path), "balance_performance\n");
+        assert_eq!(tuner.restore().unwrap(), []);
+    }
+
+    #[test]
+    fn raw_dry_run() {
+        let sysfs = sysfs();
+        let mut tuner = Tuner::new(sysfs.path(), Profile::Balanced, true, true).unwrap();
+        let changes = tuner.apply(&capabilities()).unwrap();
+        let to: Vec<&str> = changes.iter().map(|change| change.to.as_str()).collect();
+        assert_eq!(to, ["107", "170"]);
+        let path = "devices/system/cpu/cpufreq/policy0/energy_performance_preference";
+        assert_eq!(sysfs.read(path), "balance_performance\n");
+        assert_eq!(tuner.restore().unwrap(), []);
+    }
+}
@@ -0,0 +1,236 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Evacuation of tasks from CPUs the hardware asks to idle
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, io,
+    path::PathBuf,
+};
+
+use crate::{
+    affinity, cpulist,
+    daemon::Matcher,
+    hfi::HfiTable,
+    process::{self, Process},
+};
+
+/// CPUs of `cpus` the hardware asks to idle in `table`: those with zero
+/// performance or energy efficiency capability. The idle request bits of the
+/// header only tell that such rows are expected, so they select no CPU.
+pub fn idle_cpus<const NUM_CPUS: usize>(table: &HfiTable<NUM_CPUS>, cpus: &[usize]) -> Vec<usize> {
+    let entries = table.entries;
+    cpus.iter()
+        .copied()
+        .filter(|cpu| entries[*cpu].perf_cap() == 0 || entries[*cpu].ee_cap() == 0)
+        .collect()
+}
+
+/// Affinity change of a task
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Action {
+    pub pid: i32,
+    pub tid: i32,
+    pub comm: String,
+    pub from: Vec<usize>,
+    pub to: Vec<usize>,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pid {} tid {} ({}): {} -> {}",
+            self.pid,
+            self.tid,
+            self.comm,
+            cpulist::format(&self.from),
+            cpulist::format(&self.to)
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Task {
+    /// Affinity before the first evacuation
+    original: Vec<usize>,
+    /// Affinity set by the evacuator
+    applied: Vec<usize>,
+}
+
+/// Removes idle CPUs from the affinity of tasks and restores them once the
+/// capability returns.
+pub struct Evacuator {
+    procfs: PathBuf,
+    /// Processes to act on; all movable processes if empty
+    targets: Vec<Matcher>,
+    dry_run: bool,
+    tasks: BTreeMap<i32, Task>,
+}
+
+impl Evacuator {
+    pub fn new(procfs: PathBuf, targets: Vec<Matcher>, dry_run: bool) -> Self {
+        Self {
+            procfs,
+            targets,
+            dry_run,
+            tasks: BTreeMap::new(),
+        }
+    }
+
+    fn is_target(&self, process: &Process) -> bool {
+        match self.targets.is_empty() {
+            true => !process.kernel_thread,
+            false => self.targets.iter().any(|target| target.matches(process)),
+        }
+    }
+
+    /// Updates the affinity of the target tasks for `idle` CPUs and returns
+    /// the changes. In dry-run mode, the changes are only returned.
+    ///
+    /// Tasks that would be left without any CPU are not changed. Tasks whose
+    /// affinity was changed by someone else since are left alone.
+    pub fn update(&mut self, idle: &[usize]) -> io::Result<Vec<Action>> {
+        let mut actions = Vec::new();
+        let mut seen = BTreeSet::new();
+        for process in process::list(&self.procfs)? {
+            if !self.is_target(&process) {
+                continue;
+            }
+            let Ok(tids) = affinity::threads(&self.procfs, process.pid) else {
+                continue;
+            };
+            for tid in tids {
+                let Ok(current) = process::allowed_cpus(&self.procfs, process.pid, tid) else {
+                    continue;
+                };
+                seen.insert(tid);
+                let original = match self.tasks.get(&tid) {
+                    Some(task) if task.applied == current => task.original.clone(),
+                    _ => {
+                        self.tasks.remove(&tid);
+                        current.clone()
+                    }
+                };
+                let to: Vec<usize> = original
+                    .iter()
+                    .copied()
+                    .filter(|cpu| !idle.contains(cpu))
+                    .collect();
+                if to.is_empty() || to == current {
+                    continue;
+                }
+
+                let action = Action {
+                    pid: process.pid,
+                    tid,
+                    comm: process.comm.clone(),
+                    from: current,
+                    to,
+                };
+                if !self.dry_run {
+                    match affinity::set(tid, &action.to) {
+                        Ok(()) => {}
+                        Err(err) if err.raw_os_error() == Some(libc::ESRCH) => continue,
+                        Err(err) => {
+                            eprintln!("{action}: {err}");
+                            continue;
+                        }
+                    }
+                    match action.to == original {
+                        true => self.tasks.remove(&tid),
+                        false => self.tasks.insert(
+                            tid,
+                            Task {
+                                original,
+                                applied: action.to.clone(),
+                            },
+                        ),
+                    };
+                }
+                actions.push(action);
+            }
+        }
+        self.tasks.retain(|tid, _| seen.contains(tid));
+        Ok(actions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hfi::{HfiEntry, HfiHeader},
+        testutil::TempDir,
+    };
+
+    const CPUS: [usize; 4] = [0, 1, 2, 3];
+
+    fn table(caps: [(u8, u8); 4], perf_idle: bool, ee_idle: bool) -> HfiTable<4> {
+        let mut table = HfiTable::<4>::new();
+        table.header = HfiHeader::new(1, perf_idle, ee_idle);
+        for (entry, (perf, ee)) in table.entries.iter_mut().zip(caps) {
+            *entry = HfiEntry::new(perf, ee);
+        }
+        table
+    }
+
+    #[test]
+    fn zero_capability() {
+        let caps = [(200, 100), (0, 100), (150, 0), (120, 90)];
+        assert_eq!(idle_cpus(&table(caps, false, false), &CPUS), [1, 2]);
+        assert_eq!(idle_cpus(&table(caps, false, false), &[0, 2]), [2]);
+        assert_eq!(idle_cpus(&table([(200, 100); 4], false, false), &CPUS), []);
+    }
+
+    #[test]
+    fn idle_request() {
+        // The lowest rows are not idled before they reach 0.
+        let caps = [(200, 100), (120, 110), (150, 90), (120, 90)];
+        assert_eq!(idle_cpus(&table(caps, true, false), &CPUS), []);
+        assert_eq!(idle_cpus(&table(caps, true, true), &CPUS), []);
+        let caps = [(200, 100), (0, 0), (150, 90), (120, 90)];
+        assert_eq!(idle_cpus(&table(caps, true, true), &CPUS), [1]);
+    }
+
+    fn procfs() -> TempDir {
+        let procfs = TempDir::new();
+        for (pid, comm, flags, tasks) in [
+            (1, "init", 0x400100, &[(1, "0-3")][..]),
+            (2, "kthreadd", 0x208040, &[(2, "0-3")][..]),
+            (20, "worker", 0x400000, &[(20, "0-3"), (21, "1")][..]),
+        ] {
+            procfs.write(&format!("{pid}/comm"), &format!("{comm}\n"));
+            procfs.write(
+                &format!("{pid}/stat"),
+                &format!("{pid} ({comm}) S 0 0 0 0 -1 {flags} 0 0"),
+            );
+            for (tid, cpus) in tasks {
+                procfs.write(
+                    &format!("{pid}/task/{tid}/status"),
+                    &format!("Name:\t{comm}\nCpus_allowed_list:\t{cpus}\n"),
+                );
+            }
+        }
+        procfs
+    }
+
+    #[test]
+    fn dry_run() {
+        let procfs = procfs();
+        let mut evacuator = Evacuator::new(procfs.path().to_path_buf(), Vec::new(), true);
+        let actions = evacuator.update(&[1, 2]).unwrap();
+        // Kernel threads are skipped, and so is the task only allowed on CPU 1.
+        let tids: Vec<i32> = actions.iter().map(|action| action.tid).collect();
+        assert_eq!(tids, [1, 20]);
+        assert_eq!(actions[1].to_string(), "pid 20 tid 20 (worker): 0-3 -> 0,3");
+        assert!(evacuator.update(&[]).unwrap().is_empty());
+
+        let targets = vec![Matcher::Name("worker".to_string())];
+        let mut evacuator = Evacuator::new(procfs.path().to_path_buf(), targets, true);
+        let actions = evacuator.update(&[0]).unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].to, [1, 2, 3]);
+    }
+}
@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Launching commands on HFI-preferred CPUs
+
+use std::{
+    io,
+    path::Path,
+    process::{Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{advise::Selection, affinity, cpulist, hfi::HfiInfo, topology::CpuTopology};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn report(pid: u32, cpus: &[usize]) {
+    eprintln!(
+        "intel-hfi: pid {pid} pinned to CPUs {} (mask {})",
+        cpulist::format(cpus),
+        cpulist::mask(cpus)
+    );
+}
+
+/// Runs `command` on the CPUs chosen by `selection` and waits for it to exit.
+///
+/// With `repin`, the selection is re-evaluated at that interval and every
+/// thread of the child is moved when the chosen CPUs change. Failures to
+/// repin are reported and the child is still waited for.
+pub fn run<const NUM_CPUS: usize>(
+    hfi_info: &HfiInfo,
+    selection: &Selection,
+    repin: Option<Duration>,
+    command: &[String],
+) -> io::Result<ExitStatus> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no command given"))?;
+    let topology = CpuTopology::read_present(NUM_CPUS)?;
+    let mut cpus = selection.read::<NUM_CPUS>(hfi_info, &topology)?;
+    if cpus.is_empty() {
+        return Err(io::Error::other("no usable CPU"));
+    }
+
+    // The child inherits the affinity of the calling thread.
+    let original = affinity::get(0)?;
+    affinity::set(0, &cpus)?;
+    let child = Command::new(program).args(args).spawn();
+    affinity::set(0, &original)?;
+    let mut child = child?;
+    report(child.id(), &cpus);
+
+    let Some(repin) = repin else {
+        return child.wait();
+    };
+    let mut next = Instant::now() + repin;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= next {
+            next = Instant::now() + repin;
+            // Returning here would leave the child running unattended, so it
+            // just stays on its current CPUs.
+            match selection.read::<NUM_CPUS>(hfi_info, &topology) {
+                Ok(selected) if !selected.is_empty() && selected != cpus => {
+                    match affinity::set_process(Path::new("/proc"), child.id() as i32, &selected) {
+                        Ok(()) => {
+                            cpus = selected;
+                            report(child.id(), &cpus);
+                        }
+                        Err(err) => {
+                            eprintln!("intel-hfi: failed to repin pid {}: {err}", child.id())
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => eprintln!("intel-hfi: failed to read the table: {err}"),
+            }
+        }
+        thread::sleep(POLL_INTERVAL.min(repin));
+    }
+}
@@ -0,0 +1,394 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Simulated hybrid CPU for demos and tests
+//!
+//! Models a single package with 8 SMT-enabled Core CPUs (CPU 0-15) followed by
+//! 16 Atom CPUs (CPU 16-31). The HFI/EHFI table is regenerated every
+//! [`FakeBackend::PERIOD`] with deterministic variations, including periodic
+//! throttling of the Core CPUs and a CPU that is requested to idle.
+//!
+//! The table starts at the epoch given as the seed and advances with the time
+//! since the backend was created, so that every run replays the same sequence.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{backend::Backend, ehfi::NUM_CLASSES, msr};
+
+pub struct FakeBackend {
+    seed: u64,
+    /// Start of the simulation, or `None` if the time is frozen at the seed
+    start: Option<Instant>,
+    msrs: Mutex<HashMap<(usize, u32), u64>>,
+}
+
+impl FakeBackend {
+    pub const NUM_CPUS: usize = 32;
+    pub const NUM_CORE_CPUS: usize = 16;
+    pub const TABLE_ADDR: u64 = 0x4000_0000;
+    pub const PERIOD: Duration = Duration::from_secs(1);
+
+    const HEADER_SIZE: usize = 16;
+    const ROW_SIZE: usize = 8;
+    const TABLE_SIZE: usize = 4096;
+    const BRAND: &[u8] = b"Simulated Hybrid CPU (intel-hfi --fake)";
+    const MICROCODE: u64 = 0x2c;
+    const TJ_MAX: u64 = 100;
+
+    /// Creates a simulation starting at epoch `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            start: Some(Instant::now()),
+            msrs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Creates a simulation that stays at epoch `seed`.
+    pub fn frozen(seed: u64) -> Self {
+        Self {
+            seed,
+            start: None,
+            msrs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.start.map_or(Duration::ZERO, |start| start.elapsed())
+    }
+
+    fn epoch(&self) -> u64 {
+        self.seed + (self.elapsed().as_millis() / Self::PERIOD.as_millis()) as u64
+    }
+
+    /// Whether the HFI change status is set, as it is until the OS
+    /// acknowledges an update shortly after the table is regenerated
+    fn is_signalled(&self) -> bool {
+        self.elapsed().as_millis() % Self::PERIOD.as_millis() < 100
+    }
+
+    fn is_core(cpu: usize) -> bool {
+        cpu < Self::NUM_CORE_CPUS
+    }
+
+    fn x2apic_id(cpu: usize) -> u32 {
+        if Self::is_core(cpu) {
+            cpu as u32
+        } else {
+            (Self::NUM_CORE_CPUS + (cpu - Self::NUM_CORE_CPUS) * 2) as u32
+        }
+    }
+
+    /// Whether a CPU is requested to idle in `epoch`
+    fn is_idle_epoch(epoch: u64) -> bool {
+        epoch % 20 == 19
+    }
+
+    /// Returns the (performance, energy efficiency) capability of `cpu` for `class`.
+    fn capability(epoch: u64, cpu: usize, class: usize) -> (u8, u8) {
+        const CORE: [(u32, u32); NUM_CLASSES] = [(200, 120), (240, 110), (255, 100), (180, 130)];
+        const ATOM: [(u32, u32); NUM_CLASSES] = [(130, 200), (110, 210), (80, 190), (140, 220)];
+
+        if Self::is_idle_epoch(epoch) && mix(epoch) as usize % Self::NUM_CPUS == cpu {
+            return (0, 0);
+        }
+
+        let (mut perf, ee) = match Self::is_core(cpu) {
+            true => CORE[class],
+            false => ATOM[class],
+        };
+        if Self::is_core(cpu) && epoch % 12 >= 8 {
+            perf = perf * 7 / 10;
+        }
+        let jitter = (mix(epoch << 16 | (cpu as u64) << 4 | class as u64) % 17) as u32;
+        let perf = (perf + jitter).saturating_sub(8).min(255);
+        let ee = (ee + jitter).saturating_sub(8).min(255);
+        (perf as u8, ee as u8)
+    }
+
+    /// Returns the temperature of `cpu`, which reaches TCC activation while
+    /// the Core CPUs are throttled.
+    fn temperature(epoch: u64, cpu: usize) -> u64 {
+        match Self::is_core(cpu) {
+            true if epoch % 12 >= 8 => Self::TJ_MAX - 3,
+            true => 60 + mix(epoch << 8 | cpu as u64) % 8,
+            false => 50 + mix(epoch << 8 | cpu as u64) % 6,
+        }
+    }
+
+    /// Returns the thermal status bits for `temperature`.
+    fn therm_status(temperature: u64) -> u64 {
+        let throttling = match temperature + 5 >= Self::TJ_MAX {
+            true => 0x3,
+            false => 0,
+        };
+        (Self::TJ_MAX - temperature) << 16 | throttling
+    }
+
+    fn table(&self) -> Vec<u8> {
+        let epoch = self.epoch();
+        // The table occupies the whole page advertised by CPUID.
+        let mut table = vec![0u8; Self::TABLE_SIZE];
+        table[..8].copy_from_slice(&epoch.to_le_bytes());
+        table[8] = 0x1;
+        table[9] = 0x1;
+        if Self::is_idle_epoch(epoch) {
+            table[8] |= 0x2;
+        }
+        for cpu in 0..Self::NUM_CPUS {
+            let row = Self::HEADER_SIZE + Self::ROW_SIZE * cpu;
+            for class in 0..NUM_CLASSES {
+                let (perf, ee) = Self::capability(epoch, cpu, class);
+                table[row + class * 2] = perf;
+                table[row + class * 2 + 1] = ee;
+            }
+        }
+        table
+    }
+}
+
+impl Default for FakeBackend {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Backend for FakeBackend {
+    fn cpuid(&self, cpu: usize, eax: u32, ecx: u32) -> io::Result<[u32; 4]> {
+        if cpu >= Self::NUM_CPUS {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        let x2apic_id = Self::x2apic_id(cpu);
+        let value = match (eax, ecx) {
+            // HFI, ITD, 4 classes, 1 page table, row index = CPU number
+            (0x06, 0) => [
+                1 << 19 | 1 << 23,
+                0,
+                (NUM_CLASSES as u32) << 8,
+                0x3 | (cpu as u32) << 16,
+            ],
+            // GenuineIntel
+            (0x00, 0) => [0x20, 0x756e_6547, 0x6c65_746e, 0x4965_6e69],
+            // Family 6, model 0x97, stepping 2
+            (0x01, 0) => [0x0009_0672, 0, 0, 0],
+            (0x07, 1) => [1 << 22, 0, 0, 0],
+            (0x0b, 0) => [1, 0, 0, x2apic_id],
+            (0x0b, 1) => [7, 0, 0, x2apic_id],
+            (0x20, 0) => [0, 0x1, 0, 0],
+            (0x1a, 0) => match Self::is_core(cpu) {
+                true => [0x40 << 24, 0, 0, 0],
+                false => [0x20 << 24, 0, 0, 0],
+            },
+            (0x8000_0002..=0x8000_0004, 0) => {
+                let offset = (eax - 0x8000_0002) as usize * 16;
+                let mut regs = [0u32; 4];
+                for (i, reg) in regs.iter_mut().enumerate() {
+                    let mut bytes = [0u8; 4];
+                    for (j, byte) in bytes.iter_mut().enumerate() {
+                        *byte = *Self::BRAND.get(offset + i * 4 + j).unwrap_or(&0);
+                    }
+                    *reg = u32::from_le_bytes(bytes);
+                }
+                regs
+            }
+            _ => [0; 4],
+        };
+        Ok(value)
+    }
+
+    fn read_msr(&self, cpu: usize, addr: u32) -> io::Result<u64> {
+        if cpu >= Self::NUM_CPUS {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        if let Some(value) = self.msrs.lock().unwrap().get(&(cpu, addr)) {
+            return Ok(*value);
+        }
+        let value = match addr {
+            msr::IA32_BIOS_SIGN_ID => Self::MICROCODE << 32,
+            msr::IA32_THERM_INTERRUPT => 0,
+            // Reading valid, 1 degree resolution
+            msr::IA32_THERM_STATUS => {
+                let temperature = Self::temperature(self.epoch(), cpu);
+                1 << 31 | 1 << 27 | Self::therm_status(temperature)
+            }
+            msr::MSR_TEMPERATURE_TARGET => Self::TJ_MAX << 16,
+            msr::IA32_PACKAGE_THERM_STATUS => {
+                let epoch = self.epoch();
+                let temperature = (0..Self::NUM_CPUS)
+                    .map(|cpu| Self::temperature(epoch, cpu))
+                    .max()
+                    .unwrap_or_default();
+                (self.is_signalled() as u64) << 26 | Self::therm_status(temperature)
+            }
+            // HFI interrupt enabled
+            msr::IA32_PACKAGE_THERM_INTERRUPT => 1 << 25,
+            msr::IA32_HW_FEEDBACK_PTR => Self::TABLE_ADDR | 0x1,
+            msr::IA32_HW_FEEDBACK_CONFIG => 0x1,
+            msr::IA32_HW_FEEDBACK_THREAD_CONFIG => 0x1,
+            msr::IA32_HRESET_ENABLE => 0x1,
+            msr::IA32_THREAD_FEEDBACK_CHAR => {
+                let r = mix(self.epoch() << 8 | cpu as u64);
+                let valid = !r.is_multiple_of(8) as u64;
+                (valid << 63) | ((r >> 8) % NUM_CLASSES as u64)
+            }
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+        Ok(value)
+    }
+
+    fn write_msr(&self, cpu: usize, addr: u32, value: u64) -> io::Result<()> {
+        if cpu >= Self::NUM_CPUS {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        self.msrs.lock().unwrap().insert((cpu, addr), value);
+        Ok(())
+    }
+
+    fn read_mem(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        let table = self.table();
+        let start =
+            addr.checked_sub(Self::TABLE_ADDR)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))? as usize;
+        let src = table
+            .get(start..start + buf.len())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn hreset(&self, _bits: u32) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// SplitMix64 finalizer
+fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(backend: &FakeBackend) -> Vec<u8> {
+        let mut buf = vec![0u8; FakeBackend::TABLE_SIZE];
+        backend.read_mem(FakeBackend::TABLE_ADDR, &mut buf).unwrap();
+        buf
+    }
+
+    fn row(table: &[u8], cpu: usize) -> (u8, u8) {
+        let row = FakeBackend::HEADER_SIZE + FakeBackend::ROW_SIZE * cpu;
+        (table[row], table[row + 1])
+    }
+
+    #[test]
+    fn same_seed_same_table() {
+        assert_eq!(
+            table(&FakeBackend::frozen(3)),
+            table(&FakeBackend::frozen(3))
+        );
+        assert_ne!(
+            table(&FakeBackend::frozen(3)),
+            table(&FakeBackend::frozen(4))
+        );
+    }
+
+    #[test]
+    fn starts_at_seed() {
+        let table = table(&FakeBackend::new(42));
+        assert_eq!(u64::from_le_bytes(table[..8].try_into().unwrap()), 42);
+    }
+
+    #[test]
+    fn idle_request() {
+        let table = table(&FakeBackend::frozen(19));
+        assert_eq!(table[8] & 0x2, 0x2);
+        let idle: Vec<usize> = (0..FakeBackend::NUM_CPUS)
+            .filter(|cpu| row(&table, *cpu) == (0, 0))
+            .collect();
+        assert_eq!(idle.len(), 1);
+
+        let table = self::table(&FakeBackend::frozen(0));
+        assert_eq!(table[8] & 0x2, 0);
+        assert!((0..FakeBackend::NUM_CPUS).all(|cpu| row(&table, cpu) != (0, 0)));
+    }
+
+    #[test]
+    fn core_cpus_throttle() {
+        let normal = table(&FakeBackend::frozen(0));
+        let throttled = table(&FakeBackend::frozen(8));
+        assert!(row(&throttled, 0).0 < row(&normal, 0).0);
+        assert!(row(&normal, 0).0 > row(&normal, 16).0);
+        assert!(row(&normal, 0).1 < row(&normal, 16).1);
+    }
+
+    #[test]
+    fn cpuid() {
+        let backend = FakeBackend::frozen(0);
+        let [_, ebx, ecx, edx] = backend.cpuid(0, 0, 0).unwrap();
+        let vendor: Vec<u8> = [ebx, edx, ecx]
+            .iter()
+            .flat_map(|reg| reg.to_le_bytes())
+            .collect();
+        assert_eq!(vendor, b"GenuineIntel");
+        assert_eq!(backend.cpuid(0, 0x1a, 0).unwrap()[0] >> 24, 0x40);
+        assert_eq!(backend.cpuid(16, 0x1a, 0).unwrap()[0] >> 24, 0x20);
+        assert_eq!(backend.cpuid(5, 0x06, 0).unwrap()[3] >> 16, 5);
+        assert_eq!(
+            backend
+                .cpuid(FakeBackend::NUM_CPUS, 0, 0)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn msrs() {
+        let backend = FakeBackend::frozen(0);
+        assert_eq!(
+            backend.read_msr(0, msr::IA32_HW_FEEDBACK_PTR).unwrap(),
+            FakeBackend::TABLE_ADDR | 0x1
+        );
+        assert_eq!(
+            backend.read_msr(0, 0x10).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        backend.write_msr(1, msr::IA32_HRESET_ENABLE, 0).unwrap();
+        assert_eq!(backend.read_msr(1, msr::IA32_HRESET_ENABLE).unwrap(), 0);
+        assert_eq!(backend.read_msr(0, msr::IA32_HRESET_ENABLE).unwrap(), 1);
+    }
+
+    #[test]
+    fn temperatures() {
+        let backend = FakeBackend::frozen(8);
+        let status = backend.read_msr(0, msr::IA32_THERM_STATUS).unwrap();
+        assert_eq!(status >> 16 & 0x7f, 3);
+        assert_eq!(status & 0x1, 0x1);
+        let status = backend.read_msr(16, msr::IA32_THERM_STATUS).unwrap();
+        assert_eq!(status & 0x1, 0);
+        // The time is frozen at the start of the epoch.
+        let status = backend.read_msr(0, msr::IA32_PACKAGE_THERM_STATUS).unwrap();
+        assert_eq!(status >> 26 & 0x1, 1);
+    }
+
+    #[test]
+    fn read_mem_bounds() {
+        let backend = FakeBackend::frozen(0);
+        let mut buf = [0u8; 8];
+        assert!(backend
+            .read_mem(FakeBackend::TABLE_ADDR - 8, &mut buf)
+            .is_err());
+        let end = FakeBackend::TABLE_ADDR + FakeBackend::TABLE_SIZE as u64;
+        assert!(backend.read_mem(end - 4, &mut buf).is_err());
+        assert!(backend.read_mem(end - 8, &mut buf).is_ok());
+    }
+}
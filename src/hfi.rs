@@ -1,31 +1,42 @@
 // SPDX-License-Identifier: MIT
 // Copyright (C) 2023 Akira Moroo
 
-use std::{
-    fmt,
-    fs::File,
-    io::{self, Read, Seek, SeekFrom},
-};
+use std::{collections::BTreeMap, fmt, io, sync::Mutex};
 
 use bitfield_struct::bitfield;
 
 use crate::{
+    backend,
     cpuid::{self, Cpuid},
+    driver::KernelDriver,
     msr::{self, Msr},
 };
 
-#[allow(dead_code)]
+/// Row of every CPU seen online. The row comes from CPUID, which cannot be
+/// read once the CPU is offline, while the hardware keeps updating the row.
+static ROWS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
+fn record_row(cpu: usize, index: usize) {
+    ROWS.lock().unwrap().insert(cpu, index);
+}
+
+fn recorded_row(cpu: usize) -> Option<usize> {
+    ROWS.lock().unwrap().get(&cpu).copied()
+}
+
 #[derive(Debug)]
 pub struct HfiInfo {
     pub cpu: usize,
     pub addr: usize,
     pub size: usize,
     index: usize,
+    /// State of the kernel driver, shown with the table location if detected
+    driver: Option<KernelDriver>,
 }
 
 impl HfiInfo {
-    const PAGE_SIZE: usize = 4096;
-    const PAGE_SHIFT: usize = Self::PAGE_SIZE.trailing_zeros() as usize;
+    pub const PAGE_SIZE: usize = 4096;
+    pub const PAGE_SHIFT: usize = Self::PAGE_SIZE.trailing_zeros() as usize;
 
     pub fn new(cpu: usize) -> io::Result<Self> {
         let cpuid = cpuid::ThermalCpuid::read(cpu)?;
@@ -40,12 +51,91 @@ impl HfiInfo {
         if !cpuid.has_perf_cap() || !cpuid.has_ee_cap() {
             return Err(io::Error::other("HFI capability is not supported"));
         }
-        Ok(Self {
+        let info = Self {
             cpu,
             addr: (ptr.addr() as usize) << Self::PAGE_SHIFT,
             size: Self::PAGE_SIZE * cpuid.hfi_size(),
             index: cpuid.hfi_row_index(),
-        })
+            driver: None,
+        };
+        info.check()?;
+        record_row(cpu, info.index);
+        Ok(info)
+    }
+
+    /// Checks the table location before anything is read from it.
+    fn check(&self) -> io::Result<()> {
+        if HfiHeader::SIZE + HfiEntry::SIZE * (self.index + 1) > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "HFI table of {:#x} bytes has no row {}",
+                    self.size, self.index
+                ),
+            ));
+        }
+        backend::get()
+            .check_mem(self.addr as u64, self.size as u64)
+            .map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("HFI table at {:#x} is not readable: {err}", self.addr),
+                )
+            })
+    }
+
+    /// Returns the information for `cpu` at row `index` of the same table.
+    pub fn row(&self, cpu: usize, index: usize) -> io::Result<Self> {
+        let info = Self {
+            cpu,
+            addr: self.addr,
+            size: self.size,
+            index,
+            driver: None,
+        };
+        info.check()?;
+        Ok(info)
+    }
+
+    /// Returns the information for `cpu` in the same table, or `None` if
+    /// there is no such CPU. Offline CPUs cannot be queried, so those seen
+    /// online earlier by this process use the row recorded then, and the
+    /// others are treated as missing.
+    pub fn sibling(&self, cpu: usize) -> io::Result<Option<Self>> {
+        match Self::new(cpu) {
+            Ok(info) => Ok(Some(info)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => match recorded_row(cpu) {
+                Some(index) => self.row(cpu, index).map(Some),
+                None => Ok(None),
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns the CPUs in `0..num_cpus` that have a row in the table,
+    /// including offline ones.
+    pub fn present_cpus(&self, num_cpus: usize) -> io::Result<Vec<usize>> {
+        let mut cpus = Vec::new();
+        for cpu in 0..num_cpus {
+            if self.sibling(cpu)?.is_some() {
+                cpus.push(cpu);
+            }
+        }
+        Ok(cpus)
+    }
+
+    /// Attaches the detected state of the kernel driver, which is not
+    /// detected by [`Self::new`] since that queries every CPU.
+    pub fn with_driver(self, driver: KernelDriver) -> Self {
+        Self {
+            driver: Some(driver),
+            ..self
+        }
+    }
+
+    /// Returns the row of the CPU in the table.
+    pub fn index(&self) -> usize {
+        self.index
     }
 
     pub fn has_itd(&self) -> bool {
@@ -57,9 +147,50 @@ impl HfiInfo {
 }
 
 impl fmt::Display for HfiInfo {
+    /// Also shows the [`HfiSignal`] of the package if it can be read. It is
+    /// read when formatting since it changes with every table update.
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "  Address: {:#x}", self.addr)?;
-        write!(f, "  Size: {:#x}", self.size)
+        write!(f, "  Size: {:#x}", self.size)?;
+        if let Ok(signal) = HfiSignal::read(self.cpu) {
+            write!(f, "\n{signal}")?;
+        }
+        if let Some(driver) = &self.driver {
+            write!(f, "\n  Kernel Driver:")?;
+            for line in driver.to_string().lines() {
+                write!(f, "\n  {line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// HFI bits of the package thermal MSRs
+///
+/// The change status stays set until software clears it, which the kernel
+/// driver does on the thermal interrupt, so it does not tell whether the
+/// table was updated since it was last read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HfiSignal {
+    /// The table was updated and the update has not been acknowledged.
+    pub change: bool,
+    /// Table updates raise a thermal interrupt.
+    pub interrupt: bool,
+}
+
+impl HfiSignal {
+    pub fn read(cpu: usize) -> io::Result<Self> {
+        Ok(Self {
+            change: msr::PackageThermStatus::read(cpu)?.hfi_change(),
+            interrupt: msr::PackageThermInterrupt::read(cpu)?.hfi_enable(),
+        })
+    }
+}
+
+impl fmt::Display for HfiSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  Change Status: {}", self.change)?;
+        write!(f, "  Interrupt Enabled: {}", self.interrupt)
     }
 }
 
@@ -83,13 +214,20 @@ impl<const NUM_CPUS: usize> HfiTable<NUM_CPUS> {
     pub fn read(&mut self, info: &HfiInfo) -> io::Result<()> {
         self.header.read(info)?;
         for cpu in 0..Self::NUM_CPUS {
-            let info = HfiInfo::new(cpu)?;
-            self.entries[cpu].read(&info)?;
+            if let Some(info) = info.sibling(cpu)? {
+                self.entries[cpu].read(&info)?;
+            }
         }
         Ok(())
     }
 }
 
+impl<const NUM_CPUS: usize> Default for HfiTable<NUM_CPUS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<const NUM_CPUS: usize> fmt::Display for HfiTable<NUM_CPUS> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "{}", self.header)?;
@@ -127,11 +265,37 @@ pub struct HfiHeader {
 impl HfiHeader {
     const SIZE: usize = std::mem::size_of::<Self>();
 
+    /// Creates a header with the idle requests of the performance and energy
+    /// efficiency capabilities.
+    pub fn new(timestamp: u64, perf_idle: bool, ee_idle: bool) -> Self {
+        Self {
+            timestamp,
+            perf_cap: CapFlags::new().with_request_idle(perf_idle),
+            ee_cap: CapFlags::new().with_request_idle(ee_idle),
+            _reserved: [0; 6],
+        }
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Whether the hardware requests the OS to idle CPUs with zero capability
+    pub fn idle_requested(&self) -> bool {
+        self.perf_idle_requested() || self.ee_idle_requested()
+    }
+
+    pub fn perf_idle_requested(&self) -> bool {
+        self.perf_cap.request_idle()
+    }
+
+    pub fn ee_idle_requested(&self) -> bool {
+        self.ee_cap.request_idle()
+    }
+
     fn read(&mut self, info: &HfiInfo) -> io::Result<()> {
         let mut buf = [0u8; Self::SIZE];
-        let mut fd = File::open("/dev/mem")?;
-        fd.seek(SeekFrom::Start(info.addr as u64))?;
-        fd.read_exact(&mut buf)?;
+        backend::get().read_mem(info.addr as u64, &mut buf)?;
         let header = unsafe { std::mem::transmute::<[u8; Self::SIZE], Self>(buf) };
         *self = header;
         Ok(())
@@ -160,15 +324,28 @@ pub struct HfiEntry {
 impl HfiEntry {
     const SIZE: usize = std::mem::size_of::<Self>();
 
+    pub fn new(perf_cap: u8, ee_cap: u8) -> Self {
+        Self {
+            perf_cap,
+            ee_cap,
+            _reserved: [0; 6],
+        }
+    }
+
+    pub fn perf_cap(&self) -> u8 {
+        self.perf_cap
+    }
+
+    pub fn ee_cap(&self) -> u8 {
+        self.ee_cap
+    }
+
     fn read(&mut self, info: &HfiInfo) -> io::Result<()> {
         let mut buf = [0u8; Self::SIZE];
-        let mut fd = File::open("/dev/mem")?;
-        fd.seek(SeekFrom::Start(
-            info.addr as u64
-                + std::mem::size_of::<HfiHeader>() as u64
-                + std::mem::size_of::<Self>() as u64 * info.cpu as u64,
-        ))?;
-        fd.read_exact(&mut buf)?;
+        let addr = info.addr as u64
+            + std::mem::size_of::<HfiHeader>() as u64
+            + std::mem::size_of::<Self>() as u64 * info.index as u64;
+        backend::get().read_mem(addr, &mut buf)?;
         let entry = unsafe { std::mem::transmute::<[u8; Self::SIZE], Self>(buf) };
         *self = entry;
         Ok(())
@@ -181,3 +358,80 @@ impl fmt::Display for HfiEntry {
         write!(f, "    Energy Efficiency Capability: {}", self.ee_cap)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ehfi::EhfiTable, testutil::install_fake};
+
+    #[test]
+    fn row() {
+        install_fake();
+        let info = HfiInfo::new(0).unwrap();
+        assert_eq!(info.size, HfiInfo::PAGE_SIZE);
+        let row = info.row(5, 9).unwrap();
+        assert_eq!((row.cpu, row.index(), row.addr), (5, 9, info.addr));
+        // The 16-byte header leaves room for 510 rows of 8 bytes.
+        assert!(info.row(5, 509).is_ok());
+        let err = info.row(5, 510).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn sibling() {
+        install_fake();
+        let info = HfiInfo::new(0).unwrap();
+        assert_eq!(info.sibling(7).unwrap().unwrap().index(), 7);
+        assert!(info.sibling(32).unwrap().is_none());
+    }
+
+    #[test]
+    fn display_driver() {
+        install_fake();
+        let info = HfiInfo::new(0).unwrap();
+        assert!(!info.to_string().contains("Kernel Driver"));
+        let driver = KernelDriver {
+            module: true,
+            netlink: None,
+            enabled: true,
+            consistent: true,
+            tables: BTreeMap::from([(0, 0x4000_0000)]),
+        };
+        let shown = info.with_driver(driver).to_string();
+        assert!(shown.contains("\n  Kernel Driver:\n    Owns HFI: true\n"));
+        assert!(shown.ends_with("\n    Package 0 table: 0x40000000"));
+    }
+
+    #[test]
+    fn offline_sibling() {
+        install_fake();
+        let info = HfiInfo::new(0).unwrap();
+        // CPU 200 of the fake backend cannot be queried, like an offline CPU,
+        // and keeps the row recorded while it was online.
+        assert!(info.sibling(200).unwrap().is_none());
+        record_row(200, 3);
+        let sibling = info.sibling(200).unwrap().unwrap();
+        assert_eq!((sibling.cpu, sibling.index()), (200, 3));
+    }
+
+    #[test]
+    fn read_table() {
+        install_fake();
+        let info = HfiInfo::new(0).unwrap();
+        let mut hfi = HfiTable::<34>::new();
+        hfi.read(&info).unwrap();
+        let mut ehfi = EhfiTable::<34>::new();
+        ehfi.read(&info).unwrap();
+        for cpu in 0..32 {
+            let entry = hfi.entries[cpu];
+            assert_eq!(entry.perf_cap(), ehfi.entries[cpu].perf_cap(0));
+            assert_eq!(entry.ee_cap(), ehfi.entries[cpu].ee_cap(0));
+            assert_ne!(entry.perf_cap(), 0);
+        }
+        // CPUs that do not exist have no row.
+        for cpu in 32..34 {
+            assert_eq!(hfi.entries[cpu].perf_cap(), 0);
+            assert_eq!(ehfi.entries[cpu].perf_cap(0), 0);
+        }
+    }
+}
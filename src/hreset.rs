@@ -0,0 +1,159 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! History reset (HRESET)
+//!
+//! HRESET raises #GP when executed outside CPL 0, or when EAX requests a
+//! history that is not enabled in `IA32_HRESET_ENABLE[31:0]`. Linux issues it
+//! on context switches and offers no interface to issue it from user space,
+//! so [`hreset`] only succeeds with a backend that can execute it.
+
+use std::{fmt, io};
+
+use crate::{
+    affinity, backend,
+    cpuid::{self, Cpuid},
+    msr::{self, Msr},
+};
+
+/// ITD history
+pub const ITD_HISTORY: u32 = 1 << 0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HresetSupport {
+    has_hreset: bool,
+    supported_history: u32,
+}
+
+impl HresetSupport {
+    pub fn from_cpuid(
+        features: &cpuid::ExtendedFeature1Cpuid,
+        hreset: Option<&cpuid::HresetCpuid>,
+    ) -> Self {
+        let has_hreset = features.has_hreset();
+        Self {
+            has_hreset,
+            supported_history: match (has_hreset, hreset) {
+                (true, Some(hreset)) => hreset.supported_history(),
+                _ => 0,
+            },
+        }
+    }
+
+    pub fn read(cpu: usize) -> io::Result<Self> {
+        let features = cpuid::ExtendedFeature1Cpuid::read(cpu)?;
+        // Leaf 0x20 is only enumerated if HRESET is supported.
+        let hreset = match features.has_hreset() {
+            true => Some(cpuid::HresetCpuid::read(cpu)?),
+            false => None,
+        };
+        Ok(Self::from_cpuid(&features, hreset.as_ref()))
+    }
+
+    pub fn has_hreset(&self) -> bool {
+        self.has_hreset
+    }
+
+    pub fn supported_history(&self) -> u32 {
+        self.supported_history
+    }
+
+    /// Checks that every history in `bits` can be reset.
+    pub fn check(&self, bits: u32) -> io::Result<()> {
+        if !self.has_hreset {
+            return Err(io::Error::other("HRESET is not supported"));
+        }
+        let unsupported = bits & !self.supported_history;
+        if unsupported != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("HRESET history {unsupported:#x} is not supported"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for HresetSupport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "  HRESET supported: {}", self.has_hreset)?;
+        write!(f, "  Supported history: {:#x}", self.supported_history)
+    }
+}
+
+/// Checks that every history in `bits` is enabled in `IA32_HRESET_ENABLE`,
+/// as HRESET raises #GP otherwise.
+pub fn check_enabled(bits: u32, enable: u64) -> io::Result<()> {
+    let disabled = bits & !(enable as u32);
+    if disabled != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("HRESET of history {disabled:#x} is not enabled"),
+        ));
+    }
+    Ok(())
+}
+
+/// Resets the histories in `bits` of the calling thread on `cpu`.
+///
+/// The calling thread is pinned to `cpu` during the call and its original
+/// affinity is restored afterwards.
+pub fn hreset(cpu: usize, bits: u32) -> io::Result<()> {
+    HresetSupport::read(cpu)?.check(bits)?;
+    check_enabled(bits, msr::HresetEnable::read(cpu)?.into())?;
+
+    let original = affinity::get(0)?;
+    affinity::set(0, &[cpu])?;
+    let result = backend::get().hreset(bits);
+    affinity::set(0, &original)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn support(features: u32, history: u32) -> HresetSupport {
+        let features = cpuid::ExtendedFeature1Cpuid::from([features, 0, 0, 0]);
+        let hreset = cpuid::HresetCpuid::from([0, history, 0, 0]);
+        HresetSupport::from_cpuid(&features, Some(&hreset))
+    }
+
+    #[test]
+    fn from_cpuid() {
+        let support = support(1 << 22, 0x1);
+        assert!(support.has_hreset());
+        assert_eq!(support.supported_history(), 0x1);
+
+        // Leaf 0x20 is ignored without HRESET.
+        let support = self::support(0, 0x1);
+        assert!(!support.has_hreset());
+        assert_eq!(support.supported_history(), 0);
+
+        let features = cpuid::ExtendedFeature1Cpuid::from([1 << 22, 0, 0, 0]);
+        let support = HresetSupport::from_cpuid(&features, None);
+        assert!(support.has_hreset());
+        assert_eq!(support.supported_history(), 0);
+    }
+
+    #[test]
+    fn check() {
+        assert!(support(1 << 22, 0x1).check(ITD_HISTORY).is_ok());
+        assert!(support(1 << 22, 0x1).check(0).is_ok());
+        let err = support(1 << 22, 0x1).check(0x3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "HRESET history 0x2 is not supported");
+        let err = support(0, 0).check(ITD_HISTORY).unwrap_err();
+        assert_eq!(err.to_string(), "HRESET is not supported");
+    }
+
+    #[test]
+    fn enabled() {
+        assert!(check_enabled(ITD_HISTORY, 0x1).is_ok());
+        // Only the low 32 bits enable histories.
+        let err = check_enabled(0x3, 0x1 | 0x2 << 32).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "HRESET of history 0x2 is not enabled");
+        assert!(check_enabled(ITD_HISTORY, 0).is_err());
+    }
+}
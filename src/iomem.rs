@@ -0,0 +1,171 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Physical memory map from `/proc/iomem`
+
+use std::{fs, io, path::Path};
+
+use crate::hfi::HfiInfo;
+
+/// Physical address range of a resource
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    /// Last address in the region
+    pub end: u64,
+    pub name: String,
+    /// Nesting level, 0 for top-level regions
+    pub depth: usize,
+}
+
+impl Region {
+    pub fn contains(&self, start: u64, size: u64) -> bool {
+        size > 0 && start >= self.start && start.saturating_add(size - 1) <= self.end
+    }
+
+    pub fn is_reserved(&self) -> bool {
+        self.name.eq_ignore_ascii_case("reserved")
+    }
+
+    pub fn is_ram(&self) -> bool {
+        self.name == "System RAM"
+    }
+}
+
+/// Parses the lines of `/proc/iomem`, e.g. `  00000000-00000fff : Reserved`.
+pub fn parse(s: &str) -> Result<Vec<Region>, String> {
+    s.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let invalid = || format!("invalid iomem line: {line}");
+            let trimmed = line.trim_start();
+            let depth = (line.len() - trimmed.len()) / 2;
+            let (range, name) = trimmed.split_once(" : ").ok_or_else(invalid)?;
+            let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+            Ok(Region {
+                start: u64::from_str_radix(start, 16).map_err(|_| invalid())?,
+                end: u64::from_str_radix(end, 16).map_err(|_| invalid())?,
+                name: name.to_string(),
+                depth,
+            })
+        })
+        .collect()
+}
+
+pub fn read(procfs: &Path) -> io::Result<Vec<Region>> {
+    parse(&fs::read_to_string(procfs.join("iomem"))?)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Returns whether the addresses are hidden, as they are to unprivileged users.
+pub fn is_hidden(regions: &[Region]) -> bool {
+    regions
+        .iter()
+        .all(|region| region.start == 0 && region.end == 0)
+}
+
+/// Returns the innermost reserved or RAM region containing all of
+/// `[start, start + size)`.
+pub fn backing(regions: &[Region], start: u64, size: u64) -> Option<&Region> {
+    regions
+        .iter()
+        .filter(|region| region.is_reserved() || region.is_ram())
+        .filter(|region| region.contains(start, size))
+        .max_by_key(|region| region.depth)
+}
+
+/// Checks that `[start, start + size)` is a page-aligned range that lies
+/// within a single reserved or RAM region, returning that region.
+pub fn check(regions: &[Region], start: u64, size: u64) -> io::Result<&Region> {
+    let page_size = HfiInfo::PAGE_SIZE as u64;
+    if size == 0 || !start.is_multiple_of(page_size) || !size.is_multiple_of(page_size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("range {start:#x}+{size:#x} is not page-aligned"),
+        ));
+    }
+    if is_hidden(regions) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "physical addresses in /proc/iomem are hidden",
+        ));
+    }
+    backing(regions, start, size).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "range {start:#x}-{:#x} is not within a single reserved or RAM region of /proc/iomem",
+                start.saturating_add(size - 1)
+            ),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IOMEM: &str = "\
+00000000-00000fff : Reserved
+00001000-0009efff : System RAM
+00100000-3fffffff : System RAM
+  01000000-01ffffff : Kernel code
+40000000-40003fff : Reserved
+  40000000-40000fff : ACPI Non-volatile Storage
+fe000000-fe00ffff : pnp 00:04
+";
+
+    #[test]
+    fn parse_regions() {
+        let regions = parse(IOMEM).unwrap();
+        assert_eq!(regions.len(), 7);
+        assert_eq!(
+            regions[3],
+            Region {
+                start: 0x0100_0000,
+                end: 0x01ff_ffff,
+                name: "Kernel code".to_string(),
+                depth: 1,
+            }
+        );
+        assert!(parse("00000000-00000fff Reserved").is_err());
+        assert!(parse("0000000g-00000fff : Reserved").is_err());
+        assert!(!is_hidden(&regions));
+        assert!(is_hidden(&parse("00000000-00000000 : Reserved").unwrap()));
+    }
+
+    #[test]
+    fn check_ranges() {
+        let regions = parse(IOMEM).unwrap();
+        let region = check(&regions, 0x4000_1000, 0x2000).unwrap();
+        assert_eq!(region.start, 0x4000_0000);
+        // The innermost backing region wins over a non-backing child.
+        assert_eq!(
+            check(&regions, 0x0100_0000, 0x1000).unwrap().name,
+            "System RAM"
+        );
+        assert_eq!(
+            check(&regions, 0x4000_0000, 0x1000).unwrap().name,
+            "Reserved"
+        );
+
+        for (start, size) in [
+            (0x4000_0800, 0x1000),
+            (0x4000_0000, 0x800),
+            (0x4000_0000, 0),
+            (0x4000_3000, 0x2000),
+            (0xfe00_0000, 0x1000),
+            (0x0009_f000, 0x1000),
+        ] {
+            let err = check(&regions, start, size).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                io::ErrorKind::InvalidData,
+                "{start:#x}+{size:#x}"
+            );
+        }
+        let hidden = parse("00000000-00000000 : Reserved").unwrap();
+        let err = check(&hidden, 0x4000_0000, 0x1000).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
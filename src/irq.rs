@@ -0,0 +1,199 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! IRQ affinity steering
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{advise::Selection, cpulist, hfi::HfiInfo, topology::CpuTopology};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Irq {
+    pub number: u32,
+    /// Controller, hardware IRQ and actions as in `/proc/interrupts`
+    pub name: String,
+    pub affinity: Vec<usize>,
+}
+
+impl Irq {
+    /// Returns whether the name contains any of `filters`, or `true` if
+    /// there are none.
+    pub fn matches(&self, filters: &[String]) -> bool {
+        filters.is_empty() || filters.iter().any(|filter| self.name.contains(filter))
+    }
+}
+
+fn affinity_path(procfs: &Path, number: u32) -> PathBuf {
+    procfs
+        .join("irq")
+        .join(number.to_string())
+        .join("smp_affinity_list")
+}
+
+/// Lists the numbered IRQs in `<procfs>/interrupts` with an affinity.
+pub fn list(procfs: &Path) -> io::Result<Vec<Irq>> {
+    let interrupts = fs::read_to_string(procfs.join("interrupts"))?;
+    let mut lines = interrupts.lines();
+    let num_cpus = lines
+        .next()
+        .map(|header| header.split_whitespace().count())
+        .unwrap_or(0);
+    let mut irqs = Vec::new();
+    for line in lines {
+        let Some((number, rest)) = line.split_once(':') else {
+            continue;
+        };
+        let Ok(number) = number.trim().parse() else {
+            continue;
+        };
+        let name = rest
+            .split_whitespace()
+            .skip(num_cpus)
+            .collect::<Vec<_>>()
+            .join(" ");
+        let affinity = match fs::read_to_string(affinity_path(procfs, number)) {
+            Ok(list) => cpulist::parse(&list)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        irqs.push(Irq {
+            number,
+            name,
+            affinity,
+        });
+    }
+    Ok(irqs)
+}
+
+pub fn write_affinity(procfs: &Path, number: u32, cpus: &[usize]) -> io::Result<()> {
+    fs::write(
+        affinity_path(procfs, number),
+        format!("{}\n", cpulist::format(cpus)),
+    )
+}
+
+/// Points the IRQs matching `filters` to the CPUs chosen by `selection` from
+/// the current table, writing only the affinities that change. With
+/// `dry_run`, the changes are only printed.
+///
+/// IRQs whose affinity cannot be changed, such as managed ones, are reported
+/// and skipped.
+pub fn steer<const NUM_CPUS: usize>(
+    hfi_info: &HfiInfo,
+    topology: &[CpuTopology],
+    procfs: &Path,
+    filters: &[String],
+    selection: &Selection,
+    dry_run: bool,
+) -> io::Result<()> {
+    let cpus = selection.read::<NUM_CPUS>(hfi_info, topology)?;
+    if cpus.is_empty() {
+        eprintln!("no usable CPU, leaving IRQ affinities unchanged");
+        return Ok(());
+    }
+    for irq in list(procfs)? {
+        if !irq.matches(filters) || irq.affinity == cpus {
+            continue;
+        }
+        println!(
+            "IRQ {} ({}): {} -> {}",
+            irq.number,
+            irq.name,
+            cpulist::format(&irq.affinity),
+            cpulist::format(&cpus)
+        );
+        if dry_run {
+            continue;
+        }
+        if let Err(err) = write_affinity(procfs, irq.number, &cpus) {
+            eprintln!("IRQ {}: {err}", irq.number);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{install_fake, TempDir};
+
+    fn procfs() -> TempDir {
+        let procfs = TempDir::new();
+        procfs.write(
+            "interrupts",
+            "           CPU0       CPU1       \n  \
+               0:         45          0   IO-APIC    2-edge      timer\n \
+             124:       1024        512  PCI-MSIX-0000:00:14.0    0-edge      xhci_hcd\n \
+             125:          0      99999  PCI-MSIX-0000:01:00.0    1-edge      nvme0q1\n \
+             NMI:          3          3   Non-maskable interrupts\n",
+        );
+        procfs.write("irq/0/smp_affinity_list", "0-1\n");
+        procfs.write("irq/124/smp_affinity_list", "0\n");
+        // IRQ 125 has no affinity file, e.g. it was freed while listing.
+        procfs
+    }
+
+    #[test]
+    fn list_irqs() {
+        let procfs = procfs();
+        let irqs = list(procfs.path()).unwrap();
+        assert_eq!(
+            irqs,
+            [
+                Irq {
+                    number: 0,
+                    name: "IO-APIC 2-edge timer".to_string(),
+                    affinity: vec![0, 1],
+                },
+                Irq {
+                    number: 124,
+                    name: "PCI-MSIX-0000:00:14.0 0-edge xhci_hcd".to_string(),
+                    affinity: vec![0],
+                },
+            ]
+        );
+        assert!(irqs[1].matches(&[]));
+        assert!(irqs[1].matches(&["nvme".to_string(), "xhci".to_string()]));
+        assert!(!irqs[0].matches(&["xhci".to_string()]));
+    }
+
+    #[test]
+    fn steer_matching() {
+        install_fake();
+        let procfs = procfs();
+        let hfi_info = HfiInfo::new(0).unwrap();
+        let topology = CpuTopology::read_present(32).unwrap();
+        let selection = Selection::default();
+        let cpus = selection.read::<32>(&hfi_info, &topology).unwrap();
+        let filters = ["xhci".to_string()];
+
+        steer::<32>(
+            &hfi_info,
+            &topology,
+            procfs.path(),
+            &filters,
+            &selection,
+            true,
+        )
+        .unwrap();
+        assert_eq!(procfs.read("irq/124/smp_affinity_list"), "0\n");
+
+        steer::<32>(
+            &hfi_info,
+            &topology,
+            procfs.path(),
+            &filters,
+            &selection,
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            procfs.read("irq/124/smp_affinity_list"),
+            format!("{}\n", cpulist::format(&cpus))
+        );
+        assert_eq!(procfs.read("irq/0/smp_affinity_list"), "0-1\n");
+    }
+}
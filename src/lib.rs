@@ -0,0 +1,47 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Intel Hardware Feedback Interface (HFI) and Intel Thread Director (ITD) library
+
+pub mod advise;
+pub mod affinity;
+pub mod backend;
+pub mod cgroup;
+pub mod check;
+pub mod classify;
+pub mod cpuid;
+pub mod cpulist;
+pub mod daemon;
+pub mod diff;
+pub mod doctor;
+pub mod driver;
+pub mod ehfi;
+pub mod epp;
+pub mod evacuate;
+pub mod exec;
+pub mod fake;
+pub mod hfi;
+pub mod hreset;
+pub mod iomem;
+pub mod irq;
+pub mod itd;
+pub mod msr;
+pub mod netlink;
+pub mod offline;
+pub mod process;
+pub mod provenance;
+pub mod record;
+pub mod sample;
+pub mod signal;
+pub mod snapshot;
+pub mod systemd;
+pub mod temperature;
+pub mod thermal;
+pub mod topology;
+pub mod tui;
+pub mod verify;
+pub mod vm;
+pub mod watch;
+
+#[cfg(test)]
+mod testutil;
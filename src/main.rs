@@ -3,15 +3,41 @@
 
 //! Intel Hardware Feedback Interface (HFI) utility
 
-mod cpuid;
-mod ehfi;
-mod hfi;
-mod itd;
-mod msr;
-
-use crate::{cpuid::Cpuid, ehfi::EhfiTable, hfi::HfiTable, itd::ItdInfo};
-use clap::{Args, Parser, Subcommand};
-use std::io;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use intel_hfi::{
+    advise::{self, Objective, Selection, Smt},
+    backend, cgroup, check,
+    cpuid::{self, CoreType, Cpuid},
+    cpulist, daemon, diff, doctor,
+    driver::{self, KernelDriver},
+    ehfi::EhfiTable,
+    epp,
+    evacuate::{self, Evacuator},
+    exec,
+    fake::FakeBackend,
+    hfi::{self, HfiTable},
+    hreset::{self, HresetSupport},
+    irq,
+    itd::ItdInfo,
+    msr::{self, Msr},
+    offline::OfflinePolicy,
+    provenance::Provenance,
+    record, sample, signal,
+    snapshot::{Snapshot, SnapshotBackend},
+    systemd, thermal,
+    topology::CpuTopology,
+    tui,
+    verify::{self, Sample},
+    vm,
+    watch::TableWatcher,
+};
+use std::{
+    io::{self, Write},
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
 
 const NUM_CPUS: usize = 32;
 
@@ -20,6 +46,18 @@ struct Cli {
     /// CPU number
     #[arg(short, long, default_value = "0")]
     cpu: usize,
+    /// Use a simulated hybrid CPU instead of the hardware
+    #[arg(long)]
+    fake: bool,
+    /// Epoch the simulated table starts at
+    #[arg(long, value_name = "EPOCH", default_value = "0", requires = "fake")]
+    fake_seed: u64,
+    /// Read CPUID leaves, MSRs and the table from a snapshot instead of the hardware
+    #[arg(long, value_name = "FILE", conflicts_with = "fake")]
+    from_snapshot: Option<PathBuf>,
+    /// Change the host even if the kernel intel_hfi driver owns HFI
+    #[arg(long)]
+    force: bool,
     #[command(subcommand)]
     command: Commands,
 }
@@ -32,6 +70,86 @@ enum Commands {
     Ehfi(EhfiArgs),
     /// Dumps ITD table
     Itd(ItdArgs),
+    /// Shows live HFI/EHFI capabilities and ITD classes
+    Tui(TuiArgs),
+    /// Checks for idle requests and low capabilities with Nagios-style exit codes
+    Check(CheckArgs),
+    /// Samples ITD class IDs and prints a histogram per CPU and core type
+    Sample(SampleArgs),
+    /// Resets the processor history with HRESET
+    Hreset(HresetArgs),
+    /// Prints the best CPUs for an objective as a CPU list
+    Advise(AdviseArgs),
+    /// Runs a command on the best CPUs for an objective
+    Exec(ExecArgs),
+    /// Keeps matching processes on the best CPUs as the table changes
+    Daemon(DaemonArgs),
+    /// Sets cpuset.cpus of cgroup v2 directories to the best CPUs
+    Cgroup(CgroupArgs),
+    /// Removes CPUs with zero capability from the affinity of tasks
+    Evacuate(EvacuateArgs),
+    /// Offlines CPUs whose capabilities stay at 0 and brings them back online
+    Offline(OfflineArgs),
+    /// Sets cpufreq energy-performance preferences from the capabilities
+    Epp(EppArgs),
+    /// Generates a systemd drop-in, taskset mask and CPU list for the best CPUs
+    GenAffinity(GenAffinityArgs),
+    /// Points IRQ affinities to the most efficient CPUs
+    Irq(IrqArgs),
+    /// Generates libvirt and QEMU vCPU pinning for a virtual machine
+    VmPin(VmPinArgs),
+    /// Captures CPUID leaves, MSRs and the table of every CPU into a snapshot
+    Dump(DumpArgs),
+    /// Compares two snapshots, or a snapshot and the current system
+    Diff(DiffArgs),
+    /// Shows the processor, microcode, kernel and firmware
+    Info(InfoArgs),
+    /// Checks that the system is set up for reading HFI and ITD
+    Doctor(DoctorArgs),
+    /// Prints the capability changes published by the kernel over thermal netlink
+    Events(EventsArgs),
+    /// Cross-checks the table against the capabilities published by the kernel
+    Verify(VerifyArgs),
+    /// Prints every table update with the changed capabilities
+    Watch(WatchArgs),
+    /// Records the changed capabilities and temperatures of every table update as CSV
+    Record(RecordArgs),
+}
+
+impl Commands {
+    /// Whether the subcommand changes the host, e.g. affinities or sysfs
+    /// settings, rather than only reading and printing.
+    fn changes_host(&self) -> bool {
+        match self {
+            Self::Exec(_) | Self::Daemon(_) => true,
+            Self::Cgroup(args) => !args.dry_run,
+            Self::Evacuate(args) => !args.dry_run,
+            Self::Offline(args) => !args.dry_run,
+            Self::Epp(args) => !args.dry_run,
+            Self::Irq(args) => !args.dry_run,
+            Self::GenAffinity(args) => args.output_dir.is_some(),
+            _ => false,
+        }
+    }
+
+    /// sysfs mount point used for detecting the kernel driver
+    fn sysfs(&self) -> &Path {
+        match self {
+            Self::Offline(args) => &args.sysfs,
+            Self::Epp(args) => &args.sysfs,
+            _ => Path::new("/sys"),
+        }
+    }
+
+    /// procfs mount point used for detecting the kernel driver
+    fn procfs(&self) -> &Path {
+        match self {
+            Self::Daemon(args) => &args.procfs,
+            Self::Evacuate(args) => &args.procfs,
+            Self::Irq(args) => &args.procfs,
+            _ => Path::new("/proc"),
+        }
+    }
 }
 
 #[derive(Args)]
@@ -52,81 +170,1036 @@ struct ItdArgs {
     all: bool,
 }
 
-fn main() -> io::Result<()> {
-    let cli = Cli::parse();
+#[derive(Args)]
+struct TuiArgs {
+    /// Refresh interval in milliseconds
+    #[arg(short, long, default_value = "500")]
+    interval: u64,
+}
 
-    let cpuid = cpuid::NativeModelIdCpuid::read(cli.cpu)?;
-    println!("CPU: {}", cli.cpu);
-    println!("  CoreType: {:?}", cpuid.core_type());
+#[derive(Args)]
+struct CheckArgs {
+    /// Warn if a performance capability is below this value
+    #[arg(long)]
+    warn_perf: Option<u8>,
+    /// Critical if a performance capability is below this value
+    #[arg(long)]
+    crit_perf: Option<u8>,
+    /// Warn if an energy efficiency capability is below this value
+    #[arg(long)]
+    warn_ee: Option<u8>,
+    /// Critical if an energy efficiency capability is below this value
+    #[arg(long)]
+    crit_ee: Option<u8>,
+}
 
-    let hfi_info = hfi::HfiInfo::new(cli.cpu)?;
-    println!("HFI Table:");
-    println!("{hfi_info}");
+#[derive(Args)]
+struct SampleArgs {
+    /// CPU list to sample (e.g. 0-3,8), defaults to all CPUs present
+    #[arg(long)]
+    cpus: Option<String>,
+    /// Sampling duration in seconds
+    #[arg(short, long, default_value = "5")]
+    duration: u64,
+    /// Sampling interval in milliseconds
+    #[arg(short, long, default_value = "10")]
+    interval: u64,
+}
 
-    match &cli.command {
-        Commands::Hfi(args) => {
-            let mut table = HfiTable::<NUM_CPUS>::new();
-            table.read(&hfi_info)?;
+#[derive(Args)]
+struct HresetArgs {
+    /// Bitmap of histories to reset (bit 0: ITD history)
+    #[arg(short, long, default_value = "1")]
+    bits: u32,
+}
+
+#[derive(Args)]
+struct AdviseArgs {
+    /// Objective: perf, ee or mix:<weight of perf in 0.0-1.0>
+    #[arg(short, long, default_value = "perf")]
+    objective: Objective,
+    /// Rank by the EHFI capabilities of this ITD class instead of HFI
+    #[arg(long)]
+    class: Option<usize>,
+    /// Number of CPUs to print
+    #[arg(short = 'n', long, default_value = "1")]
+    count: usize,
+    /// Keep SMT siblings next to each other instead of spreading across cores
+    #[arg(long)]
+    pack_smt: bool,
+    /// Print the full ranking
+    #[arg(short, long)]
+    verbose: bool,
+}
 
-            println!("{}", table.header);
+#[derive(Args)]
+struct ExecArgs {
+    /// Objective: perf, ee or mix:<weight of perf in 0.0-1.0>
+    #[arg(short, long, default_value = "perf")]
+    prefer: Objective,
+    /// Rank by the EHFI capabilities of this ITD class instead of HFI
+    #[arg(long)]
+    class: Option<usize>,
+    /// Number of CPUs to run the command on
+    #[arg(short = 'n', long, default_value = "1")]
+    count: usize,
+    /// Keep SMT siblings next to each other instead of spreading across cores
+    #[arg(long)]
+    pack_smt: bool,
+    /// Re-evaluate the table and re-pin the command every this many milliseconds
+    #[arg(long)]
+    repin: Option<u64>,
+    /// Command to run
+    #[arg(last = true, required = true)]
+    command: Vec<String>,
+}
 
-            if args.all {
-                for cpu in 0..NUM_CPUS {
-                    println!("  CPU {cpu}:");
-                    println!("{}", table.entries[cpu]);
-                }
-            } else {
-                println!("  CPU {}:", cli.cpu);
-                println!("{}", table.entries[cli.cpu]);
+#[derive(Args)]
+struct DaemonArgs {
+    /// Placement rule, e.g. name=nginx,prefer=perf,count=4[,class=1][,smt=pack][,core=atom].
+    /// Processes are matched by pid=, name= or cgroup=
+    #[arg(short, long = "rule", required = true)]
+    rules: Vec<daemon::Rule>,
+    /// Polling interval in milliseconds
+    #[arg(short, long, default_value = "1000")]
+    interval: u64,
+    /// Minimum score improvement in percent to move processes
+    #[arg(long, default_value = "10")]
+    hysteresis: f64,
+    /// Minimum time in seconds between two moves of the same rule
+    #[arg(long, default_value = "5")]
+    min_dwell: u64,
+    /// procfs mount point
+    #[arg(long, default_value = "/proc")]
+    procfs: PathBuf,
+}
+
+#[derive(Args)]
+struct CgroupArgs {
+    /// Assignment of a cgroup relative to the root, e.g. latency:prefer=perf,count=4[,class=1][,smt=pack][,core=atom]
+    #[arg(short, long = "assign", required = true)]
+    assignments: Vec<cgroup::Assignment>,
+    /// cgroup v2 mount point
+    #[arg(long, default_value = "/sys/fs/cgroup")]
+    root: PathBuf,
+    /// Print the changes without writing them
+    #[arg(long)]
+    dry_run: bool,
+    /// Keep updating on table changes, polling every this many milliseconds
+    #[arg(short, long)]
+    watch: Option<u64>,
+}
+
+#[derive(Args)]
+struct EvacuateArgs {
+    /// Process ID to evacuate
+    #[arg(long = "pid")]
+    pids: Vec<i32>,
+    /// Process name to evacuate
+    #[arg(long = "name")]
+    names: Vec<String>,
+    /// Print the changes without applying them
+    #[arg(long)]
+    dry_run: bool,
+    /// Keep evacuating and restoring on table changes, polling every this many milliseconds
+    #[arg(short, long)]
+    watch: Option<u64>,
+    /// procfs mount point
+    #[arg(long, default_value = "/proc")]
+    procfs: PathBuf,
+}
+
+#[derive(Args)]
+struct OfflineArgs {
+    /// Seconds both capabilities must stay at 0 before a CPU is offlined
+    #[arg(short, long, default_value = "10")]
+    grace: u64,
+    /// Print the changes without writing them
+    #[arg(long)]
+    dry_run: bool,
+    /// Polling interval in milliseconds
+    #[arg(short, long, default_value = "1000")]
+    interval: u64,
+    /// sysfs mount point
+    #[arg(long, default_value = "/sys")]
+    sysfs: PathBuf,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum AffinityFormat {
+    All,
+    Cpulist,
+    Mask,
+    Dropin,
+}
+
+#[derive(Args)]
+struct GenAffinityArgs {
+    /// Objective: perf, ee or mix:<weight of perf in 0.0-1.0>
+    #[arg(short, long, default_value = "perf")]
+    prefer: Objective,
+    /// Rank by the EHFI capabilities of this ITD class instead of HFI
+    #[arg(long)]
+    class: Option<usize>,
+    /// Number of CPUs
+    #[arg(short = 'n', long, default_value = "1")]
+    count: usize,
+    /// Keep SMT siblings next to each other instead of spreading across cores
+    #[arg(long)]
+    pack_smt: bool,
+    /// Only choose CPUs of this core type: core or atom
+    #[arg(long)]
+    core_type: Option<CoreType>,
+    /// Output format
+    #[arg(short, long, value_enum, default_value = "all")]
+    format: AffinityFormat,
+    /// Drop-in settings: cpu-affinity, allowed-cpus or both
+    #[arg(long, default_value = "both")]
+    directive: systemd::Directive,
+    /// Unit the drop-in is for
+    #[arg(short, long)]
+    unit: Option<String>,
+    /// Write the drop-in for the unit under this directory, e.g. /etc/systemd/system
+    #[arg(short, long, requires = "unit")]
+    output_dir: Option<PathBuf>,
+}
+
+fn gen_affinity(cpu: usize, args: &GenAffinityArgs) -> io::Result<()> {
+    let selection = Selection {
+        objective: args.prefer,
+        class: args.class,
+        count: args.count,
+        smt: smt(args.pack_smt),
+        core_type: args.core_type,
+    };
+    let hfi_info = hfi::HfiInfo::new(cpu)?;
+    let topology = CpuTopology::read_present(NUM_CPUS)?;
+    let cpus = selection.read::<NUM_CPUS>(&hfi_info, &topology)?;
+    if cpus.is_empty() {
+        return Err(io::Error::other(format!("no usable CPU for {selection}")));
+    }
+    if cpus.len() < args.count {
+        eprintln!(
+            "warning: only {} of {} CPUs are usable",
+            cpus.len(),
+            args.count
+        );
+    }
+    let dropin = systemd::dropin(&selection, args.directive, &cpus);
+    let unit = args
+        .unit
+        .as_deref()
+        .map(systemd::unit_name)
+        .transpose()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+    if let (Some(dir), Some(unit)) = (&args.output_dir, &unit) {
+        let path = systemd::dropin_path(dir, unit);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, &dropin)?;
+        eprintln!(
+            "wrote {}; run `systemctl daemon-reload` and restart {unit}",
+            path.display()
+        );
+    }
+    match args.format {
+        AffinityFormat::Cpulist => println!("{}", cpulist::format(&cpus)),
+        AffinityFormat::Mask => println!("{}", cpulist::mask(&cpus)),
+        AffinityFormat::Dropin => print!("{dropin}"),
+        AffinityFormat::All => {
+            println!("cpulist: {}", cpulist::format(&cpus));
+            println!("mask: {}", cpulist::mask(&cpus));
+            println!("taskset: taskset {} <command>", cpulist::mask(&cpus));
+            match &unit {
+                Some(unit) => println!("systemd drop-in ({unit}.d/{}):", systemd::DROPIN_NAME),
+                None => println!("systemd drop-in ({}):", systemd::DROPIN_NAME),
             }
+            print!("{dropin}");
         }
-        Commands::Ehfi(args) => {
-            if !hfi_info.has_itd() {
-                println!("EHFI capability is not supported");
-                return Ok(());
-            }
+    }
+    Ok(())
+}
 
-            let itd_info = ItdInfo::new(&hfi_info);
-            if !itd_info.itd_enabled() {
-                println!("EHFI capability is not enabled");
-                return Ok(());
+#[derive(Args)]
+struct IrqArgs {
+    /// Only steer IRQs whose name in /proc/interrupts contains this
+    #[arg(long = "name")]
+    names: Vec<String>,
+    /// Objective: perf, ee or mix:<weight of perf in 0.0-1.0>
+    #[arg(short, long, default_value = "ee")]
+    prefer: Objective,
+    /// Rank by the EHFI capabilities of this ITD class instead of HFI
+    #[arg(long)]
+    class: Option<usize>,
+    /// Number of CPUs to point the IRQs to
+    #[arg(short = 'n', long, default_value = "2")]
+    count: usize,
+    /// Keep SMT siblings next to each other instead of spreading across cores
+    #[arg(long)]
+    pack_smt: bool,
+    /// Only choose CPUs of this core type: core or atom
+    #[arg(long)]
+    core_type: Option<CoreType>,
+    /// Print the changes without writing them
+    #[arg(long)]
+    dry_run: bool,
+    /// Keep updating on table changes, polling every this many milliseconds
+    #[arg(short, long)]
+    watch: Option<u64>,
+    /// procfs mount point
+    #[arg(long, default_value = "/proc")]
+    procfs: PathBuf,
+}
+
+fn steer_irqs(cpu: usize, args: &IrqArgs) -> io::Result<()> {
+    let selection = Selection {
+        objective: args.prefer,
+        class: args.class,
+        count: args.count,
+        smt: smt(args.pack_smt),
+        core_type: args.core_type,
+    };
+    let hfi_info = hfi::HfiInfo::new(cpu)?;
+    let topology = CpuTopology::read_present(NUM_CPUS)?;
+    let steer = || {
+        irq::steer::<NUM_CPUS>(
+            &hfi_info,
+            &topology,
+            &args.procfs,
+            &args.names,
+            &selection,
+            args.dry_run,
+        )
+    };
+    let Some(interval) = args.watch else {
+        return steer();
+    };
+    let mut watcher = TableWatcher::<NUM_CPUS>::new();
+    loop {
+        if watcher.poll(&hfi_info)?.is_some() {
+            steer()?;
+        }
+        std::thread::sleep(Duration::from_millis(interval));
+    }
+}
+
+#[derive(Args)]
+struct DumpArgs {
+    /// Write the snapshot to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct InfoArgs {
+    /// sysfs mount point
+    #[arg(long, default_value = "/sys")]
+    sysfs: PathBuf,
+    /// procfs mount point
+    #[arg(long, default_value = "/proc")]
+    procfs: PathBuf,
+}
+
+#[derive(Args)]
+struct DoctorArgs {
+    /// sysfs mount point
+    #[arg(long, default_value = "/sys")]
+    sysfs: PathBuf,
+    /// procfs mount point
+    #[arg(long, default_value = "/proc")]
+    procfs: PathBuf,
+    /// devtmpfs mount point
+    #[arg(long, default_value = "/dev")]
+    devfs: PathBuf,
+}
+
+#[derive(Args)]
+struct EventsArgs {
+    /// Also print every received datagram in hex
+    #[arg(long)]
+    raw: bool,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// Collection period in seconds
+    #[arg(short, long, default_value = "10")]
+    duration: u64,
+    /// Table polling interval in milliseconds
+    #[arg(short, long, default_value = "100")]
+    interval: u64,
+    /// Maximum delay between a table update and its kernel event in milliseconds
+    #[arg(long, default_value = "2000")]
+    max_skew: u64,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    /// Polling interval in milliseconds
+    #[arg(short, long, default_value = "100")]
+    interval: u64,
+}
+
+#[derive(Args)]
+struct RecordArgs {
+    /// Write the records to this file instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Polling interval in milliseconds
+    #[arg(short, long, default_value = "100")]
+    interval: u64,
+    /// Stop after this many seconds instead of on SIGINT or SIGTERM
+    #[arg(short, long)]
+    duration: Option<u64>,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    /// Old snapshot
+    old: PathBuf,
+    /// New snapshot; the current system if omitted
+    new: Option<PathBuf>,
+    /// Ignore capability changes smaller than this
+    #[arg(long, default_value = "0")]
+    min_delta: u8,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum VmFormat {
+    All,
+    Libvirt,
+    Qemu,
+}
+
+#[derive(Args)]
+struct VmPinArgs {
+    /// Number of vCPUs
+    #[arg(short = 'n', long)]
+    vcpus: usize,
+    /// Policy: perf-first, efficiency-first or mirror
+    #[arg(short, long, default_value = "perf-first")]
+    policy: vm::Policy,
+    /// Output format
+    #[arg(short, long, value_enum, default_value = "all")]
+    format: VmFormat,
+}
+
+fn vm_pin(cpu: usize, args: &VmPinArgs) -> io::Result<()> {
+    let hfi_info = hfi::HfiInfo::new(cpu)?;
+    let capabilities = advise::read_capabilities::<NUM_CPUS>(&hfi_info, None)?;
+    let topology = CpuTopology::read_present(NUM_CPUS)?;
+    let plan = vm::plan(args.vcpus, &capabilities, &topology, args.policy)?;
+    match args.format {
+        VmFormat::Libvirt => print!("{}", plan.libvirt()),
+        VmFormat::Qemu => print!("{}", plan.qemu()),
+        VmFormat::All => {
+            println!("libvirt:");
+            print!("{}", plan.libvirt());
+            println!("QEMU:");
+            print!("{}", plan.qemu());
+        }
+    }
+    Ok(())
+}
+
+#[derive(Args)]
+struct EppArgs {
+    /// Profile: performance, balanced or powersave
+    #[arg(short, long, default_value = "balanced")]
+    profile: epp::Profile,
+    /// Write raw EPP values instead of preference names
+    #[arg(long)]
+    raw: bool,
+    /// Print the changes without writing them
+    #[arg(long)]
+    dry_run: bool,
+    /// Write once and exit without restoring the original preferences
+    #[arg(long)]
+    once: bool,
+    /// Polling interval in milliseconds
+    #[arg(short, long, default_value = "1000")]
+    interval: u64,
+    /// sysfs mount point
+    #[arg(long, default_value = "/sys")]
+    sysfs: PathBuf,
+}
+
+/// Keeps the preferences up to date until SIGINT or SIGTERM, and then
+/// restores the original ones.
+fn tune_epp(cpu: usize, args: &EppArgs) -> io::Result<()> {
+    let hfi_info = hfi::HfiInfo::new(cpu)?;
+    let mut tuner = epp::Tuner::new(&args.sysfs, args.profile, args.raw, args.dry_run)?;
+    signal::install()?;
+    let mut watcher = TableWatcher::<NUM_CPUS>::new();
+    let result = (|| loop {
+        if watcher.poll(&hfi_info)?.is_some() {
+            let capabilities = advise::read_capabilities::<NUM_CPUS>(&hfi_info, None)?;
+            for change in tuner.apply(&capabilities)? {
+                println!("{change}");
             }
+        }
+        if args.once || signal::terminated() {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(args.interval));
+    })();
+    if args.once && result.is_ok() {
+        return Ok(());
+    }
+    for change in tuner.restore()? {
+        println!("restored {change}");
+    }
+    result
+}
 
-            let mut table = EhfiTable::<NUM_CPUS>::new();
-            table.read(&hfi_info)?;
+fn verify(cpu: usize, args: &VerifyArgs) -> io::Result<verify::Report> {
+    let hfi_info = hfi::HfiInfo::new(cpu)?;
+    let mut reader = thermal::EventReader::open()?;
+    reader.set_timeout(Some(Duration::from_millis(100)))?;
+    let duration = Duration::from_secs(args.duration);
+    let start = Instant::now();
+    // Set when either side stops, so that the other one does not keep going
+    // until the end of the duration.
+    let stop = AtomicBool::new(false);
+    let running = || start.elapsed() < duration && !stop.load(Ordering::Relaxed);
+    let (table, events) = std::thread::scope(|scope| {
+        let events = scope.spawn(|| -> io::Result<Vec<Sample>> {
+            let mut events = Vec::new();
+            while running() {
+                let updates = match reader.recv() {
+                    Ok((_, updates)) => updates,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(err) => {
+                        stop.store(true, Ordering::Relaxed);
+                        return Err(err);
+                    }
+                };
+                let at = start.elapsed();
+                events.extend(updates.iter().map(|update| Sample {
+                    at,
+                    cpu: update.cpu,
+                    cap: (update.entry.perf_cap(), update.entry.ee_cap()),
+                }));
+            }
+            Ok(events)
+        });
 
-            println!("{}", table.header);
+        let mut watcher = TableWatcher::<NUM_CPUS>::new();
+        let mut table = Vec::new();
+        let polled = (|| {
+            while running() {
+                if let Some(change) = watcher.poll(&hfi_info)? {
+                    table.extend(Sample::from_change(start.elapsed(), &change));
+                }
+                std::thread::sleep(Duration::from_millis(args.interval));
+            }
+            Ok(())
+        })();
+        stop.store(true, Ordering::Relaxed);
+        let events = events.join().expect("event reader panicked");
+        polled.and(events).map(|events| (table, events))
+    })?;
+    Ok(verify::verify(
+        &table,
+        &events,
+        Duration::from_millis(args.max_skew),
+    ))
+}
 
-            if args.all {
-                for cpu in 0..NUM_CPUS {
-                    println!("  CPU {cpu}:");
-                    println!("{}", table.entries[cpu]);
+fn watch(cpu: usize, args: &WatchArgs) -> io::Result<()> {
+    let hfi_info = hfi::HfiInfo::new(cpu)?;
+    let topology = CpuTopology::read_present(NUM_CPUS)?;
+    let mut watcher = TableWatcher::<NUM_CPUS>::new();
+    let start = Instant::now();
+    loop {
+        if let Some(change) = watcher.poll(&hfi_info)? {
+            // Temperatures are shown when the thermal MSRs can be read.
+            println!(
+                "[{:9.3}s] {}",
+                start.elapsed().as_secs_f64(),
+                change.header()
+            );
+            for package in record::read(&change, &topology) {
+                if let (Some(id), Some(temperature)) = (package.package, &package.temperature) {
+                    println!("  package {id}: {temperature}");
+                }
+                for cpu in &package.cpus {
+                    match &cpu.temperature {
+                        Some(temperature) => println!("  {}; {temperature}", cpu.change),
+                        None => println!("  {}", cpu.change),
+                    }
                 }
-            } else {
-                println!("  CPU {}:", cli.cpu);
-                println!("{}", table.entries[cli.cpu]);
             }
         }
-        Commands::Itd(args) => {
-            if !hfi_info.has_itd() {
-                println!("ITD capability is not supported");
-                return Ok(());
-            }
+        std::thread::sleep(Duration::from_millis(args.interval));
+    }
+}
+
+/// Writes the changed CPUs of every table update as CSV until SIGINT or
+/// SIGTERM, or until the duration has passed.
+fn record(cpu: usize, args: &RecordArgs) -> io::Result<()> {
+    let hfi_info = hfi::HfiInfo::new(cpu)?;
+    let topology = CpuTopology::read_present(NUM_CPUS)?;
+    let mut output: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(io::stdout()),
+    };
+    writeln!(output, "{}", record::CSV_HEADER)?;
+    signal::install()?;
+    let mut watcher = TableWatcher::<NUM_CPUS>::new();
+    let start = Instant::now();
+    let duration = args.duration.map(Duration::from_secs);
+    while !signal::terminated() && duration.is_none_or(|duration| start.elapsed() < duration) {
+        if let Some(change) = watcher.poll(&hfi_info)? {
+            let packages = record::read(&change, &topology);
+            write!(
+                output,
+                "{}",
+                record::csv(start.elapsed(), &change, &packages)
+            )?;
+            output.flush()?;
+        }
+        std::thread::sleep(Duration::from_millis(args.interval));
+    }
+    Ok(())
+}
 
-            if args.all {
-                for cpu in 0..NUM_CPUS {
-                    let hfi_info = hfi::HfiInfo::new(cpu)?;
-                    let itd_info = ItdInfo::new(&hfi_info);
-                    println!("ITD Table (CPU {cpu}):");
-                    println!("{itd_info}");
+/// Offlines CPUs until SIGINT or SIGTERM, and then brings them back online.
+fn offline(cpu: usize, args: &OfflineArgs) -> io::Result<()> {
+    let hfi_info = hfi::HfiInfo::new(cpu)?;
+    let mut policy = OfflinePolicy::new(
+        args.sysfs.clone(),
+        Duration::from_secs(args.grace),
+        args.dry_run,
+    );
+    signal::install()?;
+    let result = (|| loop {
+        let capabilities = advise::read_capabilities::<NUM_CPUS>(&hfi_info, None)?;
+        for action in policy.update(&capabilities, Instant::now())? {
+            println!("{action}");
+        }
+        if signal::terminated() {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(args.interval));
+    })();
+    for action in policy.restore() {
+        println!("restored {action}");
+    }
+    result
+}
+
+fn evacuate(cpu: usize, args: &EvacuateArgs) -> io::Result<()> {
+    let hfi_info = hfi::HfiInfo::new(cpu)?;
+    let targets = args
+        .pids
+        .iter()
+        .map(|pid| daemon::Matcher::Pid(*pid))
+        .chain(
+            args.names
+                .iter()
+                .map(|name| daemon::Matcher::Name(name.clone())),
+        )
+        .collect();
+    let mut evacuator = Evacuator::new(args.procfs.clone(), targets, args.dry_run);
+    let cpus = hfi_info.present_cpus(NUM_CPUS)?;
+    let mut last: Option<(u64, Vec<usize>)> = None;
+    loop {
+        let mut table = HfiTable::<NUM_CPUS>::new();
+        table.read(&hfi_info)?;
+        let timestamp = table.header.timestamp();
+        // Rows may reach 0 after the header requests idle, so the table is
+        // evaluated on every poll while it does.
+        let seen = last.as_ref().is_some_and(|(last, _)| *last == timestamp);
+        if !seen || table.header.idle_requested() {
+            let idle = evacuate::idle_cpus(&table, &cpus);
+            if last.as_ref() != Some(&(timestamp, idle.clone())) {
+                println!(
+                    "Timestamp {timestamp}: idle CPUs: {}, idle requested: {}",
+                    match idle.is_empty() {
+                        true => "none".to_string(),
+                        false => cpulist::format(&idle),
+                    },
+                    table.header.idle_requested()
+                );
+                for action in evacuator.update(&idle)? {
+                    println!("  {action}");
                 }
-            } else {
-                let itd_info = ItdInfo::new(&hfi_info);
-                println!("ITD Table (CPU {}):", cli.cpu);
-                println!("{itd_info}");
             }
+            last = Some((timestamp, idle));
+        }
+        match args.watch {
+            Some(interval) => std::thread::sleep(Duration::from_millis(interval)),
+            None => return Ok(()),
+        }
+    }
+}
+
+fn smt(pack_smt: bool) -> Smt {
+    match pack_smt {
+        true => Smt::Pack,
+        false => Smt::Spread,
+    }
+}
+
+fn advise(cpu: usize, args: &AdviseArgs) -> io::Result<()> {
+    let hfi_info = hfi::HfiInfo::new(cpu)?;
+    let capabilities = advise::read_capabilities::<NUM_CPUS>(&hfi_info, args.class)?;
+    let topology = CpuTopology::read_present(NUM_CPUS)?;
+    let advice = advise::rank(&capabilities, &topology, args.objective, smt(args.pack_smt));
+
+    if args.verbose {
+        println!("Ranking ({}):", args.objective);
+        println!("{advice}");
+    }
+    let best = advice.best(args.count);
+    if best.len() < args.count {
+        eprintln!(
+            "warning: only {} of {} CPUs are usable",
+            best.len(),
+            args.count
+        );
+    }
+    println!("{}", cpulist::format(&best));
+    Ok(())
+}
+
+fn check(cpu: usize, args: &CheckArgs) -> check::Report {
+    let thresholds = check::Thresholds {
+        warn_perf: args.warn_perf,
+        crit_perf: args.crit_perf,
+        warn_ee: args.warn_ee,
+        crit_ee: args.crit_ee,
+    };
+    let mut table = HfiTable::<NUM_CPUS>::new();
+    let cpus = hfi::HfiInfo::new(cpu).and_then(|info| {
+        table.read(&info)?;
+        info.present_cpus(NUM_CPUS)
+    });
+    match cpus {
+        Ok(cpus) => check::evaluate(&table, &cpus, thresholds),
+        Err(err) => check::Report::unknown(err),
+    }
+}
+
+/// Prints the CPU, the table location and the state of the kernel driver
+/// before a table is dumped.
+fn describe(cpu: usize) -> io::Result<hfi::HfiInfo> {
+    let cpuid = cpuid::NativeModelIdCpuid::read(cpu)?;
+    println!("CPU: {cpu}");
+    println!("  CoreType: {:?}", cpuid.core_type());
+
+    let driver = KernelDriver::detect(NUM_CPUS, Path::new("/sys"), Path::new("/proc"));
+    let hfi_info = hfi::HfiInfo::new(cpu)?.with_driver(driver);
+    println!("HFI Table:");
+    println!("{hfi_info}");
+    Ok(hfi_info)
+}
+
+fn dump_hfi(cpu: usize, args: &HfiArgs) -> io::Result<()> {
+    let hfi_info = describe(cpu)?;
+    let mut table = HfiTable::<NUM_CPUS>::new();
+    table.read(&hfi_info)?;
+
+    println!("{}", table.header);
+
+    if args.all {
+        for cpu in 0..NUM_CPUS {
+            println!("  CPU {cpu}:");
+            println!("{}", table.entries[cpu]);
+        }
+    } else {
+        println!("  CPU {cpu}:");
+        println!("{}", table.entries[cpu]);
+    }
+    Ok(())
+}
+
+fn dump_ehfi(cpu: usize, args: &EhfiArgs) -> io::Result<()> {
+    let hfi_info = describe(cpu)?;
+    if !hfi_info.has_itd() {
+        println!("EHFI capability is not supported");
+        return Ok(());
+    }
+
+    let itd_info = ItdInfo::new(&hfi_info);
+    if !itd_info.itd_enabled() {
+        println!("EHFI capability is not enabled");
+        return Ok(());
+    }
+
+    let mut table = EhfiTable::<NUM_CPUS>::new();
+    table.read(&hfi_info)?;
+
+    println!("{}", table.header);
+
+    if args.all {
+        for cpu in 0..NUM_CPUS {
+            println!("  CPU {cpu}:");
+            println!("{}", table.entries[cpu]);
         }
+    } else {
+        println!("  CPU {cpu}:");
+        println!("{}", table.entries[cpu]);
     }
+    Ok(())
+}
+
+fn dump_itd(cpu: usize, args: &ItdArgs) -> io::Result<()> {
+    let hfi_info = describe(cpu)?;
+    if !hfi_info.has_itd() {
+        println!("ITD capability is not supported");
+        return Ok(());
+    }
+
+    if args.all {
+        for cpu in 0..NUM_CPUS {
+            let hfi_info = hfi::HfiInfo::new(cpu)?;
+            let itd_info = ItdInfo::new(&hfi_info);
+            println!("ITD Table (CPU {cpu}):");
+            println!("{itd_info}");
+        }
+    } else {
+        let itd_info = ItdInfo::new(&hfi_info);
+        println!("ITD Table (CPU {cpu}):");
+        println!("{itd_info}");
+    }
+    Ok(())
+}
+
+fn sample_classes(cpu: usize, args: &SampleArgs) -> io::Result<()> {
+    let hfi_info = describe(cpu)?;
+    if !hfi_info.has_itd() {
+        println!("ITD capability is not supported");
+        return Ok(());
+    }
+
+    let (cpus, topology) = match &args.cpus {
+        Some(list) => {
+            let cpus = cpulist::parse(list)?;
+            let topology = cpus
+                .iter()
+                .map(|cpu| CpuTopology::read(*cpu))
+                .collect::<io::Result<Vec<_>>>()?;
+            (cpus, topology)
+        }
+        None => {
+            let topology = CpuTopology::read_present(NUM_CPUS)?;
+            (topology.iter().map(|t| t.cpu).collect(), topology)
+        }
+    };
+    let report = sample::sample(
+        &cpus,
+        Duration::from_secs(args.duration),
+        Duration::from_millis(args.interval),
+        msr::ThreadFeedbackChar::read,
+    )?;
 
+    for (cpu, histogram) in &report.cpus {
+        println!("ITD Classes (CPU {cpu}):");
+        println!("{histogram}");
+    }
+    for (core_type, histogram) in report.by_core_type(&topology) {
+        println!("ITD Classes ({core_type:?}):");
+        println!("{histogram}");
+    }
+    Ok(())
+}
+
+/// Prints the HRESET support and enabled histories, and then issues HRESET,
+/// which fails with `Unsupported` unless the backend can execute it.
+fn issue_hreset(cpu: usize, args: &HresetArgs) -> io::Result<()> {
+    let support = HresetSupport::read(cpu)?;
+    println!("HRESET:");
+    println!("{support}");
+    if support.has_hreset() {
+        let enable = msr::HresetEnable::read(cpu)?;
+        println!("  IA32_HRESET_ENABLE: {:#x}", u64::from(enable));
+    }
+    hreset::hreset(cpu, args.bits)?;
+    println!("HRESET issued on CPU {cpu} (history {:#x})", args.bits);
     Ok(())
 }
+
+fn cgroups(cpu: usize, args: &CgroupArgs) -> io::Result<()> {
+    let hfi_info = hfi::HfiInfo::new(cpu)?;
+    let topology = CpuTopology::read_present(NUM_CPUS)?;
+    let apply = || {
+        cgroup::apply::<NUM_CPUS>(
+            &hfi_info,
+            &topology,
+            &args.root,
+            &args.assignments,
+            args.dry_run,
+        )
+    };
+    let Some(interval) = args.watch else {
+        return apply();
+    };
+    let mut watcher = TableWatcher::<NUM_CPUS>::new();
+    loop {
+        if watcher.poll(&hfi_info)?.is_some() {
+            apply()?;
+        }
+        std::thread::sleep(Duration::from_millis(interval));
+    }
+}
+
+fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+
+    // Decisions based on a simulated or recorded table must not be applied
+    // to the host.
+    let source = match (cli.fake, &cli.from_snapshot) {
+        (true, _) => Some("--fake"),
+        (false, Some(_)) => Some("--from-snapshot"),
+        (false, None) => None,
+    };
+    if let Some(source) = source.filter(|_| cli.command.changes_host()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("changing the host is not allowed with {source}; use --dry-run if available"),
+        ));
+    }
+
+    if cli.fake {
+        backend::install(Box::new(FakeBackend::new(cli.fake_seed)));
+    }
+    // Changes would conflict with the kernel driver updating the table.
+    if cli.command.changes_host() {
+        let driver = KernelDriver::detect(NUM_CPUS, cli.command.sysfs(), cli.command.procfs());
+        driver::check_unowned(&driver, cli.force)?;
+    }
+    let mut snapshot_meta = None;
+    if let Some(path) = &cli.from_snapshot {
+        let snapshot = Snapshot::load(path)?;
+        snapshot_meta = Some(snapshot.meta.clone());
+        backend::install(Box::new(SnapshotBackend::new(snapshot)));
+    }
+    // The host information of a snapshot is only available from its metadata.
+    let provenance = |sysfs: &Path, procfs: &Path| match &snapshot_meta {
+        Some(meta) => Provenance::from_meta(meta),
+        None => Provenance::read(cli.cpu, sysfs, procfs),
+    };
+    let default_provenance = || provenance(Path::new("/sys"), Path::new("/proc"));
+
+    match &cli.command {
+        Commands::Hfi(args) => dump_hfi(cli.cpu, args),
+        Commands::Ehfi(args) => dump_ehfi(cli.cpu, args),
+        Commands::Itd(args) => dump_itd(cli.cpu, args),
+        Commands::Tui(args) => {
+            let hfi_info = describe(cli.cpu)?;
+            tui::run::<NUM_CPUS>(&hfi_info, Duration::from_millis(args.interval))
+        }
+        Commands::Check(args) => {
+            let report = check(cli.cpu, args);
+            println!("{report}");
+            std::process::exit(report.status.code());
+        }
+        Commands::Sample(args) => sample_classes(cli.cpu, args),
+        Commands::Hreset(args) => issue_hreset(cli.cpu, args),
+        Commands::Advise(args) => advise(cli.cpu, args),
+        Commands::Exec(args) => {
+            let selection = Selection {
+                objective: args.prefer,
+                class: args.class,
+                count: args.count,
+                smt: smt(args.pack_smt),
+                core_type: None,
+            };
+            let repin = args.repin.map(Duration::from_millis);
+            let hfi_info = hfi::HfiInfo::new(cli.cpu)?;
+            let status = exec::run::<NUM_CPUS>(&hfi_info, &selection, repin, &args.command)?;
+            std::process::exit(match (status.code(), status.signal()) {
+                (Some(code), _) => code,
+                (None, Some(signal)) => 128 + signal,
+                (None, None) => 1,
+            });
+        }
+        Commands::Daemon(args) => {
+            let config = daemon::Config {
+                rules: args.rules.clone(),
+                interval: Duration::from_millis(args.interval),
+                hysteresis: args.hysteresis / 100.0,
+                min_dwell: Duration::from_secs(args.min_dwell),
+                procfs: args.procfs.clone(),
+            };
+            let hfi_info = hfi::HfiInfo::new(cli.cpu)?;
+            daemon::run::<NUM_CPUS>(&hfi_info, &config)
+        }
+        Commands::Cgroup(args) => cgroups(cli.cpu, args),
+        Commands::Evacuate(args) => evacuate(cli.cpu, args),
+        Commands::Offline(args) => offline(cli.cpu, args),
+        Commands::Epp(args) => tune_epp(cli.cpu, args),
+        Commands::GenAffinity(args) => gen_affinity(cli.cpu, args),
+        Commands::Irq(args) => steer_irqs(cli.cpu, args),
+        Commands::VmPin(args) => vm_pin(cli.cpu, args),
+        Commands::Dump(args) => {
+            let snapshot = Snapshot::capture(NUM_CPUS, &default_provenance());
+            match &args.output {
+                Some(path) => std::fs::write(path, snapshot.to_string()),
+                None => {
+                    print!("{snapshot}");
+                    Ok(())
+                }
+            }
+        }
+        Commands::Diff(args) => {
+            let old = Snapshot::load(&args.old)?;
+            let new = match &args.new {
+                Some(path) => Snapshot::load(path)?,
+                None => Snapshot::capture(NUM_CPUS, &default_provenance()),
+            };
+            println!("{}", diff::Report::new(&old, &new, args.min_delta));
+            Ok(())
+        }
+        Commands::Info(args) => {
+            println!("Platform:");
+            println!("{}", provenance(&args.sysfs, &args.procfs));
+            Ok(())
+        }
+        Commands::Doctor(args) => {
+            let env = doctor::Environment {
+                sysfs: args.sysfs.clone(),
+                procfs: args.procfs.clone(),
+                devfs: args.devfs.clone(),
+                cpu: cli.cpu,
+                num_cpus: NUM_CPUS,
+            };
+            let checks = doctor::run(&env);
+            for check in &checks {
+                println!("{check}");
+            }
+            if checks
+                .iter()
+                .any(|check| check.status == doctor::Status::Fail)
+            {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Commands::Events(args) => {
+            let mut reader = thermal::EventReader::open()?;
+            loop {
+                let (datagram, updates) = reader.recv()?;
+                if args.raw {
+                    let hex: String = datagram.iter().map(|byte| format!("{byte:02x}")).collect();
+                    println!("raw {hex}");
+                }
+                for update in updates {
+                    println!("{update}");
+                }
+            }
+        }
+        Commands::Verify(args) => {
+            let report = verify(cli.cpu, args)?;
+            println!("{report}");
+            if report.has_issues() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Commands::Watch(args) => watch(cli.cpu, args),
+        Commands::Record(args) => record(cli.cpu, args),
+    }
+}
@@ -1,13 +1,12 @@
 // SPDX-License-Identifier: MIT
 // Copyright (C) 2023 Akira Moroo
 
-use std::{
-    fs::File,
-    io::{self, Read, Seek, SeekFrom, Write},
-};
+use std::io;
 
 use bitfield_struct::bitfield;
 
+use crate::backend;
+
 pub trait Msr<const ADDR: u32> {
     const ADDR: u32 = ADDR;
 
@@ -15,11 +14,7 @@ pub trait Msr<const ADDR: u32> {
     where
         Self: Sized + From<u64>,
     {
-        let mut fd = File::open(format!("/dev/cpu/{cpu}/msr"))?;
-        let mut buf = [0u8; 8];
-        fd.seek(SeekFrom::Start(Self::ADDR as u64))?;
-        fd.read_exact(&mut buf)?;
-        Ok(Self::from(u64::from_le_bytes(buf)))
+        Ok(Self::from(backend::get().read_msr(cpu, Self::ADDR)?))
     }
 
     #[allow(dead_code)]
@@ -27,19 +22,165 @@ pub trait Msr<const ADDR: u32> {
     where
         Self: Into<u64>,
     {
-        let mut fd = File::open(format!("/dev/cpu/{cpu}/msr"))?;
-        let buf = value.to_le_bytes();
-        fd.seek(SeekFrom::Start(Self::ADDR as u64))?;
-        fd.write_all(&buf)?;
-        Ok(())
+        backend::get().write_msr(cpu, Self::ADDR, value)
     }
 }
 
-const IA32_HW_FEEDBACK_PTR: u32 = 0x17D0;
-const IA32_HW_FEEDBACK_CONFIG: u32 = 0x17D1;
-const IA32_THREAD_FEEDBACK_CHAR: u32 = 0x17D2;
-const IA32_HW_FEEDBACK_THREAD_CONFIG: u32 = 0x17D4;
-const IA32_HRESET_ENABLE: u32 = 0x17DA;
+pub const IA32_BIOS_SIGN_ID: u32 = 0x8B;
+pub const IA32_THERM_INTERRUPT: u32 = 0x19B;
+pub const IA32_THERM_STATUS: u32 = 0x19C;
+pub const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+pub const IA32_PACKAGE_THERM_STATUS: u32 = 0x1B1;
+pub const IA32_PACKAGE_THERM_INTERRUPT: u32 = 0x1B2;
+pub const IA32_HW_FEEDBACK_PTR: u32 = 0x17D0;
+pub const IA32_HW_FEEDBACK_CONFIG: u32 = 0x17D1;
+pub const IA32_THREAD_FEEDBACK_CHAR: u32 = 0x17D2;
+pub const IA32_HW_FEEDBACK_THREAD_CONFIG: u32 = 0x17D4;
+pub const IA32_HRESET_ENABLE: u32 = 0x17DA;
+
+/// MSRs read by this module
+pub const MSRS: [u32; 11] = [
+    IA32_BIOS_SIGN_ID,
+    IA32_THERM_INTERRUPT,
+    IA32_THERM_STATUS,
+    MSR_TEMPERATURE_TARGET,
+    IA32_PACKAGE_THERM_STATUS,
+    IA32_PACKAGE_THERM_INTERRUPT,
+    IA32_HW_FEEDBACK_PTR,
+    IA32_HW_FEEDBACK_CONFIG,
+    IA32_THREAD_FEEDBACK_CHAR,
+    IA32_HW_FEEDBACK_THREAD_CONFIG,
+    IA32_HRESET_ENABLE,
+];
+
+#[bitfield(u64)]
+pub struct BiosSignId {
+    #[bits(32)]
+    _reserved: u64,
+    #[bits(32)]
+    pub microcode_revision: u64,
+}
+impl Msr<IA32_BIOS_SIGN_ID> for BiosSignId {}
+
+#[bitfield(u64)]
+pub struct ThermInterrupt {
+    pub high_temp_enable: bool,
+    pub low_temp_enable: bool,
+    pub prochot_enable: bool,
+    pub forcepr_enable: bool,
+    pub critical_temp_enable: bool,
+    #[bits(3)]
+    _reserved0: u64,
+    #[bits(7)]
+    pub threshold1: u64,
+    pub threshold1_enable: bool,
+    #[bits(7)]
+    pub threshold2: u64,
+    pub threshold2_enable: bool,
+    pub power_limit_enable: bool,
+    #[bits(39)]
+    _reserved1: u64,
+}
+impl Msr<IA32_THERM_INTERRUPT> for ThermInterrupt {}
+
+#[bitfield(u64)]
+pub struct ThermStatus {
+    pub thermal_status: bool,
+    pub thermal_log: bool,
+    pub prochot: bool,
+    pub prochot_log: bool,
+    pub critical_temp: bool,
+    pub critical_temp_log: bool,
+    pub threshold1: bool,
+    pub threshold1_log: bool,
+    pub threshold2: bool,
+    pub threshold2_log: bool,
+    pub power_limit: bool,
+    pub power_limit_log: bool,
+    pub current_limit: bool,
+    pub current_limit_log: bool,
+    pub cross_domain_limit: bool,
+    pub cross_domain_limit_log: bool,
+    /// Degrees Celsius below TjMax
+    #[bits(7)]
+    pub digital_readout: u64,
+    #[bits(4)]
+    _reserved0: u64,
+    /// Resolution of the readout in degrees Celsius
+    #[bits(4)]
+    pub resolution: u64,
+    pub reading_valid: bool,
+    #[bits(32)]
+    _reserved1: u64,
+}
+impl Msr<IA32_THERM_STATUS> for ThermStatus {}
+
+#[bitfield(u64)]
+pub struct TemperatureTarget {
+    #[bits(16)]
+    _reserved0: u64,
+    /// TjMax in degrees Celsius
+    #[bits(8)]
+    pub target: u64,
+    /// Offset below TjMax where throttling starts
+    #[bits(6)]
+    pub tcc_offset: u64,
+    #[bits(34)]
+    _reserved1: u64,
+}
+impl Msr<MSR_TEMPERATURE_TARGET> for TemperatureTarget {}
+
+#[bitfield(u64)]
+pub struct PackageThermStatus {
+    pub thermal_status: bool,
+    pub thermal_log: bool,
+    pub prochot: bool,
+    pub prochot_log: bool,
+    pub critical_temp: bool,
+    pub critical_temp_log: bool,
+    pub threshold1: bool,
+    pub threshold1_log: bool,
+    pub threshold2: bool,
+    pub threshold2_log: bool,
+    pub power_limit: bool,
+    pub power_limit_log: bool,
+    #[bits(4)]
+    _reserved0: u64,
+    /// Degrees Celsius below TjMax
+    #[bits(7)]
+    pub digital_readout: u64,
+    #[bits(3)]
+    _reserved1: u64,
+    /// Set by the hardware when it updates the HFI table. The hardware does
+    /// not update the table again until software clears it.
+    pub hfi_change: bool,
+    #[bits(37)]
+    _reserved2: u64,
+}
+impl Msr<IA32_PACKAGE_THERM_STATUS> for PackageThermStatus {}
+
+#[bitfield(u64)]
+pub struct PackageThermInterrupt {
+    pub high_temp_enable: bool,
+    pub low_temp_enable: bool,
+    pub prochot_enable: bool,
+    _reserved0: bool,
+    pub critical_temp_enable: bool,
+    #[bits(3)]
+    _reserved1: u64,
+    #[bits(7)]
+    pub threshold1: u64,
+    pub threshold1_enable: bool,
+    #[bits(7)]
+    pub threshold2: u64,
+    pub threshold2_enable: bool,
+    pub power_limit_enable: bool,
+    /// Raises a thermal interrupt when the HFI table is updated
+    pub hfi_enable: bool,
+    #[bits(38)]
+    _reserved2: u64,
+}
+impl Msr<IA32_PACKAGE_THERM_INTERRUPT> for PackageThermInterrupt {}
 
 #[bitfield(u64)]
 pub struct HwFeedbackPtr {
@@ -84,3 +225,50 @@ pub struct HresetEnable {
     _reserved: u64,
 }
 impl Msr<IA32_HRESET_ENABLE> for HresetEnable {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thermal_status() {
+        // Valid readout of 33 below TjMax at 1 degree resolution, PROCHOT
+        // active and a logged power limit
+        let status = ThermStatus::from(1 << 31 | 1 << 27 | 33 << 16 | 1 << 11 | 1 << 2);
+        assert!(status.reading_valid());
+        assert_eq!(status.resolution(), 1);
+        assert_eq!(status.digital_readout(), 33);
+        assert!(status.prochot() && !status.prochot_log());
+        assert!(status.power_limit_log() && !status.power_limit());
+
+        let status = PackageThermStatus::from(1 << 26 | 0x7f << 16 | 1);
+        assert!(status.hfi_change());
+        assert_eq!(status.digital_readout(), 0x7f);
+        assert!(status.thermal_status());
+        assert!(!PackageThermStatus::from(1 << 25).hfi_change());
+    }
+
+    #[test]
+    fn thermal_interrupt() {
+        let interrupt = PackageThermInterrupt::from(1 << 25 | 1 << 24 | 90 << 8);
+        assert!(interrupt.hfi_enable());
+        assert!(interrupt.power_limit_enable());
+        assert_eq!(interrupt.threshold1(), 90);
+        let interrupt = ThermInterrupt::from(1 << 24 | 80 << 16 | 1 << 15);
+        assert!(interrupt.power_limit_enable() && interrupt.threshold1_enable());
+        assert_eq!(interrupt.threshold2(), 80);
+    }
+
+    #[test]
+    fn other_fields() {
+        let target = TemperatureTarget::from(5 << 24 | 100 << 16);
+        assert_eq!((target.target(), target.tcc_offset()), (100, 5));
+        assert_eq!(BiosSignId::from(0x2c << 32).microcode_revision(), 0x2c);
+        let ptr = HwFeedbackPtr::from(0x4000_0001);
+        assert!(ptr.valid());
+        assert_eq!(ptr.addr() << 12, 0x4000_0000);
+        let feedback = ThreadFeedbackChar::from(1 << 63 | 3);
+        assert!(feedback.valid());
+        assert_eq!(feedback.class_id(), 3);
+    }
+}
@@ -0,0 +1,408 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Generic netlink messages and sockets
+
+use std::{
+    collections::BTreeMap,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    time::Duration,
+};
+
+const NLMSG_HDRLEN: usize = 16;
+const GENL_HDRLEN: usize = 4;
+const NLA_HDRLEN: usize = 4;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Netlink attribute
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Attr<'a> {
+    pub kind: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Attr<'a> {
+    pub fn u16(&self) -> Option<u16> {
+        Some(u16::from_ne_bytes(self.payload.get(..2)?.try_into().ok()?))
+    }
+
+    pub fn u32(&self) -> Option<u32> {
+        Some(u32::from_ne_bytes(self.payload.get(..4)?.try_into().ok()?))
+    }
+
+    /// Returns the payload up to the terminating NUL.
+    pub fn str(&self) -> Option<&'a str> {
+        let end = self
+            .payload
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(self.payload.len());
+        std::str::from_utf8(&self.payload[..end]).ok()
+    }
+
+    /// Returns the attributes nested in the payload.
+    pub fn nested(&self) -> Result<Vec<Attr<'a>>, String> {
+        attrs(self.payload)
+    }
+}
+
+/// Parses a stream of attributes.
+pub fn attrs(mut buf: &[u8]) -> Result<Vec<Attr<'_>>, String> {
+    let mut attrs = Vec::new();
+    while buf.len() >= NLA_HDRLEN {
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let kind = u16::from_ne_bytes([buf[2], buf[3]]) & libc::NLA_TYPE_MASK as u16;
+        if len < NLA_HDRLEN || len > buf.len() {
+            return Err(format!("invalid attribute length {len}"));
+        }
+        attrs.push(Attr {
+            kind,
+            payload: &buf[NLA_HDRLEN..len],
+        });
+        buf = &buf[align(len).min(buf.len())..];
+    }
+    Ok(attrs)
+}
+
+/// Generic netlink message
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message<'a> {
+    /// Family ID, or a netlink control message type
+    pub kind: u16,
+    pub cmd: u8,
+    pub attrs: Vec<Attr<'a>>,
+}
+
+/// Parses the messages in a datagram received from a netlink socket.
+///
+/// A netlink error is returned as the error of the whole datagram.
+pub fn messages(mut buf: &[u8]) -> io::Result<Vec<Message<'_>>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut messages = Vec::new();
+    while buf.len() >= NLMSG_HDRLEN {
+        let len = u32::from_ne_bytes(buf[..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes([buf[4], buf[5]]);
+        if len < NLMSG_HDRLEN || len > buf.len() {
+            return Err(invalid(format!("invalid message length {len}")));
+        }
+        let payload = &buf[NLMSG_HDRLEN..len];
+        buf = &buf[align(len).min(buf.len())..];
+        match kind as i32 {
+            libc::NLMSG_NOOP | libc::NLMSG_DONE => continue,
+            libc::NLMSG_ERROR => {
+                let errno = payload
+                    .get(..4)
+                    .map(|errno| i32::from_ne_bytes(errno.try_into().unwrap()))
+                    .ok_or_else(|| invalid("truncated error message".into()))?;
+                match errno {
+                    0 => continue,
+                    errno => return Err(io::Error::from_raw_os_error(-errno)),
+                }
+            }
+            _ => {}
+        }
+        if payload.len() < GENL_HDRLEN {
+            return Err(invalid("truncated generic netlink header".into()));
+        }
+        messages.push(Message {
+            kind,
+            cmd: payload[0],
+            attrs: attrs(&payload[GENL_HDRLEN..]).map_err(invalid)?,
+        });
+    }
+    Ok(messages)
+}
+
+/// Generic netlink family
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Family {
+    pub id: u16,
+    /// Multicast group IDs by name
+    pub groups: BTreeMap<String, u32>,
+}
+
+impl Family {
+    fn from_message(message: &Message) -> Option<Self> {
+        let mut id = None;
+        let mut groups = BTreeMap::new();
+        for attr in &message.attrs {
+            match attr.kind as i32 {
+                libc::CTRL_ATTR_FAMILY_ID => id = attr.u16(),
+                libc::CTRL_ATTR_MCAST_GROUPS => {
+                    for group in attr.nested().ok()? {
+                        let (mut name, mut group_id) = (None, None);
+                        for attr in group.nested().ok()? {
+                            match attr.kind as i32 {
+                                libc::CTRL_ATTR_MCAST_GRP_NAME => name = attr.str(),
+                                libc::CTRL_ATTR_MCAST_GRP_ID => group_id = attr.u32(),
+                                _ => {}
+                            }
+                        }
+                        if let (Some(name), Some(group_id)) = (name, group_id) {
+                            groups.insert(name.to_string(), group_id);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Some(Self { id: id?, groups })
+    }
+}
+
+/// Generic netlink socket
+pub struct Socket {
+    fd: OwnedFd,
+}
+
+impl Socket {
+    pub fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_GENERIC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    fn send(&self, kind: u16, cmd: u8, attrs: &[(u16, &[u8])]) -> io::Result<()> {
+        let mut buf = vec![0u8; NLMSG_HDRLEN];
+        buf.extend_from_slice(&[cmd, 1, 0, 0]);
+        for (attr, payload) in attrs {
+            buf.extend_from_slice(&((NLA_HDRLEN + payload.len()) as u16).to_ne_bytes());
+            buf.extend_from_slice(&attr.to_ne_bytes());
+            buf.extend_from_slice(payload);
+            buf.resize(align(buf.len()), 0);
+        }
+        let len = buf.len() as u32;
+        buf[..4].copy_from_slice(&len.to_ne_bytes());
+        buf[4..6].copy_from_slice(&kind.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(libc::NLM_F_REQUEST as u16).to_ne_bytes());
+        let sent = unsafe {
+            libc::send(
+                self.fd.as_raw_fd(),
+                buf.as_ptr() as *const libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Joins the multicast group `group`.
+    pub fn subscribe(&self, group: u32) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_NETLINK,
+                libc::NETLINK_ADD_MEMBERSHIP,
+                &group as *const u32 as *const libc::c_void,
+                mem::size_of::<u32>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Makes [`Socket::recv`] fail with `WouldBlock` after `timeout`, or
+    /// block forever if `None` or zero.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = timeout.unwrap_or_default();
+        let tv = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: timeout.subsec_micros() as libc::suseconds_t,
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                self.fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &tv as *const libc::timeval as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Receives a datagram into `buf`, returning its length.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                0,
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+
+    /// Resolves the family `name`, returning `None` if the kernel does not
+    /// provide it.
+    pub fn family(&self, name: &str) -> io::Result<Option<Family>> {
+        let mut name = name.as_bytes().to_vec();
+        name.push(0);
+        self.send(
+            libc::GENL_ID_CTRL as u16,
+            libc::CTRL_CMD_GETFAMILY as u8,
+            &[(libc::CTRL_ATTR_FAMILY_NAME as u16, &name)],
+        )?;
+        let mut buf = vec![0u8; 8192];
+        let len = self.recv(&mut buf)?;
+        match messages(&buf[..len]) {
+            Ok(messages) => Ok(messages.first().and_then(Family::from_message)),
+            Err(err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = ((NLA_HDRLEN + payload.len()) as u16).to_ne_bytes().to_vec();
+        buf.extend_from_slice(&kind.to_ne_bytes());
+        buf.extend_from_slice(payload);
+        buf.resize(align(buf.len()), 0);
+        buf
+    }
+
+    fn message(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = ((NLMSG_HDRLEN + payload.len()) as u32)
+            .to_ne_bytes()
+            .to_vec();
+        buf.extend_from_slice(&kind.to_ne_bytes());
+        buf.extend_from_slice(&[0; 10]);
+        buf.extend_from_slice(payload);
+        buf.resize(align(buf.len()), 0);
+        buf
+    }
+
+    #[test]
+    fn parse_attrs() {
+        let mut buf = attr(1, b"abc\0");
+        buf.extend(attr(2, &7u16.to_ne_bytes()));
+        let nested = attr(4, &9u32.to_ne_bytes());
+        buf.extend(attr(3 | libc::NLA_F_NESTED as u16, &nested));
+        let parsed = attrs(&buf).unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed[0].str(), Some("abc"));
+        // The padding of the 6-byte attribute is skipped.
+        assert_eq!((parsed[1].kind, parsed[1].u16()), (2, Some(7)));
+        assert_eq!(parsed[1].u32(), None);
+        assert_eq!(parsed[2].kind, 3);
+        assert_eq!(parsed[2].nested().unwrap()[0].u32(), Some(9));
+
+        // Trailing bytes shorter than a header are ignored.
+        assert_eq!(attrs(&[0, 0]).unwrap(), []);
+        assert_eq!(
+            attrs(&[8, 0, 1, 0, 0, 0]).unwrap_err(),
+            "invalid attribute length 8"
+        );
+        assert_eq!(
+            attrs(&[2, 0, 1, 0]).unwrap_err(),
+            "invalid attribute length 2"
+        );
+    }
+
+    #[test]
+    fn parse_messages() {
+        let mut payload = vec![3, 1, 0, 0];
+        payload.extend(attr(1, &0x1cu16.to_ne_bytes()));
+        let mut buf = message(0x10, &payload);
+        buf.extend(message(libc::NLMSG_ERROR as u16, &0i32.to_ne_bytes()));
+        buf.extend(message(libc::NLMSG_DONE as u16, &[]));
+        let messages = messages(&buf).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!((messages[0].kind, messages[0].cmd), (0x10, 3));
+        assert_eq!(messages[0].attrs[0].u16(), Some(0x1c));
+    }
+
+    #[test]
+    fn error_messages() {
+        let buf = message(libc::NLMSG_ERROR as u16, &(-libc::ENOENT).to_ne_bytes());
+        let err = messages(&buf).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+        let buf = message(libc::NLMSG_ERROR as u16, &[0, 0]);
+        assert_eq!(
+            messages(&buf).unwrap_err().to_string(),
+            "truncated error message"
+        );
+    }
+
+    #[test]
+    fn malformed_messages() {
+        let buf = message(0x10, &[3, 1, 0, 0]);
+        let err = messages(&buf[..buf.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "invalid message length 20");
+
+        let mut buf = message(0x10, &[]);
+        buf[0] = 8;
+        assert_eq!(
+            messages(&buf).unwrap_err().to_string(),
+            "invalid message length 8"
+        );
+
+        let buf = message(0x10, &[3, 1]);
+        assert_eq!(
+            messages(&buf).unwrap_err().to_string(),
+            "truncated generic netlink header"
+        );
+
+        let mut payload = vec![3, 1, 0, 0];
+        payload.extend([12, 0, 1, 0]);
+        let err = messages(&message(0x10, &payload)).unwrap_err();
+        assert_eq!(err.to_string(), "invalid attribute length 12");
+    }
+
+    #[test]
+    fn family() {
+        let mut group = attr(libc::CTRL_ATTR_MCAST_GRP_NAME as u16, b"event\0");
+        group.extend(attr(
+            libc::CTRL_ATTR_MCAST_GRP_ID as u16,
+            &5u32.to_ne_bytes(),
+        ));
+        let groups = attr(1, &group);
+        let mut payload = vec![libc::CTRL_CMD_NEWFAMILY as u8, 2, 0, 0];
+        payload.extend(attr(
+            libc::CTRL_ATTR_FAMILY_ID as u16,
+            &0x1cu16.to_ne_bytes(),
+        ));
+        payload.extend(attr(libc::CTRL_ATTR_MCAST_GROUPS as u16, &groups));
+        let buf = message(libc::GENL_ID_CTRL as u16, &payload);
+        let messages = messages(&buf).unwrap();
+        assert_eq!(
+            Family::from_message(&messages[0]),
+            Some(Family {
+                id: 0x1c,
+                groups: BTreeMap::from([("event".to_string(), 5)]),
+            })
+        );
+    }
+}
@@ -0,0 +1,299 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! CPU offlining policy for CPUs without any capability
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use crate::advise::Capability;
+
+fn cpu_dir(sysfs: &Path, cpu: usize) -> PathBuf {
+    sysfs.join(format!("devices/system/cpu/cpu{cpu}"))
+}
+
+/// Returns whether `cpu` is present and online. CPUs without an `online`
+/// file, such as CPU 0 on most systems, cannot be offlined and are always
+/// online.
+pub fn is_online(sysfs: &Path, cpu: usize) -> io::Result<bool> {
+    let dir = cpu_dir(sysfs, cpu);
+    if !dir.is_dir() {
+        return Ok(false);
+    }
+    match fs::read_to_string(dir.join("online")) {
+        Ok(online) => Ok(online.trim() != "0"),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(err) => Err(err),
+    }
+}
+
+fn can_offline(sysfs: &Path, cpu: usize) -> bool {
+    cpu != 0 && cpu_dir(sysfs, cpu).join("online").exists()
+}
+
+pub fn set_online(sysfs: &Path, cpu: usize, online: bool) -> io::Result<()> {
+    fs::write(
+        cpu_dir(sysfs, cpu).join("online"),
+        if online { "1\n" } else { "0\n" },
+    )
+}
+
+/// Returns the package of an online `cpu`.
+pub fn package(sysfs: &Path, cpu: usize) -> io::Result<u32> {
+    let path = cpu_dir(sysfs, cpu).join("topology/physical_package_id");
+    fs::read_to_string(&path)?.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid package ID in {}", path.display()),
+        )
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Offline(usize),
+    Online(usize),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Offline(cpu) => write!(f, "CPU {cpu} offline"),
+            Self::Online(cpu) => write!(f, "CPU {cpu} online"),
+        }
+    }
+}
+
+/// Offlines CPUs whose performance and energy efficiency capabilities have
+/// both been 0 for longer than a grace period, and brings them back online
+/// once either capability returns.
+///
+/// CPU 0 and the last online CPU of a package are never offlined. Only CPUs
+/// offlined by the policy are brought back online.
+pub struct OfflinePolicy {
+    sysfs: PathBuf,
+    grace: Duration,
+    dry_run: bool,
+    /// Since when the capabilities of a CPU have been 0
+    zero_since: BTreeMap<usize, Instant>,
+    offlined: BTreeSet<usize>,
+}
+
+impl OfflinePolicy {
+    pub fn new(sysfs: PathBuf, grace: Duration, dry_run: bool) -> Self {
+        Self {
+            sysfs,
+            grace,
+            dry_run,
+            zero_since: BTreeMap::new(),
+            offlined: BTreeSet::new(),
+        }
+    }
+
+    /// CPUs offlined by the policy; in dry-run mode, those it would have offlined
+    pub fn offlined(&self) -> &BTreeSet<usize> {
+        &self.offlined
+    }
+
+    fn is_online(&self, cpu: usize) -> io::Result<bool> {
+        Ok(!self.offlined.contains(&cpu) && is_online(&self.sysfs, cpu)?)
+    }
+
+    fn apply(&mut self, action: Action) -> io::Result<()> {
+        if !self.dry_run {
+            match action {
+                Action::Offline(cpu) => set_online(&self.sysfs, cpu, false)?,
+                Action::Online(cpu) => set_online(&self.sysfs, cpu, true)?,
+            }
+        }
+        match action {
+            Action::Offline(cpu) => self.offlined.insert(cpu),
+            Action::Online(cpu) => self.offlined.remove(&cpu),
+        };
+        Ok(())
+    }
+
+    /// Updates the CPUs for `capabilities` observed at `now` and returns the
+    /// actions taken. In dry-run mode, nothing is written to sysfs.
+    pub fn update(&mut self, capabilities: &[Capability], now: Instant) -> io::Result<Vec<Action>> {
+        for capability in capabilities {
+            if capability.perf == 0 && capability.ee == 0 {
+                self.zero_since.entry(capability.cpu).or_insert(now);
+            } else {
+                self.zero_since.remove(&capability.cpu);
+            }
+        }
+
+        let mut actions = Vec::new();
+        let recovered: Vec<usize> = self
+            .offlined
+            .iter()
+            .copied()
+            .filter(|cpu| !self.zero_since.contains_key(cpu))
+            .collect();
+        for cpu in recovered {
+            match self.apply(Action::Online(cpu)) {
+                Ok(()) => actions.push(Action::Online(cpu)),
+                Err(err) => eprintln!("{}: {err}", Action::Online(cpu)),
+            }
+        }
+
+        let expired: Vec<usize> = self
+            .zero_since
+            .iter()
+            .filter(|(_, since)| now.duration_since(**since) >= self.grace)
+            .map(|(cpu, _)| *cpu)
+            .collect();
+        if expired.is_empty() {
+            return Ok(actions);
+        }
+        let mut online = BTreeMap::new();
+        for capability in capabilities {
+            if self.is_online(capability.cpu)? {
+                let package = package(&self.sysfs, capability.cpu)?;
+                online.insert(capability.cpu, package);
+            }
+        }
+        for cpu in expired {
+            let Some(&package) = online.get(&cpu) else {
+                continue;
+            };
+            let siblings = online.values().filter(|p| **p == package).count();
+            if !can_offline(&self.sysfs, cpu) || siblings <= 1 {
+                continue;
+            }
+            match self.apply(Action::Offline(cpu)) {
+                Ok(()) => {
+                    online.remove(&cpu);
+                    actions.push(Action::Offline(cpu));
+                }
+                Err(err) => eprintln!("{}: {err}", Action::Offline(cpu)),
+            }
+        }
+        Ok(actions)
+    }
+
+    /// Brings every CPU offlined by the policy back online and returns the
+    /// actions taken. CPUs that fail to come back are reported and skipped.
+    pub fn restore(&mut self) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for cpu in self.offlined.clone() {
+            match self.apply(Action::Online(cpu)) {
+                Ok(()) => actions.push(Action::Online(cpu)),
+                Err(err) => eprintln!("{}: {err}", Action::Online(cpu)),
+            }
+        }
+        actions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// CPU 0-2 in package 0 and CPU 3 in package 1
+    fn sysfs() -> TempDir {
+        let sysfs = TempDir::new();
+        for (cpu, package) in [(0, 0), (1, 0), (2, 0), (3, 1)] {
+            let dir = format!("devices/system/cpu/cpu{cpu}");
+            if cpu != 0 {
+                sysfs.write(&format!("{dir}/online"), "1\n");
+            }
+            sysfs.write(
+                &format!("{dir}/topology/physical_package_id"),
+                &format!("{package}\n"),
+            );
+        }
+        sysfs
+    }
+
+    fn capabilities(zero: &[usize]) -> Vec<Capability> {
+        (0..4)
+            .map(|cpu| match zero.contains(&cpu) {
+                true => Capability {
+                    cpu,
+                    perf: 0,
+                    ee: 0,
+                },
+                false => Capability {
+                    cpu,
+                    perf: 100,
+                    ee: 100,
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn online() {
+        let sysfs = sysfs();
+        assert!(is_online(sysfs.path(), 0).unwrap());
+        set_online(sysfs.path(), 2, false).unwrap();
+        assert!(!is_online(sysfs.path(), 2).unwrap());
+        assert!(!is_online(sysfs.path(), 4).unwrap());
+        assert_eq!(package(sysfs.path(), 3).unwrap(), 1);
+    }
+
+    #[test]
+    fn grace_period() {
+        let sysfs = sysfs();
+        let mut policy =
+            OfflinePolicy::new(sysfs.path().to_path_buf(), Duration::from_secs(10), false);
+        let start = Instant::now();
+        assert_eq!(policy.update(&capabilities(&[1]), start).unwrap(), []);
+        assert_eq!(
+            policy
+                .update(&capabilities(&[1]), start + Duration::from_secs(10))
+                .unwrap(),
+            [Action::Offline(1)]
+        );
+        assert_eq!(sysfs.read("devices/system/cpu/cpu1/online"), "0\n");
+        assert_eq!(
+            policy
+                .update(&capabilities(&[]), start + Duration::from_secs(11))
+                .unwrap(),
+            [Action::Online(1)]
+        );
+        assert_eq!(sysfs.read("devices/system/cpu/cpu1/online"), "1\n");
+        assert!(policy.offlined().is_empty());
+    }
+
+    #[test]
+    fn keeps_one_cpu_per_package() {
+        let sysfs = sysfs();
+        let mut policy = OfflinePolicy::new(sysfs.path().to_path_buf(), Duration::ZERO, false);
+        let actions = policy
+            .update(&capabilities(&[0, 1, 2, 3]), Instant::now())
+            .unwrap();
+        // CPU 0 cannot be offlined, CPU 3 is the last one of package 1.
+        assert_eq!(actions, [Action::Offline(1), Action::Offline(2)]);
+    }
+
+    #[test]
+    fn restore() {
+        let sysfs = sysfs();
+        let mut policy = OfflinePolicy::new(sysfs.path().to_path_buf(), Duration::ZERO, false);
+        policy
+            .update(&capabilities(&[1, 2]), Instant::now())
+            .unwrap();
+        assert_eq!(policy.restore(), [Action::Online(1), Action::Online(2)]);
+        assert_eq!(sysfs.read("devices/system/cpu/cpu2/online"), "1\n");
+        assert!(policy.offlined().is_empty());
+        assert_eq!(policy.restore(), []);
+    }
+
+    #[test]
+    fn dry_run() {
+        let sysfs = sysfs();
+        let mut policy = OfflinePolicy::new(sysfs.path().to_path_buf(), Duration::ZERO, true);
+        let actions = policy.update(&capabilities(&[2]), Instant::now()).unwrap();
+        assert_eq!(actions, [Action::Offline(2)]);
+        assert_eq!(sysfs.read("devices/system/cpu/cpu2/online"), "1\n");
+        assert_eq!(policy.restore(), [Action::Online(2)]);
+    }
+}
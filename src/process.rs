@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Process enumeration through procfs
+
+use std::{fs, io, path::Path};
+
+use crate::cpulist;
+
+/// `PF_KTHREAD` in the per-process flags
+const PF_KTHREAD: u64 = 0x0020_0000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Process {
+    pub pid: i32,
+    pub comm: String,
+    /// cgroup v2 path, e.g. `/system.slice/foo.service`
+    pub cgroup: Option<String>,
+    pub kernel_thread: bool,
+}
+
+impl Process {
+    pub fn read(procfs: &Path, pid: i32) -> io::Result<Self> {
+        let dir = procfs.join(pid.to_string());
+        let comm = fs::read_to_string(dir.join("comm"))?.trim_end().to_string();
+        let cgroup = fs::read_to_string(dir.join("cgroup"))
+            .ok()
+            .and_then(|cgroup| {
+                cgroup
+                    .lines()
+                    .find_map(|line| line.strip_prefix("0::").map(str::to_string))
+            });
+        // Fields after the parenthesized comm start with the state (field 3),
+        // and the flags are field 9.
+        let stat = fs::read_to_string(dir.join("stat"))?;
+        let flags = stat
+            .rsplit_once(')')
+            .and_then(|(_, fields)| fields.split_whitespace().nth(6))
+            .and_then(|flags| flags.parse::<u64>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid stat"))?;
+        Ok(Self {
+            pid,
+            comm,
+            cgroup,
+            kernel_thread: flags & PF_KTHREAD != 0,
+        })
+    }
+}
+
+/// Returns the CPUs thread `tid` of process `pid` may run on, as reported by procfs.
+pub fn allowed_cpus(procfs: &Path, pid: i32, tid: i32) -> io::Result<Vec<usize>> {
+    let path = procfs
+        .join(pid.to_string())
+        .join("task")
+        .join(tid.to_string())
+        .join("status");
+    let status = fs::read_to_string(path)?;
+    let list = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Cpus_allowed_list:"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no Cpus_allowed_list"))?;
+    cpulist::parse(list)
+}
+
+/// Lists the processes under `procfs`, skipping those that exit in the meantime.
+pub fn list(procfs: &Path) -> io::Result<Vec<Process>> {
+    let mut processes = Vec::new();
+    for entry in fs::read_dir(procfs)? {
+        let Ok(pid) = entry?.file_name().to_string_lossy().parse() else {
+            continue;
+        };
+        if let Ok(process) = Process::read(procfs, pid) {
+            processes.push(process);
+        }
+    }
+    processes.sort_by_key(|process| process.pid);
+    Ok(processes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn procfs() -> TempDir {
+        let procfs = TempDir::new();
+        procfs.write("1/comm", "systemd\n");
+        procfs.write("1/cgroup", "0::/init.scope\n");
+        procfs.write("1/stat", "1 (systemd) S 0 1 1 0 -1 4194560 0 0");
+        procfs.write("2/comm", "kthreadd\n");
+        procfs.write("2/cgroup", "0::/\n");
+        procfs.write("2/stat", "2 (kthreadd) S 0 0 0 0 -1 2129984 0 0");
+        procfs.write("10/comm", "a b) c\n");
+        procfs.write("10/stat", "10 (a b) c) R 1 10 10 0 -1 4194304 0 0");
+        procfs.write(
+            "10/task/11/status",
+            "Name:\ta b) c\nCpus_allowed:\t0f\nCpus_allowed_list:\t0-3\n",
+        );
+        // Exited while listing
+        procfs.write("12/comm", "gone\n");
+        procfs.write("self/comm", "self\n");
+        procfs
+    }
+
+    #[test]
+    fn read() {
+        let procfs = procfs();
+        let process = Process::read(procfs.path(), 1).unwrap();
+        assert_eq!(
+            process,
+            Process {
+                pid: 1,
+                comm: "systemd".to_string(),
+                cgroup: Some("/init.scope".to_string()),
+                kernel_thread: false,
+            }
+        );
+        assert!(Process::read(procfs.path(), 2).unwrap().kernel_thread);
+        let process = Process::read(procfs.path(), 10).unwrap();
+        assert_eq!(process.comm, "a b) c");
+        assert_eq!(process.cgroup, None);
+        assert!(!process.kernel_thread);
+    }
+
+    #[test]
+    fn list_processes() {
+        let procfs = procfs();
+        let pids: Vec<i32> = list(procfs.path())
+            .unwrap()
+            .iter()
+            .map(|process| process.pid)
+            .collect();
+        assert_eq!(pids, [1, 2, 10]);
+    }
+
+    #[test]
+    fn allowed() {
+        let procfs = procfs();
+        assert_eq!(allowed_cpus(procfs.path(), 10, 11).unwrap(), [0, 1, 2, 3]);
+        assert!(allowed_cpus(procfs.path(), 10, 12).is_err());
+    }
+}
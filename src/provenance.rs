@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Platform provenance recorded with captured data
+
+use std::{collections::BTreeMap, fmt, fs, path::Path};
+
+use crate::{
+    cpuid::{self, Cpuid, VersionCpuid},
+    msr::{BiosSignId, Msr},
+};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Provenance {
+    pub brand: Option<String>,
+    /// Family, model and stepping
+    pub signature: Option<(u32, u32, u32)>,
+    pub microcode: Option<u64>,
+    pub kernel: Option<String>,
+    pub bios_vendor: Option<String>,
+    pub bios_version: Option<String>,
+    pub bios_date: Option<String>,
+    /// Whether the kernel `intel_hfi` driver is loaded
+    pub hfi_driver: Option<bool>,
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Returns whether the `intel_hfi` driver is listed in `<sysfs>/module` or
+/// `<procfs>/modules`.
+pub fn hfi_driver_loaded(sysfs: &Path, procfs: &Path) -> bool {
+    sysfs.join("module/intel_hfi").exists()
+        || fs::read_to_string(procfs.join("modules"))
+            .is_ok_and(|modules| modules.lines().any(|line| line.starts_with("intel_hfi ")))
+}
+
+impl Provenance {
+    /// Reads the processor information of `cpu` through the backend and the
+    /// kernel and firmware information from `sysfs` and `procfs`.
+    pub fn read(cpu: usize, sysfs: &Path, procfs: &Path) -> Self {
+        let dmi = sysfs.join("class/dmi/id");
+        Self {
+            brand: cpuid::brand_string(cpu)
+                .ok()
+                .filter(|brand| !brand.is_empty()),
+            signature: VersionCpuid::read(cpu)
+                .ok()
+                .map(|version| (version.family(), version.model(), version.stepping())),
+            microcode: BiosSignId::read(cpu)
+                .ok()
+                .map(|sign_id| sign_id.microcode_revision()),
+            kernel: read_trimmed(&procfs.join("sys/kernel/osrelease")),
+            bios_vendor: read_trimmed(&dmi.join("bios_vendor")),
+            bios_version: read_trimmed(&dmi.join("bios_version")),
+            bios_date: read_trimmed(&dmi.join("bios_date")),
+            hfi_driver: Some(hfi_driver_loaded(sysfs, procfs)),
+        }
+    }
+
+    /// Returns the snapshot metadata recording the provenance.
+    pub fn to_meta(&self) -> BTreeMap<String, String> {
+        let mut meta = BTreeMap::new();
+        let mut add = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                meta.insert(key.to_string(), value);
+            }
+        };
+        add("cpu.brand", self.brand.clone());
+        if let Some((family, model, stepping)) = self.signature {
+            add("cpu.family", Some(format!("{family:#x}")));
+            add("cpu.model", Some(format!("{model:#x}")));
+            add("cpu.stepping", Some(format!("{stepping:#x}")));
+        }
+        add(
+            "cpu.microcode",
+            self.microcode.map(|rev| format!("{rev:#x}")),
+        );
+        add("kernel.release", self.kernel.clone());
+        add(
+            "kernel.intel_hfi",
+            self.hfi_driver.map(|loaded| loaded.to_string()),
+        );
+        add("bios.vendor", self.bios_vendor.clone());
+        add("bios.version", self.bios_version.clone());
+        add("bios.date", self.bios_date.clone());
+        meta
+    }
+
+    /// Reads the provenance from snapshot metadata.
+    pub fn from_meta(meta: &BTreeMap<String, String>) -> Self {
+        let hex = |key: &str| {
+            meta.get(key)
+                .and_then(|value| value.strip_prefix("0x"))
+                .and_then(|value| u64::from_str_radix(value, 16).ok())
+        };
+        let signature = match (hex("cpu.family"), hex("cpu.model"), hex("cpu.stepping")) {
+            (Some(family), Some(model), Some(stepping)) => {
+                Some((family as u32, model as u32, stepping as u32))
+            }
+            _ => None,
+        };
+        Self {
+            brand: meta.get("cpu.brand").cloned(),
+            signature,
+            microcode: hex("cpu.microcode"),
+            kernel: meta.get("kernel.release").cloned(),
+            bios_vendor: meta.get("bios.vendor").cloned(),
+            bios_version: meta.get("bios.version").cloned(),
+            bios_date: meta.get("bios.date").cloned(),
+            hfi_driver: meta
+                .get("kernel.intel_hfi")
+                .and_then(|loaded| loaded.parse().ok()),
+        }
+    }
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = |value: &Option<String>| value.clone().unwrap_or_else(|| "unknown".into());
+        writeln!(f, "  Brand: {}", unknown(&self.brand))?;
+        match self.signature {
+            Some((family, model, stepping)) => writeln!(
+                f,
+                "  Family/Model/Stepping: {family:#x}/{model:#x}/{stepping:#x}"
+            )?,
+            None => writeln!(f, "  Family/Model/Stepping: unknown")?,
+        }
+        match self.microcode {
+            Some(rev) => writeln!(f, "  Microcode: {rev:#x}")?,
+            None => writeln!(f, "  Microcode: unknown")?,
+        }
+        writeln!(f, "  Kernel: {}", unknown(&self.kernel))?;
+        writeln!(
+            f,
+            "  BIOS: {} {} ({})",
+            unknown(&self.bios_vendor),
+            unknown(&self.bios_version),
+            unknown(&self.bios_date)
+        )?;
+        match self.hfi_driver {
+            Some(loaded) => write!(f, "  intel_hfi driver loaded: {loaded}"),
+            None => write!(f, "  intel_hfi driver loaded: unknown"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{install_fake, TempDir};
+
+    #[test]
+    fn read() {
+        install_fake();
+        let root = TempDir::new();
+        root.write("sys/class/dmi/id/bios_vendor", "Acme\n");
+        root.write("sys/class/dmi/id/bios_version", "1.2.3\n");
+        root.write("sys/class/dmi/id/bios_date", "\n");
+        root.write("proc/sys/kernel/osrelease", "6.8.0\n");
+        root.write("proc/modules", "intel_hfi 16384 0 - Live 0x0\n");
+        let provenance = Provenance::read(0, &root.path().join("sys"), &root.path().join("proc"));
+        assert_eq!(
+            provenance,
+            Provenance {
+                brand: Some("Simulated Hybrid CPU (intel-hfi --fake)".to_string()),
+                signature: Some((0x6, 0x97, 0x2)),
+                microcode: Some(0x2c),
+                kernel: Some("6.8.0".to_string()),
+                bios_vendor: Some("Acme".to_string()),
+                bios_version: Some("1.2.3".to_string()),
+                bios_date: None,
+                hfi_driver: Some(true),
+            }
+        );
+        assert_eq!(Provenance::from_meta(&provenance.to_meta()), provenance);
+    }
+
+    #[test]
+    fn driver() {
+        let root = TempDir::new();
+        let (sysfs, procfs) = (root.path().join("sys"), root.path().join("proc"));
+        assert!(!hfi_driver_loaded(&sysfs, &procfs));
+        root.write("proc/modules", "intel_hfi_extra 16384 0 - Live 0x0\n");
+        assert!(!hfi_driver_loaded(&sysfs, &procfs));
+        root.write("sys/module/intel_hfi/refcnt", "0\n");
+        assert!(hfi_driver_loaded(&sysfs, &procfs));
+    }
+
+    #[test]
+    fn unknown() {
+        let provenance = Provenance::default();
+        assert!(provenance.to_meta().is_empty());
+        assert_eq!(
+            provenance.to_string(),
+            "  Brand: unknown\n  \
+             Family/Model/Stepping: unknown\n  \
+             Microcode: unknown\n  \
+             Kernel: unknown\n  \
+             BIOS: unknown unknown (unknown)\n  \
+             intel_hfi driver loaded: unknown"
+        );
+    }
+}
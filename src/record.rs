@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Table updates with the thermal state of the changed CPUs
+
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+use crate::{
+    temperature::{Reason, Temperature},
+    topology::CpuTopology,
+    watch::{CpuChange, TableChange},
+};
+
+/// Columns of [`csv`]
+pub const CSV_HEADER: &str =
+    "time,timestamp,cpu,package,perf,ee,celsius,margin,package_celsius,package_margin,throttling";
+
+/// Changed CPU with the temperature of its core
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuRecord {
+    pub change: CpuChange,
+    /// `None` if the thermal MSRs cannot be read
+    pub temperature: Option<Temperature>,
+}
+
+/// Changed CPUs of a package with the temperature of the package
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PackageRecord {
+    /// `None` for CPUs whose topology is unknown
+    pub package: Option<u32>,
+    /// Read on the first changed CPU of the package; `None` if the package
+    /// is unknown or the thermal MSRs cannot be read
+    pub temperature: Option<Temperature>,
+    pub cpus: Vec<CpuRecord>,
+}
+
+/// Reads the temperatures of the CPUs changed by `change`, grouped by the
+/// package in `topology`.
+pub fn read(change: &TableChange, topology: &[CpuTopology]) -> Vec<PackageRecord> {
+    let mut packages: BTreeMap<Option<u32>, Vec<CpuRecord>> = BTreeMap::new();
+    for cpu_change in &change.cpus {
+        let package = topology
+            .iter()
+            .find(|topology| topology.cpu == cpu_change.cpu)
+            .map(|topology| topology.package);
+        packages.entry(package).or_default().push(CpuRecord {
+            change: *cpu_change,
+            temperature: Temperature::core(cpu_change.cpu).ok(),
+        });
+    }
+    packages
+        .into_iter()
+        .map(|(package, cpus)| PackageRecord {
+            package,
+            temperature: package.and_then(|_| Temperature::package(cpus[0].change.cpu).ok()),
+            cpus,
+        })
+        .collect()
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// Formats one CSV row per changed CPU, `elapsed` after the recording started.
+/// Empty fields are unknown, and the throttling reasons of the core and the
+/// package are separated by semicolons.
+pub fn csv(elapsed: Duration, change: &TableChange, packages: &[PackageRecord]) -> String {
+    let mut rows = String::new();
+    for package in packages {
+        for cpu in &package.cpus {
+            let (perf, ee) = cpu.change.new;
+            let mut throttling: Vec<Reason> = [&cpu.temperature, &package.temperature]
+                .into_iter()
+                .flatten()
+                .flat_map(|temperature| temperature.throttling.iter().copied())
+                .collect();
+            throttling.sort();
+            throttling.dedup();
+            let throttling: Vec<String> = throttling.iter().map(Reason::to_string).collect();
+            let _ = writeln!(
+                rows,
+                "{:.3},{},{},{},{perf},{ee},{},{},{},{},{}",
+                elapsed.as_secs_f64(),
+                change.timestamp,
+                cpu.change.cpu,
+                optional(package.package),
+                optional(cpu.temperature.as_ref().and_then(Temperature::celsius)),
+                optional(cpu.temperature.as_ref().and_then(|t| t.margin)),
+                optional(package.temperature.as_ref().and_then(Temperature::celsius)),
+                optional(package.temperature.as_ref().and_then(|t| t.margin)),
+                throttling.join(";"),
+            );
+        }
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cpuid::CoreType, testutil::install_fake};
+
+    fn change(cpus: &[usize]) -> TableChange {
+        TableChange {
+            previous: Some(1),
+            timestamp: 2,
+            cpus: cpus
+                .iter()
+                .map(|&cpu| CpuChange {
+                    cpu,
+                    old: Some((10, 20)),
+                    new: (30, 40),
+                })
+                .collect(),
+            signalled: false,
+        }
+    }
+
+    #[test]
+    fn group_by_package() {
+        install_fake();
+        let topology: Vec<CpuTopology> = [(0, 1), (1, 0), (2, 1)]
+            .into_iter()
+            .map(|(cpu, package)| CpuTopology {
+                cpu,
+                package,
+                core: cpu as u32,
+                core_type: CoreType::Core,
+            })
+            .collect();
+        let packages = read(&change(&[0, 1, 2, 3]), &topology);
+        let grouped: Vec<(Option<u32>, Vec<usize>)> = packages
+            .iter()
+            .map(|package| {
+                let cpus = package.cpus.iter().map(|cpu| cpu.change.cpu).collect();
+                (package.package, cpus)
+            })
+            .collect();
+        assert_eq!(
+            grouped,
+            [(None, vec![3]), (Some(0), vec![1]), (Some(1), vec![0, 2])]
+        );
+        assert_eq!(packages[0].temperature, None);
+        for package in &packages[1..] {
+            assert_eq!(package.temperature.as_ref().unwrap().tj_max, Some(100));
+        }
+        assert!(packages[0].cpus[0].temperature.is_some());
+    }
+
+    #[test]
+    fn rows() {
+        let temperature = |margin, throttling: &[Reason]| Temperature {
+            tj_max: Some(100),
+            margin: Some(margin),
+            throttling: throttling.to_vec(),
+            logged: Vec::new(),
+        };
+        let packages = [
+            PackageRecord {
+                package: Some(0),
+                temperature: Some(temperature(3, &[Reason::Thermal, Reason::PowerLimit])),
+                cpus: vec![CpuRecord {
+                    change: change(&[4]).cpus[0],
+                    temperature: Some(temperature(5, &[Reason::Prochot, Reason::Thermal])),
+                }],
+            },
+            PackageRecord {
+                package: None,
+                temperature: None,
+                cpus: vec![CpuRecord {
+                    change: change(&[40]).cpus[0],
+                    temperature: None,
+                }],
+            },
+        ];
+        assert_eq!(
+            csv(Duration::from_millis(1500), &change(&[4, 40]), &packages),
+            "1.500,2,4,0,30,40,95,5,97,3,thermal;PROCHOT;power limit\n\
+             1.500,2,40,,30,40,,,,,\n"
+        );
+        assert_eq!(CSV_HEADER.split(',').count(), 11);
+    }
+}
@@ -0,0 +1,216 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! ITD class sampling
+
+use std::{
+    collections::BTreeMap,
+    fmt, io, thread,
+    time::{Duration, Instant},
+};
+
+use crate::{cpuid::CoreType, msr::ThreadFeedbackChar, topology::CpuTopology};
+
+/// Number of readings per ITD class, plus readings without a valid class
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClassHistogram {
+    pub classes: BTreeMap<usize, u64>,
+    pub invalid: u64,
+}
+
+impl ClassHistogram {
+    pub fn record(&mut self, char: ThreadFeedbackChar) {
+        match char.valid() {
+            true => *self.classes.entry(char.class_id() as usize).or_default() += 1,
+            false => self.invalid += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (class, count) in &other.classes {
+            *self.classes.entry(*class).or_default() += count;
+        }
+        self.invalid += other.invalid;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.classes.values().sum::<u64>() + self.invalid
+    }
+
+    /// Returns `count` readings as a fraction of all readings.
+    pub fn fraction(&self, count: u64) -> f64 {
+        match self.total() {
+            0 => 0.0,
+            total => count as f64 / total as f64,
+        }
+    }
+
+    pub fn invalid_fraction(&self) -> f64 {
+        self.fraction(self.invalid)
+    }
+
+    /// Most frequently observed valid class, preferring the lowest class on ties
+    pub fn dominant(&self) -> Option<usize> {
+        self.classes
+            .iter()
+            .max_by(|(a_class, a), (b_class, b)| a.cmp(b).then(b_class.cmp(a_class)))
+            .map(|(class, _)| *class)
+    }
+}
+
+impl fmt::Display for ClassHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "    Samples: {}", self.total())?;
+        for (class, count) in &self.classes {
+            let percent = self.fraction(*count) * 100.0;
+            writeln!(f, "    Class #{class}: {count} ({percent:.1}%)")?;
+        }
+        let percent = self.invalid_fraction() * 100.0;
+        write!(f, "    Invalid: {} ({percent:.1}%)", self.invalid)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SampleReport {
+    pub cpus: BTreeMap<usize, ClassHistogram>,
+}
+
+impl SampleReport {
+    /// Aggregates the per-CPU histograms by core type.
+    pub fn by_core_type(&self, topology: &[CpuTopology]) -> BTreeMap<CoreType, ClassHistogram> {
+        let mut groups = BTreeMap::<_, ClassHistogram>::new();
+        for (cpu, histogram) in &self.cpus {
+            let core_type = topology
+                .iter()
+                .find(|topology| topology.cpu == *cpu)
+                .map_or(CoreType::Unknown, |topology| topology.core_type);
+            groups.entry(core_type).or_default().merge(histogram);
+        }
+        groups
+    }
+}
+
+/// Reads the thread feedback characteristics of `cpus` with `read` every
+/// `interval` until `duration` has elapsed.
+pub fn sample<F>(
+    cpus: &[usize],
+    duration: Duration,
+    interval: Duration,
+    mut read: F,
+) -> io::Result<SampleReport>
+where
+    F: FnMut(usize) -> io::Result<ThreadFeedbackChar>,
+{
+    let mut report = SampleReport::default();
+    let start = Instant::now();
+    loop {
+        let next = Instant::now() + interval;
+        for cpu in cpus {
+            report.cpus.entry(*cpu).or_default().record(read(*cpu)?);
+        }
+        if start.elapsed() >= duration {
+            return Ok(report);
+        }
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn char(class: Option<u64>) -> ThreadFeedbackChar {
+        match class {
+            Some(class) => ThreadFeedbackChar::new()
+                .with_class_id(class)
+                .with_valid(true),
+            None => ThreadFeedbackChar::new(),
+        }
+    }
+
+    fn histogram(classes: &[Option<u64>]) -> ClassHistogram {
+        let mut histogram = ClassHistogram::default();
+        for class in classes {
+            histogram.record(char(*class));
+        }
+        histogram
+    }
+
+    #[test]
+    fn record() {
+        let histogram = histogram(&[Some(1), Some(1), Some(3), None]);
+        assert_eq!(histogram.classes, BTreeMap::from([(1, 2), (3, 1)]));
+        assert_eq!(histogram.invalid, 1);
+        assert_eq!(histogram.total(), 4);
+        assert_eq!(histogram.fraction(2), 0.5);
+        assert_eq!(histogram.invalid_fraction(), 0.25);
+        assert_eq!(histogram.dominant(), Some(1));
+        assert_eq!(
+            histogram.to_string(),
+            "    Samples: 4\n    Class #1: 2 (50.0%)\n    Class #3: 1 (25.0%)\n    \
+             Invalid: 1 (25.0%)"
+        );
+    }
+
+    #[test]
+    fn empty() {
+        let histogram = ClassHistogram::default();
+        assert_eq!(histogram.fraction(0), 0.0);
+        assert_eq!(histogram.dominant(), None);
+    }
+
+    #[test]
+    fn dominant_tie() {
+        assert_eq!(
+            histogram(&[Some(2), Some(0), None, None]).dominant(),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn by_core_type() {
+        let topology = [
+            CpuTopology {
+                cpu: 0,
+                package: 0,
+                core: 0,
+                core_type: CoreType::Core,
+            },
+            CpuTopology {
+                cpu: 1,
+                package: 0,
+                core: 1,
+                core_type: CoreType::Atom,
+            },
+        ];
+        let report = SampleReport {
+            cpus: BTreeMap::from([
+                (0, histogram(&[Some(0)])),
+                (1, histogram(&[Some(1), None])),
+                (2, histogram(&[Some(1)])),
+            ]),
+        };
+        let groups = report.by_core_type(&topology);
+        assert_eq!(groups[&CoreType::Core], histogram(&[Some(0)]));
+        assert_eq!(groups[&CoreType::Atom], histogram(&[Some(1), None]));
+        assert_eq!(groups[&CoreType::Unknown], histogram(&[Some(1)]));
+    }
+
+    #[test]
+    fn sample_until_duration() {
+        let mut reads = 0;
+        let report = sample(&[0, 1], Duration::ZERO, Duration::ZERO, |cpu| {
+            reads += 1;
+            Ok(char(Some(cpu as u64)))
+        })
+        .unwrap();
+        assert_eq!(reads, 2);
+        assert_eq!(report.cpus[&1], histogram(&[Some(1)]));
+
+        let err = sample(&[0], Duration::ZERO, Duration::ZERO, |_| {
+            Err(io::Error::from(io::ErrorKind::NotFound))
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
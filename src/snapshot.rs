@@ -0,0 +1,392 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Machine snapshots and their replay
+//!
+//! A snapshot is a text file starting with `intel-hfi-snapshot <version>`,
+//! followed by one record per line:
+//!
+//! ```text
+//! meta <key> <value>
+//! cpuid <cpu> <leaf> <subleaf> <eax> <ebx> <ecx> <edx>
+//! msr <cpu> <address> <value>
+//! mem <address> <bytes in hex>
+//! ```
+//!
+//! Numbers other than CPU numbers are hexadecimal with a `0x` prefix. Lines
+//! starting with `#` are comments.
+
+use std::{collections::BTreeMap, fmt, fs, io, path::Path, str::FromStr, time::SystemTime};
+
+use crate::{
+    backend::{self, Backend},
+    cpuid, hfi, msr,
+    provenance::Provenance,
+};
+
+const MAGIC: &str = "intel-hfi-snapshot";
+pub const VERSION: u32 = 1;
+/// Bytes per `mem` record
+const MEM_LINE: usize = 64;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub meta: BTreeMap<String, String>,
+    pub cpuid: BTreeMap<(usize, u32, u32), [u32; 4]>,
+    pub msrs: BTreeMap<(usize, u32), u64>,
+    /// Contiguous memory regions by start address
+    pub mem: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Snapshot {
+    /// Captures the CPUID leaves and MSRs of CPUs `0..num_cpus` and the HFI
+    /// table through the installed backend, along with `provenance`. Leaves
+    /// and MSRs that cannot be read, e.g. of offline CPUs, are left out.
+    pub fn capture(num_cpus: usize, provenance: &Provenance) -> Self {
+        let backend = backend::get();
+        let mut snapshot = Self::default();
+        let captured = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        snapshot
+            .meta
+            .insert("captured".to_string(), captured.as_secs().to_string());
+        snapshot.meta.insert(
+            "tool".to_string(),
+            format!("intel-hfi {}", env!("CARGO_PKG_VERSION")),
+        );
+        snapshot.meta.extend(provenance.to_meta());
+
+        for cpu in 0..num_cpus {
+            for (leaf, subleaf) in cpuid::LEAVES {
+                if let Ok(regs) = backend.cpuid(cpu, leaf, subleaf) {
+                    snapshot.cpuid.insert((cpu, leaf, subleaf), regs);
+                }
+            }
+            for addr in msr::MSRS {
+                if let Ok(value) = backend.read_msr(cpu, addr) {
+                    snapshot.msrs.insert((cpu, addr), value);
+                }
+            }
+        }
+
+        if let Some(info) = (0..num_cpus).find_map(|cpu| hfi::HfiInfo::new(cpu).ok()) {
+            let mut table = vec![0u8; info.size];
+            if backend.read_mem(info.addr as u64, &mut table).is_ok() {
+                snapshot.mem.insert(info.addr as u64, table);
+            }
+        }
+        snapshot
+    }
+
+    /// Returns the address and the bytes of the HFI table pointed to by the
+    /// lowest CPU with a valid table pointer.
+    pub fn table(&self) -> Option<(u64, &[u8])> {
+        let (cpu, ptr) = self.msrs.iter().find_map(|((cpu, addr), value)| {
+            let ptr = msr::HwFeedbackPtr::from(*value);
+            (*addr == msr::IA32_HW_FEEDBACK_PTR && ptr.valid()).then_some((*cpu, ptr))
+        })?;
+        let thermal = cpuid::ThermalCpuid::from(*self.cpuid.get(&(cpu, 0x06, 0x0))?);
+        let addr = ptr.addr() << hfi::HfiInfo::PAGE_SHIFT;
+        let size = thermal.hfi_size() * hfi::HfiInfo::PAGE_SIZE;
+        let (start, region) = self.mem.range(..=addr).next_back()?;
+        let offset = (addr - start) as usize;
+        Some((addr, region.get(offset..offset + size)?))
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        fs::read_to_string(path)?.parse().map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {err}", path.display()),
+            )
+        })
+    }
+}
+
+fn parse_hex<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    s.strip_prefix("0x")
+        .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or_else(|| format!("invalid hexadecimal number: {s}"))
+}
+
+fn parse_bytes(s: &str) -> Result<Vec<u8>, String> {
+    if !s.is_ascii() || !s.len().is_multiple_of(2) {
+        return Err(format!("invalid bytes: {s}"));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| format!("invalid bytes: {s}")))
+        .collect()
+}
+
+impl FromStr for Snapshot {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines().enumerate();
+        let header = lines.next().map(|(_, line)| line).unwrap_or_default();
+        match header.split_once(' ') {
+            Some((MAGIC, version)) if version.trim() == VERSION.to_string() => {}
+            Some((MAGIC, version)) => {
+                return Err(format!("unsupported snapshot version {version}"));
+            }
+            _ => return Err("not a snapshot".to_string()),
+        }
+
+        let mut snapshot = Self::default();
+        for (number, line) in lines {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |err: String| format!("line {}: {err}", number + 1);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["meta", key, ..] => {
+                    let value = line
+                        .splitn(3, char::is_whitespace)
+                        .nth(2)
+                        .unwrap_or_default();
+                    snapshot
+                        .meta
+                        .insert(key.to_string(), value.trim().to_string());
+                }
+                ["cpuid", cpu, leaf, subleaf, regs @ ..] if regs.len() == 4 => {
+                    let cpu = cpu
+                        .parse()
+                        .map_err(|_| error(format!("invalid CPU: {cpu}")))?;
+                    let mut values = [0; 4];
+                    for (value, reg) in values.iter_mut().zip(regs) {
+                        *value = parse_hex(reg).map_err(error)?;
+                    }
+                    let leaf = parse_hex(leaf).map_err(error)?;
+                    let subleaf = parse_hex(subleaf).map_err(error)?;
+                    snapshot.cpuid.insert((cpu, leaf, subleaf), values);
+                }
+                ["msr", cpu, addr, value] => {
+                    let cpu = cpu
+                        .parse()
+                        .map_err(|_| error(format!("invalid CPU: {cpu}")))?;
+                    let addr = parse_hex(addr).map_err(error)?;
+                    snapshot
+                        .msrs
+                        .insert((cpu, addr), parse_hex(value).map_err(error)?);
+                }
+                ["mem", addr, bytes] => {
+                    let addr: u64 = parse_hex(addr).map_err(error)?;
+                    let bytes = parse_bytes(bytes).map_err(error)?;
+                    // Append to the region ending at `addr`, if any.
+                    match snapshot
+                        .mem
+                        .range_mut(..=addr)
+                        .next_back()
+                        .filter(|(start, region)| **start + region.len() as u64 == addr)
+                    {
+                        Some((_, region)) => region.extend(bytes),
+                        None => {
+                            snapshot.mem.insert(addr, bytes);
+                        }
+                    }
+                }
+                _ => return Err(error(format!("invalid record: {line}"))),
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{MAGIC} {VERSION}")?;
+        for (key, value) in &self.meta {
+            writeln!(f, "meta {key} {value}")?;
+        }
+        for ((cpu, leaf, subleaf), regs) in &self.cpuid {
+            write!(f, "cpuid {cpu} {leaf:#x} {subleaf:#x}")?;
+            for reg in regs {
+                write!(f, " {reg:#010x}")?;
+            }
+            writeln!(f)?;
+        }
+        for ((cpu, addr), value) in &self.msrs {
+            writeln!(f, "msr {cpu} {addr:#x} {value:#018x}")?;
+        }
+        for (start, region) in &self.mem {
+            for (i, chunk) in region.chunks(MEM_LINE).enumerate() {
+                write!(f, "mem {:#x} ", start + (i * MEM_LINE) as u64)?;
+                for byte in chunk {
+                    write!(f, "{byte:02x}")?;
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serves the CPUID leaves, MSRs and memory recorded in a snapshot.
+pub struct SnapshotBackend {
+    snapshot: Snapshot,
+}
+
+impl SnapshotBackend {
+    pub fn new(snapshot: Snapshot) -> Self {
+        Self { snapshot }
+    }
+}
+
+fn not_captured(what: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{what} is not in the snapshot"),
+    )
+}
+
+impl Backend for SnapshotBackend {
+    fn cpuid(&self, cpu: usize, eax: u32, ecx: u32) -> io::Result<[u32; 4]> {
+        self.snapshot
+            .cpuid
+            .get(&(cpu, eax, ecx))
+            .copied()
+            .ok_or_else(|| not_captured(format!("CPUID {eax:#x}/{ecx:#x} of CPU {cpu}")))
+    }
+
+    fn read_msr(&self, cpu: usize, addr: u32) -> io::Result<u64> {
+        self.snapshot
+            .msrs
+            .get(&(cpu, addr))
+            .copied()
+            .ok_or_else(|| not_captured(format!("MSR {addr:#x} of CPU {cpu}")))
+    }
+
+    fn write_msr(&self, _cpu: usize, _addr: u32, _value: u64) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "snapshots are read-only",
+        ))
+    }
+
+    fn read_mem(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        let (start, region) = self
+            .snapshot
+            .mem
+            .range(..=addr)
+            .next_back()
+            .ok_or_else(|| not_captured(format!("memory at {addr:#x}")))?;
+        let offset = (addr - start) as usize;
+        let bytes = region
+            .get(offset..offset + buf.len())
+            .ok_or_else(|| not_captured(format!("memory at {addr:#x}")))?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn hreset(&self, _bits: u32) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "HRESET cannot be executed on a snapshot",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fake::FakeBackend, testutil::install_fake};
+
+    const SNAPSHOT: &str = "intel-hfi-snapshot 1
+# Captured on a test machine
+meta kernel 6.8.0 #1 SMP
+cpuid 0 0x6 0x0 0x00a80004 0x00000000 0x00000400 0x00000003
+msr 0 0x17d0 0x0000000040000001
+mem 0x40000000 0001
+mem 0x40000002 0203
+";
+
+    #[test]
+    fn parse() {
+        let snapshot: Snapshot = SNAPSHOT.parse().unwrap();
+        assert_eq!(snapshot.meta["kernel"], "6.8.0 #1 SMP");
+        assert_eq!(snapshot.cpuid[&(0, 0x6, 0x0)], [0x00a8_0004, 0, 0x400, 0x3]);
+        assert_eq!(snapshot.msrs[&(0, 0x17d0)], 0x4000_0001);
+        // Adjacent records make up one region.
+        assert_eq!(
+            snapshot.mem,
+            BTreeMap::from([(0x4000_0000, vec![0, 1, 2, 3])])
+        );
+        assert_eq!(
+            snapshot.to_string(),
+            "intel-hfi-snapshot 1\n\
+             meta kernel 6.8.0 #1 SMP\n\
+             cpuid 0 0x6 0x0 0x00a80004 0x00000000 0x00000400 0x00000003\n\
+             msr 0 0x17d0 0x0000000040000001\n\
+             mem 0x40000000 00010203\n"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!("".parse::<Snapshot>().unwrap_err(), "not a snapshot");
+        assert_eq!(
+            "intel-hfi-snapshot 2\n".parse::<Snapshot>().unwrap_err(),
+            "unsupported snapshot version 2"
+        );
+        for (record, error) in [
+            ("msr 0 0x10", "line 2: invalid record: msr 0 0x10"),
+            ("msr x 0x10 0x0", "line 2: invalid CPU: x"),
+            ("msr 0 16 0x0", "line 2: invalid hexadecimal number: 16"),
+            (
+                "cpuid 0 0x6 0x0 0x1 0x2 0x3 0x100000000",
+                "line 2: invalid hexadecimal number: 0x100000000",
+            ),
+            ("mem 0x0 abc", "line 2: invalid bytes: abc"),
+        ] {
+            let snapshot = format!("intel-hfi-snapshot 1\n{record}\n");
+            assert_eq!(snapshot.parse::<Snapshot>().unwrap_err(), error);
+        }
+    }
+
+    #[test]
+    fn capture_and_replay() {
+        install_fake();
+        let provenance = Provenance {
+            kernel: Some("6.8.0".to_string()),
+            ..Default::default()
+        };
+        let snapshot = Snapshot::capture(2, &provenance);
+        assert_eq!(snapshot.meta["kernel.release"], "6.8.0");
+        let (addr, table) = snapshot.table().unwrap();
+        assert_eq!(addr, FakeBackend::TABLE_ADDR);
+        let mut expected = vec![0u8; table.len()];
+        backend::get().read_mem(addr, &mut expected).unwrap();
+        assert_eq!(table, expected);
+
+        let backend = SnapshotBackend::new(snapshot.to_string().parse().unwrap());
+        assert_eq!(
+            backend.read_msr(1, msr::IA32_HW_FEEDBACK_PTR).unwrap(),
+            FakeBackend::TABLE_ADDR | 0x1
+        );
+        let mut buf = [0u8; 8];
+        backend.read_mem(addr + 16, &mut buf).unwrap();
+        assert_eq!(buf, table[16..24]);
+        assert_eq!(
+            backend
+                .read_msr(2, msr::IA32_HW_FEEDBACK_PTR)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(backend
+            .read_mem(addr + table.len() as u64 - 4, &mut buf)
+            .is_err());
+        assert_eq!(
+            backend
+                .write_msr(0, msr::IA32_HW_FEEDBACK_CONFIG, 0)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+}
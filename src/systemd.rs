@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! systemd drop-in generation
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{advise::Selection, cpulist};
+
+/// File name of the generated drop-in
+pub const DROPIN_NAME: &str = "50-intel-hfi-affinity.conf";
+
+/// `[Service]` settings restricting the CPUs of a unit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Directive {
+    /// `CPUAffinity=`, the affinity of the executed processes
+    CpuAffinity,
+    /// `AllowedCPUs=`, the cpuset of the unit's cgroup
+    AllowedCpus,
+    #[default]
+    Both,
+}
+
+impl FromStr for Directive {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpu-affinity" => Ok(Self::CpuAffinity),
+            "allowed-cpus" => Ok(Self::AllowedCpus),
+            "both" => Ok(Self::Both),
+            _ => Err(format!("invalid directive: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Directive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CpuAffinity => write!(f, "cpu-affinity"),
+            Self::AllowedCpus => write!(f, "allowed-cpus"),
+            Self::Both => write!(f, "both"),
+        }
+    }
+}
+
+/// Returns the unit name with `.service` appended if it has no unit type.
+pub fn unit_name(unit: &str) -> Result<String, String> {
+    if unit.is_empty() || unit.contains('/') {
+        return Err(format!("invalid unit name: {unit}"));
+    }
+    match unit.contains('.') {
+        true => Ok(unit.to_string()),
+        false => Ok(format!("{unit}.service")),
+    }
+}
+
+/// Path of the drop-in for `unit` under a unit directory such as
+/// `/etc/systemd/system`
+pub fn dropin_path(dir: &Path, unit: &str) -> PathBuf {
+    dir.join(format!("{unit}.d")).join(DROPIN_NAME)
+}
+
+/// Renders a drop-in restricting a unit to `cpus` chosen by `selection`.
+///
+/// Both settings merge with earlier assignments in the unit and in other
+/// drop-ins, so each is emptied first to replace them.
+pub fn dropin(selection: &Selection, directive: Directive, cpus: &[usize]) -> String {
+    let cpus = cpulist::format(cpus);
+    let mut dropin = format!("# Generated by intel-hfi gen-affinity ({selection})\n[Service]\n");
+    if matches!(directive, Directive::CpuAffinity | Directive::Both) {
+        dropin += &format!("CPUAffinity=\nCPUAffinity={cpus}\n");
+    }
+    if matches!(directive, Directive::AllowedCpus | Directive::Both) {
+        dropin += &format!("AllowedCPUs=\nAllowedCPUs={cpus}\n");
+    }
+    dropin
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_names() {
+        assert_eq!(unit_name("nginx").unwrap(), "nginx.service");
+        assert_eq!(unit_name("batch.slice").unwrap(), "batch.slice");
+        assert!(unit_name("").is_err());
+        assert!(unit_name("../nginx").is_err());
+        assert_eq!(
+            dropin_path(Path::new("/etc/systemd/system"), "nginx.service"),
+            Path::new("/etc/systemd/system/nginx.service.d/50-intel-hfi-affinity.conf")
+        );
+    }
+
+    #[test]
+    fn render() {
+        let selection = Selection::default();
+        assert_eq!(
+            dropin(&selection, Directive::Both, &[0, 1, 2, 3, 8]),
+            format!(
+                "# Generated by intel-hfi gen-affinity ({selection})\n\
+                 [Service]\n\
+                 CPUAffinity=\n\
+                 CPUAffinity=0-3,8\n\
+                 AllowedCPUs=\n\
+                 AllowedCPUs=0-3,8\n"
+            )
+        );
+        let dropin = dropin(&selection, Directive::AllowedCpus, &[4]);
+        assert!(dropin.ends_with("[Service]\nAllowedCPUs=\nAllowedCPUs=4\n"));
+        assert!(!dropin.contains("CPUAffinity"));
+    }
+}
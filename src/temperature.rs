@@ -0,0 +1,186 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Digital thermal sensor readouts and throttling reasons
+
+use std::{fmt, io};
+
+use crate::msr::{Msr, PackageThermStatus, TemperatureTarget, ThermStatus};
+
+/// Reason for the processor to be throttled
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reason {
+    /// At or above the thermal control circuit activation temperature
+    Thermal,
+    Prochot,
+    CriticalTemperature,
+    PowerLimit,
+    CurrentLimit,
+    CrossDomainLimit,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Thermal => write!(f, "thermal"),
+            Self::Prochot => write!(f, "PROCHOT"),
+            Self::CriticalTemperature => write!(f, "critical temperature"),
+            Self::PowerLimit => write!(f, "power limit"),
+            Self::CurrentLimit => write!(f, "current limit"),
+            Self::CrossDomainLimit => write!(f, "cross-domain limit"),
+        }
+    }
+}
+
+fn reasons(flags: &[(bool, Reason)]) -> Vec<Reason> {
+    flags
+        .iter()
+        .filter(|(active, _)| *active)
+        .map(|(_, reason)| *reason)
+        .collect()
+}
+
+/// Thermal state of a core or a package
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Temperature {
+    /// TjMax in degrees Celsius, if known
+    pub tj_max: Option<u32>,
+    /// Degrees Celsius below TjMax, if the readout is valid
+    pub margin: Option<u32>,
+    /// Currently active throttling reasons
+    pub throttling: Vec<Reason>,
+    /// Throttling reasons logged since the log bits were last cleared
+    pub logged: Vec<Reason>,
+}
+
+impl Temperature {
+    /// Reads the core of `cpu`.
+    pub fn core(cpu: usize) -> io::Result<Self> {
+        let status = ThermStatus::read(cpu)?;
+        Ok(Self {
+            tj_max: tj_max(cpu),
+            margin: status
+                .reading_valid()
+                .then_some(status.digital_readout() as u32),
+            throttling: reasons(&[
+                (status.thermal_status(), Reason::Thermal),
+                (status.prochot(), Reason::Prochot),
+                (status.critical_temp(), Reason::CriticalTemperature),
+                (status.power_limit(), Reason::PowerLimit),
+                (status.current_limit(), Reason::CurrentLimit),
+                (status.cross_domain_limit(), Reason::CrossDomainLimit),
+            ]),
+            logged: reasons(&[
+                (status.thermal_log(), Reason::Thermal),
+                (status.prochot_log(), Reason::Prochot),
+                (status.critical_temp_log(), Reason::CriticalTemperature),
+                (status.power_limit_log(), Reason::PowerLimit),
+                (status.current_limit_log(), Reason::CurrentLimit),
+                (status.cross_domain_limit_log(), Reason::CrossDomainLimit),
+            ]),
+        })
+    }
+
+    /// Reads the package of `cpu`.
+    pub fn package(cpu: usize) -> io::Result<Self> {
+        let status = PackageThermStatus::read(cpu)?;
+        Ok(Self {
+            tj_max: tj_max(cpu),
+            margin: Some(status.digital_readout() as u32),
+            throttling: reasons(&[
+                (status.thermal_status(), Reason::Thermal),
+                (status.prochot(), Reason::Prochot),
+                (status.critical_temp(), Reason::CriticalTemperature),
+                (status.power_limit(), Reason::PowerLimit),
+            ]),
+            logged: reasons(&[
+                (status.thermal_log(), Reason::Thermal),
+                (status.prochot_log(), Reason::Prochot),
+                (status.critical_temp_log(), Reason::CriticalTemperature),
+                (status.power_limit_log(), Reason::PowerLimit),
+            ]),
+        })
+    }
+
+    /// Returns the temperature in degrees Celsius.
+    pub fn celsius(&self) -> Option<u32> {
+        Some(self.tj_max?.saturating_sub(self.margin?))
+    }
+}
+
+/// Returns TjMax of `cpu` in degrees Celsius, which not every processor reports.
+pub fn tj_max(cpu: usize) -> Option<u32> {
+    let target = TemperatureTarget::read(cpu).ok()?.target() as u32;
+    (target != 0).then_some(target)
+}
+
+fn list(reasons: &[Reason]) -> String {
+    reasons
+        .iter()
+        .map(|reason| reason.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl fmt::Display for Temperature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.celsius(), self.margin) {
+            (Some(celsius), Some(margin)) => write!(f, "{celsius}°C, margin {margin}°C")?,
+            (None, Some(margin)) => write!(f, "margin {margin}°C")?,
+            _ => write!(f, "no reading")?,
+        }
+        if !self.throttling.is_empty() {
+            write!(f, ", throttling: {}", list(&self.throttling))?;
+        }
+        // Only reasons that are no longer active are worth mentioning.
+        let past: Vec<Reason> = self
+            .logged
+            .iter()
+            .copied()
+            .filter(|reason| !self.throttling.contains(reason))
+            .collect();
+        if !past.is_empty() {
+            write!(f, " (logged: {})", list(&past))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::install_fake;
+
+    #[test]
+    fn display() {
+        let mut temperature = Temperature {
+            tj_max: Some(100),
+            margin: Some(8),
+            throttling: vec![Reason::Thermal, Reason::Prochot],
+            logged: vec![Reason::Thermal, Reason::PowerLimit],
+        };
+        assert_eq!(temperature.celsius(), Some(92));
+        assert_eq!(
+            temperature.to_string(),
+            "92°C, margin 8°C, throttling: thermal, PROCHOT (logged: power limit)"
+        );
+        temperature.tj_max = None;
+        temperature.throttling.clear();
+        temperature.logged.clear();
+        assert_eq!(temperature.celsius(), None);
+        assert_eq!(temperature.to_string(), "margin 8°C");
+        temperature.margin = None;
+        assert_eq!(temperature.to_string(), "no reading");
+    }
+
+    #[test]
+    fn read() {
+        install_fake();
+        let core = Temperature::core(0).unwrap();
+        assert_eq!(core.tj_max, Some(100));
+        assert!(core.celsius().is_some());
+        let package = Temperature::package(0).unwrap();
+        assert!(package.margin.unwrap() <= core.margin.unwrap());
+        assert!(Temperature::core(64).is_err());
+    }
+}
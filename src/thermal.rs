@@ -0,0 +1,227 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! CPU capability events of the kernel thermal netlink family
+//!
+//! The kernel `intel_hfi` driver forwards HFI table updates as
+//! `THERMAL_GENL_EVENT_CPU_CAPABILITY_CHANGE` events, which can be read
+//! without `/dev/mem`.
+
+use std::{fmt, io, time::Duration};
+
+use crate::{
+    driver::THERMAL_FAMILY,
+    hfi::HfiEntry,
+    netlink::{self, Attr},
+};
+
+/// Multicast group of the thermal events
+pub const EVENT_GROUP: &str = "event";
+
+const EVENT_CPU_CAPABILITY_CHANGE: u8 = 14;
+const ATTR_CPU_CAPABILITY: u16 = 20;
+const ATTR_CPU_CAPABILITY_ID: u16 = 21;
+const ATTR_CPU_CAPABILITY_PERFORMANCE: u16 = 22;
+const ATTR_CPU_CAPABILITY_EFFICIENCY: u16 = 23;
+
+/// The kernel publishes capabilities shifted left by this to a 0-1023 range.
+pub const SCALE_SHIFT: u32 = 2;
+
+/// Capability of a CPU published by the kernel
+#[derive(Clone, Copy, Debug)]
+pub struct CapabilityUpdate {
+    pub cpu: usize,
+    pub entry: HfiEntry,
+    /// Capabilities as published, before unscaling
+    pub raw: (u32, u32),
+}
+
+impl fmt::Display for CapabilityUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "CPU {}: perf {} ee {} (raw {} {})",
+            self.cpu,
+            self.entry.perf_cap(),
+            self.entry.ee_cap(),
+            self.raw.0,
+            self.raw.1
+        )
+    }
+}
+
+fn unscale(value: u32) -> u8 {
+    (value >> SCALE_SHIFT).min(u8::MAX as u32) as u8
+}
+
+/// Parses the (ID, performance, efficiency) triples in a
+/// `THERMAL_GENL_ATTR_CPU_CAPABILITY` attribute.
+fn capabilities(attr: &Attr) -> Result<Vec<CapabilityUpdate>, String> {
+    let mut updates = Vec::new();
+    let (mut cpu, mut perf) = (None, None);
+    for attr in attr.nested()? {
+        let value = attr
+            .u32()
+            .ok_or_else(|| format!("truncated capability attribute {}", attr.kind))?;
+        match attr.kind {
+            ATTR_CPU_CAPABILITY_ID => cpu = Some(value as usize),
+            ATTR_CPU_CAPABILITY_PERFORMANCE => perf = Some(value),
+            ATTR_CPU_CAPABILITY_EFFICIENCY => {
+                let (Some(cpu), Some(perf)) = (cpu.take(), perf.take()) else {
+                    return Err("efficiency capability without a CPU".to_string());
+                };
+                updates.push(CapabilityUpdate {
+                    cpu,
+                    entry: HfiEntry::new(unscale(perf), unscale(value)),
+                    raw: (perf, value),
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(updates)
+}
+
+/// Parses a datagram received from the thermal event group of family
+/// `family`, ignoring everything but CPU capability changes.
+pub fn parse(buf: &[u8], family: u16) -> io::Result<Vec<CapabilityUpdate>> {
+    let mut updates = Vec::new();
+    for message in netlink::messages(buf)? {
+        if message.kind != family || message.cmd != EVENT_CPU_CAPABILITY_CHANGE {
+            continue;
+        }
+        for attr in &message.attrs {
+            if attr.kind == ATTR_CPU_CAPABILITY {
+                updates.extend(
+                    capabilities(attr)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+                );
+            }
+        }
+    }
+    Ok(updates)
+}
+
+/// Subscription to the thermal events
+pub struct EventReader {
+    socket: netlink::Socket,
+    family: u16,
+    buf: Vec<u8>,
+}
+
+impl EventReader {
+    pub fn open() -> io::Result<Self> {
+        let socket = netlink::Socket::open()?;
+        let family = socket.family(THERMAL_FAMILY)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "the thermal netlink family is not registered",
+            )
+        })?;
+        let group = family.groups.get(EVENT_GROUP).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "the thermal netlink family has no event group",
+            )
+        })?;
+        socket.subscribe(*group)?;
+        Ok(Self {
+            socket,
+            family: family.id,
+            buf: vec![0; 64 * 1024],
+        })
+    }
+
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+
+    /// Waits for the next datagram, returning it along with the capability
+    /// updates in it. Other thermal events yield no updates.
+    pub fn recv(&mut self) -> io::Result<(&[u8], Vec<CapabilityUpdate>)> {
+        let len = self.socket.recv(&mut self.buf)?;
+        let buf = &self.buf[..len];
+        Ok((buf, parse(buf, self.family)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAMILY: u16 = 0x1c;
+
+    /// `THERMAL_GENL_EVENT_CPU_CAPABILITY_CHANGE` for CPU 0 and 4 as sent by
+    /// the kernel, with the nested flag on the capability attribute
+    const DATAGRAM: [u8; 72] = [
+        0x48, 0x00, 0x00, 0x00, 0x1c, 0x00, 0x00, 0x00, // nlmsghdr
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+        0x0e, 0x01, 0x00, 0x00, // genlmsghdr
+        0x34, 0x00, 0x14, 0x80, // THERMAL_GENL_ATTR_CPU_CAPABILITY
+        0x08, 0x00, 0x15, 0x00, 0x00, 0x00, 0x00, 0x00, // ID 0
+        0x08, 0x00, 0x16, 0x00, 0xfc, 0x03, 0x00, 0x00, // performance 1020
+        0x08, 0x00, 0x17, 0x00, 0x90, 0x01, 0x00, 0x00, // efficiency 400
+        0x08, 0x00, 0x15, 0x00, 0x04, 0x00, 0x00, 0x00, // ID 4
+        0x08, 0x00, 0x16, 0x00, 0x00, 0x02, 0x00, 0x00, // performance 512
+        0x08, 0x00, 0x17, 0x00, 0xfc, 0x03, 0x00, 0x00, // efficiency 1020
+    ];
+
+    fn summary(updates: &[CapabilityUpdate]) -> Vec<(usize, u8, u8)> {
+        updates
+            .iter()
+            .map(|update| (update.cpu, update.entry.perf_cap(), update.entry.ee_cap()))
+            .collect()
+    }
+
+    #[test]
+    fn captured() {
+        let updates = parse(&DATAGRAM, FAMILY).unwrap();
+        assert_eq!(summary(&updates), [(0, 255, 100), (4, 128, 255)]);
+        assert_eq!(updates[1].raw, (512, 1020));
+        assert_eq!(
+            updates[0].to_string(),
+            "CPU 0: perf 255 ee 100 (raw 1020 400)"
+        );
+    }
+
+    #[test]
+    fn other_events() {
+        assert!(parse(&DATAGRAM, FAMILY + 1).unwrap().is_empty());
+        let mut datagram = DATAGRAM;
+        // THERMAL_GENL_EVENT_TZ_TRIP_UP
+        datagram[16] = 5;
+        assert!(parse(&datagram, FAMILY).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        let err = parse(&DATAGRAM[..60], FAMILY).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "invalid message length 72");
+
+        // The last efficiency value is cut to two bytes.
+        let mut datagram = DATAGRAM[..70].to_vec();
+        datagram[0] = 70;
+        datagram[20] = 0x32;
+        datagram[64] = 0x06;
+        let err = parse(&datagram, FAMILY).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "truncated capability attribute 23");
+    }
+
+    #[test]
+    fn malformed() {
+        // The ID of CPU 4 is replaced by an unknown attribute.
+        let mut datagram = DATAGRAM;
+        datagram[50] = 0x7f;
+        let err = parse(&datagram, FAMILY).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "efficiency capability without a CPU");
+
+        // The nested length runs past the message.
+        let mut datagram = DATAGRAM;
+        datagram[20] = 0x38;
+        let err = parse(&datagram, FAMILY).unwrap_err();
+        assert_eq!(err.to_string(), "invalid attribute length 56");
+    }
+}
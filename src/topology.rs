@@ -0,0 +1,46 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! CPU topology
+
+use std::io;
+
+use crate::cpuid::{self, CoreType, Cpuid};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuTopology {
+    pub cpu: usize,
+    pub package: u32,
+    pub core: u32,
+    pub core_type: CoreType,
+}
+
+impl CpuTopology {
+    pub fn read(cpu: usize) -> io::Result<Self> {
+        let smt = cpuid::SmtTopologyCpuid::read(cpu)?;
+        let core = cpuid::CoreTopologyCpuid::read(cpu)?;
+        let native = cpuid::NativeModelIdCpuid::read(cpu)?;
+        let x2apic_id = smt.x2apic_id();
+        Ok(Self {
+            cpu,
+            package: x2apic_id >> core.shift(),
+            core: x2apic_id >> smt.shift(),
+            core_type: native.core_type(),
+        })
+    }
+
+    /// Reads the topology of the CPUs in `0..num_cpus` that are present and
+    /// online, sorted by CPU number. Missing CPUs are skipped, since most
+    /// machines have fewer CPUs than `num_cpus`.
+    pub fn read_present(num_cpus: usize) -> io::Result<Vec<Self>> {
+        let mut topology = Vec::new();
+        for cpu in 0..num_cpus {
+            match Self::read(cpu) {
+                Ok(cpu) => topology.push(cpu),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(topology)
+    }
+}
@@ -0,0 +1,582 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Interactive terminal dashboard
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write as _,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cpuid::CoreType,
+    ehfi::{EhfiTable, NUM_CLASSES},
+    hfi::{HfiInfo, HfiTable},
+    itd::ItdInfo,
+    topology::CpuTopology,
+};
+
+const HISTORY_LEN: usize = 8;
+const CELL_WIDTH: usize = 4 + 4 + HISTORY_LEN + 2;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+const HEATMAP: [u8; 10] = [196, 202, 208, 214, 220, 226, 190, 154, 118, 46];
+const ESC: u8 = 0x1b;
+const CTRL_C: u8 = 0x03;
+/// Time within which the rest of an escape sequence follows its Esc
+const ESCAPE_TIMEOUT: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    Performance,
+    EnergyEfficiency,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum View {
+    Hfi,
+    Class(usize),
+}
+
+/// Capabilities of a CPU as (performance, energy efficiency) pairs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Row {
+    pub hfi: (u8, u8),
+    pub ehfi: Option<[(u8, u8); NUM_CLASSES]>,
+}
+
+impl Row {
+    fn value(&self, view: View, metric: Metric) -> Option<u8> {
+        let (perf, ee) = match view {
+            View::Hfi => self.hfi,
+            View::Class(class) => self.ehfi?[class],
+        };
+        match metric {
+            Metric::Performance => Some(perf),
+            Metric::EnergyEfficiency => Some(ee),
+        }
+    }
+}
+
+/// One reading of the tables and the ITD class of every CPU, by CPU number
+#[derive(Clone, Debug, Default)]
+pub struct Sample {
+    pub timestamp: u64,
+    pub rows: BTreeMap<usize, Row>,
+    /// Valid ITD class IDs
+    pub classes: BTreeMap<usize, usize>,
+}
+
+impl Sample {
+    pub fn read<const NUM_CPUS: usize>(hfi_info: &HfiInfo, has_ehfi: bool) -> io::Result<Self> {
+        let mut hfi = HfiTable::<NUM_CPUS>::new();
+        hfi.read(hfi_info)?;
+        let ehfi = match has_ehfi {
+            true => {
+                let mut table = EhfiTable::<NUM_CPUS>::new();
+                table.read(hfi_info)?;
+                Some(table)
+            }
+            false => None,
+        };
+
+        let mut rows = BTreeMap::new();
+        let mut classes = BTreeMap::new();
+        for cpu in 0..NUM_CPUS {
+            let Some(info) = hfi_info.sibling(cpu)? else {
+                continue;
+            };
+            let entry = &hfi.entries[cpu];
+            rows.insert(
+                cpu,
+                Row {
+                    hfi: (entry.perf_cap(), entry.ee_cap()),
+                    ehfi: ehfi.as_ref().map(|table| {
+                        let entry = &table.entries[cpu];
+                        std::array::from_fn(|class| (entry.perf_cap(class), entry.ee_cap(class)))
+                    }),
+                },
+            );
+            if let Some(class) = has_ehfi.then(|| ItdInfo::new(&info).class_id()).flatten() {
+                classes.insert(cpu, class);
+            }
+        }
+
+        Ok(Self {
+            timestamp: hfi.header.timestamp(),
+            rows,
+            classes,
+        })
+    }
+}
+
+pub struct Dashboard {
+    topology: Vec<CpuTopology>,
+    has_ehfi: bool,
+    metric: Metric,
+    view: View,
+    sample: Option<Sample>,
+    history: BTreeMap<usize, VecDeque<Row>>,
+}
+
+impl Dashboard {
+    pub fn new(topology: Vec<CpuTopology>, has_ehfi: bool) -> Self {
+        Self {
+            topology,
+            has_ehfi,
+            metric: Metric::Performance,
+            view: View::Hfi,
+            sample: None,
+            history: BTreeMap::new(),
+        }
+    }
+
+    /// Records `sample`, extending the history only when the table has changed.
+    pub fn update(&mut self, sample: Sample) {
+        let changed = match &self.sample {
+            Some(prev) => prev.timestamp != sample.timestamp || prev.rows != sample.rows,
+            None => true,
+        };
+        if changed {
+            for (cpu, row) in &sample.rows {
+                let history = self
+                    .history
+                    .entry(*cpu)
+                    .or_insert_with(|| VecDeque::with_capacity(HISTORY_LEN));
+                if history.len() == HISTORY_LEN {
+                    history.pop_front();
+                }
+                history.push_back(*row);
+            }
+        }
+        self.sample = Some(sample);
+    }
+
+    pub fn toggle_metric(&mut self) {
+        self.metric = match self.metric {
+            Metric::Performance => Metric::EnergyEfficiency,
+            Metric::EnergyEfficiency => Metric::Performance,
+        };
+    }
+
+    pub fn next_view(&mut self) {
+        self.view = match self.view {
+            View::Hfi if self.has_ehfi => View::Class(0),
+            View::Class(class) if class + 1 < NUM_CLASSES => View::Class(class + 1),
+            _ => View::Hfi,
+        };
+    }
+
+    fn groups(&self) -> BTreeMap<(u32, CoreType), Vec<usize>> {
+        let mut groups = BTreeMap::<_, Vec<_>>::new();
+        for topology in &self.topology {
+            groups
+                .entry((topology.package, topology.core_type))
+                .or_default()
+                .push(topology.cpu);
+        }
+        groups
+    }
+
+    fn cell(&self, cpu: usize) -> String {
+        let value = self
+            .sample
+            .as_ref()
+            .and_then(|sample| sample.rows.get(&cpu))
+            .and_then(|row| row.value(self.view, self.metric));
+        let mut cell = format!("{cpu:>3} ");
+        match value {
+            Some(value) => {
+                let color = match value {
+                    0 => HEATMAP[0],
+                    _ => HEATMAP[1 + value as usize * (HEATMAP.len() - 1) / 256],
+                };
+                write!(cell, "\x1b[30;48;5;{color}m{value:>3}\x1b[0m ").unwrap();
+            }
+            None => cell.push_str("  - "),
+        }
+        let sparkline: String = self
+            .history
+            .get(&cpu)
+            .into_iter()
+            .flatten()
+            .filter_map(|row| row.value(self.view, self.metric))
+            .map(|value| SPARKS[value as usize * SPARKS.len() / 256])
+            .collect();
+        write!(cell, "{sparkline:<HISTORY_LEN$}  ").unwrap();
+        cell
+    }
+
+    /// Renders the dashboard for a terminal `width` columns wide.
+    pub fn render(&self, width: usize) -> Vec<String> {
+        let per_line = (width / CELL_WIDTH).max(1);
+        let metric = match self.metric {
+            Metric::Performance => "Performance",
+            Metric::EnergyEfficiency => "Energy Efficiency",
+        };
+        let view = match self.view {
+            View::Hfi => "HFI".to_string(),
+            View::Class(class) => format!("EHFI Class #{class}"),
+        };
+        let timestamp = match &self.sample {
+            Some(sample) => sample.timestamp.to_string(),
+            None => "-".to_string(),
+        };
+
+        let mut lines = vec![
+            format!("Timestamp: {timestamp}  Metric: {metric}  View: {view}"),
+            "[m] toggle metric  [v] next view  [q] quit".to_string(),
+        ];
+        let groups = self.groups();
+        for ((package, core_type), cpus) in &groups {
+            lines.push(String::new());
+            lines.push(format!("Package {package} / {core_type:?}:"));
+            for chunk in cpus.chunks(per_line) {
+                lines.push(chunk.iter().map(|cpu| self.cell(*cpu)).collect());
+            }
+        }
+
+        if self.has_ehfi {
+            lines.push(String::new());
+            lines.push("ITD class IDs:".to_string());
+            for ((package, core_type), cpus) in &groups {
+                let classes: Vec<String> = cpus
+                    .iter()
+                    .map(|cpu| {
+                        let class = self
+                            .sample
+                            .as_ref()
+                            .and_then(|sample| sample.classes.get(cpu));
+                        match class {
+                            Some(class) => format!("{cpu}:{class}"),
+                            None => format!("{cpu}:-"),
+                        }
+                    })
+                    .collect();
+                lines.push(format!(
+                    "  Package {package} / {core_type:?}: {}",
+                    classes.join(" ")
+                ));
+            }
+        }
+        lines
+    }
+}
+
+/// Waits up to `timeout` for input on `fd`.
+fn poll_input(fd: libc::c_int, timeout: Duration) -> io::Result<bool> {
+    let mut fds = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ret = unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        return match err.kind() {
+            io::ErrorKind::Interrupted => Ok(false),
+            _ => Err(err),
+        };
+    }
+    Ok(ret > 0)
+}
+
+/// Reads a byte from `fd` without buffering, so that [`poll_input`] sees
+/// whatever follows it.
+fn read_byte(fd: libc::c_int) -> io::Result<u8> {
+    let mut byte = 0u8;
+    match unsafe { libc::read(fd, (&mut byte as *mut u8).cast(), 1) } {
+        1 => Ok(byte),
+        0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Returns the key pressed on `fd` within `timeout`. Escape sequences, such
+/// as those of the arrow keys, are consumed and return `None`, so that only
+/// a bare Esc returns [`ESC`].
+fn read_key(fd: libc::c_int, timeout: Duration) -> io::Result<Option<u8>> {
+    if !poll_input(fd, timeout)? {
+        return Ok(None);
+    }
+    let key = read_byte(fd)?;
+    if key != ESC {
+        return Ok(Some(key));
+    }
+    let mut sequence = false;
+    while poll_input(fd, ESCAPE_TIMEOUT)? {
+        read_byte(fd)?;
+        sequence = true;
+    }
+    Ok((!sequence).then_some(key))
+}
+
+/// Puts the terminal into raw mode on the alternate screen until dropped.
+///
+/// Signal keys are disabled so that Ctrl-C arrives as a key and the terminal
+/// is restored before quitting.
+struct Terminal {
+    termios: libc::termios,
+}
+
+impl Terminal {
+    fn new() -> io::Result<Self> {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = termios;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        print!("\x1b[?1049h\x1b[?25l");
+        io::stdout().flush()?;
+        Ok(Self { termios })
+    }
+
+    fn width(&self) -> usize {
+        let mut size = unsafe { std::mem::zeroed::<libc::winsize>() };
+        match unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } {
+            0 if size.ws_col > 0 => size.ws_col as usize,
+            _ => 80,
+        }
+    }
+
+    fn read_key(&self, timeout: Duration) -> io::Result<Option<u8>> {
+        read_key(libc::STDIN_FILENO, timeout)
+    }
+
+    fn draw(&self, lines: &[String]) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        write!(stdout, "\x1b[H\x1b[2J{}", lines.join("\r\n"))?;
+        stdout.flush()
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.termios) };
+    }
+}
+
+pub fn run<const NUM_CPUS: usize>(hfi_info: &HfiInfo, interval: Duration) -> io::Result<()> {
+    let has_ehfi = hfi_info.has_itd() && ItdInfo::new(hfi_info).itd_enabled();
+    let mut dashboard = Dashboard::new(CpuTopology::read_present(NUM_CPUS)?, has_ehfi);
+
+    let terminal = Terminal::new()?;
+    loop {
+        let deadline = Instant::now() + interval;
+        dashboard.update(Sample::read::<NUM_CPUS>(hfi_info, has_ehfi)?);
+        terminal.draw(&dashboard.render(terminal.width()))?;
+
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            match terminal.read_key(timeout)? {
+                Some(b'q' | ESC | CTRL_C) => return Ok(()),
+                Some(b'm') => dashboard.toggle_metric(),
+                Some(b'v') => dashboard.next_view(),
+                _ => continue,
+            }
+            terminal.draw(&dashboard.render(terminal.width()))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::install_fake;
+
+    fn topology() -> Vec<CpuTopology> {
+        (0..4)
+            .map(|cpu| CpuTopology {
+                cpu,
+                package: 0,
+                core: cpu as u32,
+                core_type: match cpu < 2 {
+                    true => CoreType::Core,
+                    false => CoreType::Atom,
+                },
+            })
+            .collect()
+    }
+
+    fn sample(timestamp: u64, perf: u8) -> Sample {
+        Sample {
+            timestamp,
+            rows: (0..4)
+                .map(|cpu| {
+                    let row = Row {
+                        hfi: (perf - cpu as u8, 100 + cpu as u8),
+                        ehfi: Some([(10, 20), (30, 40), (50, 60), (70, 80)]),
+                    };
+                    (cpu, row)
+                })
+                .collect(),
+            classes: BTreeMap::from([(0, 1), (2, 3), (3, 0)]),
+        }
+    }
+
+    /// Strips the color escape sequences.
+    fn plain(line: &str) -> String {
+        let mut plain = String::new();
+        let mut escape = false;
+        for c in line.chars() {
+            match c {
+                '\x1b' => escape = true,
+                'm' if escape => escape = false,
+                _ if escape => {}
+                c => plain.push(c),
+            }
+        }
+        plain
+    }
+
+    fn render(dashboard: &Dashboard, width: usize) -> Vec<String> {
+        dashboard
+            .render(width)
+            .iter()
+            .map(|line| plain(line))
+            .collect()
+    }
+
+    #[test]
+    fn empty() {
+        let dashboard = Dashboard::new(topology(), false);
+        let lines = render(&dashboard, 80);
+        assert_eq!(lines[0], "Timestamp: -  Metric: Performance  View: HFI");
+        assert_eq!(lines[3], "Package 0 / Atom:");
+        assert!(lines[4].starts_with("  2   - "));
+        assert_eq!(lines[6], "Package 0 / Core:");
+        assert!(!lines.iter().any(|line| line.starts_with("ITD")));
+    }
+
+    #[test]
+    fn values_and_history() {
+        let mut dashboard = Dashboard::new(topology(), false);
+        dashboard.update(sample(1, 200));
+        dashboard.update(sample(1, 200));
+        dashboard.update(sample(2, 250));
+        let lines = render(&dashboard, 80);
+        assert_eq!(lines[0], "Timestamp: 2  Metric: Performance  View: HFI");
+        // CPU 0 went from 200 to 250 with an unchanged sample in between.
+        assert_eq!(lines[7], "  0 250 ▇█          1 249 ▇█        ");
+
+        dashboard.toggle_metric();
+        let lines = render(&dashboard, 80);
+        assert_eq!(
+            lines[0],
+            "Timestamp: 2  Metric: Energy Efficiency  View: HFI"
+        );
+        assert!(lines[7].starts_with("  0 100 ▄▄ "));
+    }
+
+    #[test]
+    fn heatmap() {
+        let mut dashboard = Dashboard::new(topology(), false);
+        dashboard.update(sample(1, 3));
+        let line = &dashboard.render(80)[7];
+        assert!(line.contains(&format!("48;5;{}m  3", HEATMAP[1])));
+        assert!(line.contains(&format!("48;5;{}m  2", HEATMAP[1])));
+    }
+
+    #[test]
+    fn wraps_to_width() {
+        let mut dashboard = Dashboard::new(topology(), false);
+        dashboard.update(sample(1, 200));
+        let lines = render(&dashboard, CELL_WIDTH);
+        assert_eq!(lines[3], "Package 0 / Atom:");
+        assert!(lines[4].starts_with("  2 "));
+        assert!(lines[5].starts_with("  3 "));
+    }
+
+    #[test]
+    fn views() {
+        let mut dashboard = Dashboard::new(topology(), false);
+        dashboard.next_view();
+        assert_eq!(dashboard.view, View::Hfi);
+
+        let mut dashboard = Dashboard::new(topology(), true);
+        dashboard.update(sample(1, 200));
+        for class in 0..NUM_CLASSES {
+            dashboard.next_view();
+            assert_eq!(dashboard.view, View::Class(class));
+        }
+        dashboard.next_view();
+        assert_eq!(dashboard.view, View::Hfi);
+
+        dashboard.next_view();
+        dashboard.next_view();
+        let lines = render(&dashboard, 80);
+        assert_eq!(
+            lines[0],
+            "Timestamp: 1  Metric: Performance  View: EHFI Class #1"
+        );
+        assert!(lines[7].starts_with("  0  30 "));
+        assert_eq!(lines[9], "ITD class IDs:");
+        assert_eq!(lines[10], "  Package 0 / Atom: 2:3 3:0");
+        assert_eq!(lines[11], "  Package 0 / Core: 0:1 1:-");
+    }
+
+    #[test]
+    fn sparse_cpus() {
+        let topology: Vec<CpuTopology> = [0, 2, 5]
+            .into_iter()
+            .map(|cpu| CpuTopology {
+                cpu,
+                package: 0,
+                core: cpu as u32,
+                core_type: CoreType::Core,
+            })
+            .collect();
+        let mut dashboard = Dashboard::new(topology, false);
+        let mut sample = sample(1, 200);
+        sample.rows = BTreeMap::from([(0, sample.rows[&0]), (5, sample.rows[&1])]);
+        dashboard.update(sample);
+        let lines = render(&dashboard, 80);
+        assert_eq!(
+            lines[4],
+            "  0 200 ▇           2   -             5 199 ▇         "
+        );
+    }
+
+    #[test]
+    fn read_present() {
+        install_fake();
+        let info = HfiInfo::new(0).unwrap();
+        let sample = Sample::read::<64>(&info, true).unwrap();
+        assert_eq!(sample.rows.len(), 32);
+        assert_eq!(sample.rows.keys().last(), Some(&31));
+        assert!(sample.classes.keys().all(|cpu| *cpu < 32));
+    }
+
+    #[test]
+    fn keys() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let [input, output] = fds;
+        let send = |bytes: &[u8]| {
+            let written = unsafe { libc::write(output, bytes.as_ptr().cast(), bytes.len()) };
+            assert_eq!(written, bytes.len() as isize);
+        };
+        let timeout = Duration::from_millis(10);
+
+        // An arrow key is consumed as a whole.
+        send(b"m\x1b[A");
+        assert_eq!(read_key(input, timeout).unwrap(), Some(b'm'));
+        assert_eq!(read_key(input, timeout).unwrap(), None);
+        send(b"q");
+        assert_eq!(read_key(input, timeout).unwrap(), Some(b'q'));
+        send(&[ESC]);
+        assert_eq!(read_key(input, timeout).unwrap(), Some(ESC));
+        assert_eq!(read_key(input, timeout).unwrap(), None);
+
+        unsafe {
+            libc::close(input);
+            libc::close(output);
+        }
+    }
+}
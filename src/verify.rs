@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! Cross-check of the `/dev/mem` table against the kernel's capability events
+
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+use crate::watch::TableChange;
+
+/// (performance, energy efficiency) capability of a CPU at a point in time
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sample {
+    /// Time since the start of the collection
+    pub at: Duration,
+    pub cpu: usize,
+    pub cap: (u8, u8),
+}
+
+impl Sample {
+    /// Returns the samples of the CPUs changed in `change`.
+    pub fn from_change(at: Duration, change: &TableChange) -> Vec<Self> {
+        change
+            .cpus
+            .iter()
+            .map(|cpu| Self {
+                at,
+                cpu: cpu.cpu,
+                cap: cpu.new,
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Issue {
+    /// The kernel published a value the table did not have around that time.
+    Mismatch {
+        at: Duration,
+        event: (u8, u8),
+        table: Option<(u8, u8)>,
+    },
+    /// The table changed without a kernel event with the new value.
+    MissingEvent { at: Duration, table: (u8, u8) },
+}
+
+fn format_cap(cap: Option<(u8, u8)>) -> String {
+    cap.map_or_else(
+        || "-".to_string(),
+        |(perf, ee)| format!("perf {perf} ee {ee}"),
+    )
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mismatch { at, event, table } => write!(
+                f,
+                "mismatch at {:.3}s: kernel {}, table {}",
+                at.as_secs_f64(),
+                format_cap(Some(*event)),
+                format_cap(*table)
+            ),
+            Self::MissingEvent { at, table } => write!(
+                f,
+                "no kernel event for table update at {:.3}s to {}",
+                at.as_secs_f64(),
+                format_cap(Some(*table))
+            ),
+        }
+    }
+}
+
+/// Result of the cross-check of a CPU
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuReport {
+    /// Delay of every matched kernel event after the table update, negative
+    /// if the event came first, in milliseconds
+    pub skews: Vec<i64>,
+    pub issues: Vec<Issue>,
+}
+
+impl CpuReport {
+    pub fn max_skew(&self) -> Option<i64> {
+        self.skews.iter().copied().max_by_key(|skew| skew.abs())
+    }
+
+    pub fn mean_skew(&self) -> Option<i64> {
+        let len = self.skews.len() as i64;
+        (len > 0).then(|| self.skews.iter().sum::<i64>() / len)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub cpus: BTreeMap<usize, CpuReport>,
+}
+
+impl Report {
+    pub fn has_issues(&self) -> bool {
+        self.cpus.values().any(|cpu| !cpu.issues.is_empty())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.cpus.is_empty() {
+            return write!(f, "no table updates or kernel events");
+        }
+        let mut first = true;
+        for (cpu, report) in &self.cpus {
+            if !first {
+                writeln!(f)?;
+            }
+            first = false;
+            write!(f, "CPU {cpu}: {} matched", report.skews.len())?;
+            if let (Some(mean), Some(max)) = (report.mean_skew(), report.max_skew()) {
+                write!(f, ", skew mean {mean}ms max {max}ms")?;
+            }
+            for issue in &report.issues {
+                write!(f, "\n  {issue}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Table values of a CPU in time order
+struct History<'a>(Vec<&'a Sample>);
+
+impl History<'_> {
+    fn value_at(&self, at: Duration) -> Option<(u8, u8)> {
+        self.0
+            .iter()
+            .take_while(|sample| sample.at <= at)
+            .last()
+            .map(|sample| sample.cap)
+    }
+
+    /// Returns the time the table took `cap` closest to `at`, also counting
+    /// the value it already had at the start of the window.
+    fn find(&self, cap: (u8, u8), at: Duration, max_skew: Duration) -> Option<Duration> {
+        let start = at.saturating_sub(max_skew);
+        let mut candidates: Vec<Duration> = self
+            .0
+            .iter()
+            .filter(|sample| sample.cap == cap && sample.at >= start && sample.at <= at + max_skew)
+            .map(|sample| sample.at)
+            .collect();
+        if let Some(sample) = self.0.iter().take_while(|sample| sample.at <= start).last() {
+            if sample.cap == cap {
+                candidates.push(sample.at);
+            }
+        }
+        candidates.into_iter().min_by_key(|time| time.abs_diff(at))
+    }
+}
+
+fn skew(table: Duration, event: Duration) -> i64 {
+    match event >= table {
+        true => (event - table).as_millis() as i64,
+        false => -((table - event).as_millis() as i64),
+    }
+}
+
+/// Compares the table samples with the kernel events.
+///
+/// `table` starts with the initial value of every CPU, which is not expected
+/// to have an event. An event matches if the table had the same value within
+/// `max_skew`, and every later table update needs an event within `max_skew`.
+pub fn verify(table: &[Sample], events: &[Sample], max_skew: Duration) -> Report {
+    let mut report = Report::default();
+    let mut histories = BTreeMap::<usize, History>::new();
+    for sample in table {
+        histories
+            .entry(sample.cpu)
+            .or_insert_with(|| History(Vec::new()))
+            .0
+            .push(sample);
+    }
+    for history in histories.values_mut() {
+        history.0.sort_by_key(|sample| sample.at);
+    }
+    let empty = History(Vec::new());
+
+    for event in events {
+        let history = histories.get(&event.cpu).unwrap_or(&empty);
+        let cpu = report.cpus.entry(event.cpu).or_default();
+        match history.find(event.cap, event.at, max_skew) {
+            Some(at) => cpu.skews.push(skew(at, event.at)),
+            None => cpu.issues.push(Issue::Mismatch {
+                at: event.at,
+                event: event.cap,
+                table: history.value_at(event.at),
+            }),
+        }
+    }
+
+    for (cpu, history) in &histories {
+        let report = report.cpus.entry(*cpu).or_default();
+        for sample in history.0.iter().skip(1) {
+            let matched = events.iter().any(|event| {
+                event.cpu == *cpu
+                    && event.cap == sample.cap
+                    && event.at.abs_diff(sample.at) <= max_skew
+            });
+            if !matched {
+                report.issues.push(Issue::MissingEvent {
+                    at: sample.at,
+                    table: sample.cap,
+                });
+            }
+        }
+    }
+    for cpu in report.cpus.values_mut() {
+        cpu.issues.sort_by_key(|issue| match issue {
+            Issue::Mismatch { at, .. } | Issue::MissingEvent { at, .. } => *at,
+        });
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_SKEW: Duration = Duration::from_millis(100);
+
+    fn sample(ms: u64, cpu: usize, cap: (u8, u8)) -> Sample {
+        Sample {
+            at: Duration::from_millis(ms),
+            cpu,
+            cap,
+        }
+    }
+
+    fn table() -> Vec<Sample> {
+        vec![
+            sample(0, 0, (100, 100)),
+            sample(1000, 0, (120, 100)),
+            sample(2000, 0, (130, 100)),
+            sample(3000, 0, (140, 100)),
+        ]
+    }
+
+    #[test]
+    fn matching() {
+        let events = [
+            sample(1050, 0, (120, 100)),
+            sample(1980, 0, (130, 100)),
+            sample(3000, 0, (140, 100)),
+        ];
+        let report = verify(&table(), &events, MAX_SKEW);
+        assert!(!report.has_issues());
+        assert_eq!(report.cpus[&0].skews, [50, -20, 0]);
+
+        // The value the table already had counts.
+        let report = verify(&table()[..1], &[sample(500, 0, (100, 100))], MAX_SKEW);
+        assert_eq!(report.cpus[&0].skews, [500]);
+    }
+
+    #[test]
+    fn skew() {
+        let report = CpuReport {
+            skews: vec![50, -80, 10],
+            issues: Vec::new(),
+        };
+        assert_eq!(report.max_skew(), Some(-80));
+        assert_eq!(report.mean_skew(), Some(-6));
+        assert_eq!(CpuReport::default().max_skew(), None);
+        assert_eq!(CpuReport::default().mean_skew(), None);
+        assert_eq!(super::skew(Duration::from_millis(5), Duration::ZERO), -5);
+    }
+
+    #[test]
+    fn issues() {
+        let events = [
+            // Too late for the update at 1000ms, but the table still had
+            // the value at the start of the window.
+            sample(1200, 0, (120, 100)),
+            sample(1980, 0, (130, 100)),
+            sample(2500, 0, (99, 99)),
+            sample(200, 1, (1, 1)),
+        ];
+        let report = verify(&table(), &events, MAX_SKEW);
+        assert!(report.has_issues());
+        assert_eq!(report.cpus[&0].skews, [200, -20]);
+        assert_eq!(
+            report.cpus[&0].issues,
+            [
+                Issue::MissingEvent {
+                    at: Duration::from_millis(1000),
+                    table: (120, 100),
+                },
+                Issue::Mismatch {
+                    at: Duration::from_millis(2500),
+                    event: (99, 99),
+                    table: Some((130, 100)),
+                },
+                Issue::MissingEvent {
+                    at: Duration::from_millis(3000),
+                    table: (140, 100),
+                },
+            ]
+        );
+        assert_eq!(
+            report.to_string(),
+            "CPU 0: 2 matched, skew mean 90ms max 200ms\n  \
+             no kernel event for table update at 1.000s to perf 120 ee 100\n  \
+             mismatch at 2.500s: kernel perf 99 ee 99, table perf 130 ee 100\n  \
+             no kernel event for table update at 3.000s to perf 140 ee 100\n\
+             CPU 1: 0 matched\n  \
+             mismatch at 0.200s: kernel perf 1 ee 1, table -"
+        );
+        assert_eq!(
+            Report::default().to_string(),
+            "no table updates or kernel events"
+        );
+    }
+}
@@ -0,0 +1,329 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! vCPU pinning plans for virtual machines
+
+use std::{collections::BTreeMap, fmt, io, str::FromStr};
+
+use crate::{
+    advise::{self, Capability, Objective, Smt},
+    cpuid::CoreType,
+    cpulist,
+    topology::CpuTopology,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Policy {
+    /// Use the most performant cores.
+    #[default]
+    PerfFirst,
+    /// Use the most efficient cores.
+    EfficiencyFirst,
+    /// Split the vCPUs between Core and Atom CPUs like the host.
+    Mirror,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "perf-first" => Ok(Self::PerfFirst),
+            "efficiency-first" | "ee-first" => Ok(Self::EfficiencyFirst),
+            "mirror" => Ok(Self::Mirror),
+            _ => Err(format!("invalid policy: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PerfFirst => write!(f, "perf-first"),
+            Self::EfficiencyFirst => write!(f, "efficiency-first"),
+            Self::Mirror => write!(f, "mirror"),
+        }
+    }
+}
+
+/// Host CPUs of vCPUs, indexed by vCPU number
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Plan {
+    pub policy: Policy,
+    pub cpus: Vec<usize>,
+    /// Threads per core of the guest topology
+    pub threads: usize,
+}
+
+/// Ranks the usable CPUs of `core_type`, or all of them, with SMT siblings
+/// next to each other.
+fn ranked(
+    capabilities: &[Capability],
+    topology: &[CpuTopology],
+    core_type: Option<CoreType>,
+    objective: Objective,
+) -> Vec<usize> {
+    let capabilities: Vec<Capability> = capabilities
+        .iter()
+        .filter(|c| {
+            core_type.is_none_or(|core_type| {
+                topology
+                    .iter()
+                    .any(|t| t.cpu == c.cpu && t.core_type == core_type)
+            })
+        })
+        .copied()
+        .collect();
+    let advice = advise::rank(&capabilities, topology, objective, Smt::Pack);
+    advice.best(advice.ranked.len())
+}
+
+/// Returns the host core of `cpu`. CPUs missing from `topology` are treated
+/// as separate cores.
+fn core_of(cpu: usize, topology: &[CpuTopology]) -> (u32, u32, usize) {
+    match topology.iter().find(|t| t.cpu == cpu) {
+        Some(topology) => (topology.package, topology.core, 0),
+        None => (u32::MAX, u32::MAX, cpu),
+    }
+}
+
+/// Takes up to `count` of the ranked `cpus` a whole core at a time, skipping
+/// cores whose usable threads do not fit anymore, so that no host core is
+/// split between used and unused threads.
+fn take_cores(cpus: &[usize], topology: &[CpuTopology], count: usize) -> Vec<usize> {
+    let mut cores = Vec::<((u32, u32, usize), Vec<usize>)>::new();
+    for cpu in cpus {
+        let core = core_of(*cpu, topology);
+        match cores.iter_mut().find(|(key, _)| *key == core) {
+            Some((_, threads)) => threads.push(*cpu),
+            None => cores.push((core, vec![*cpu])),
+        }
+    }
+    let mut taken = Vec::new();
+    for (_, threads) in cores {
+        if taken.len() + threads.len() <= count {
+            taken.extend(threads);
+        }
+    }
+    taken
+}
+
+/// Guest threads per core: the number of host threads used of every core if
+/// it is the same for all of them, and 1 otherwise, e.g. when Core and Atom
+/// CPUs are mixed.
+fn threads(cpus: &[usize], topology: &[CpuTopology]) -> usize {
+    let mut cores = BTreeMap::<(u32, u32, usize), usize>::new();
+    for cpu in cpus {
+        *cores.entry(core_of(*cpu, topology)).or_default() += 1;
+    }
+    let mut counts = cores.values();
+    match counts.next() {
+        Some(&threads) if counts.all(|count| *count == threads) => threads,
+        _ => 1,
+    }
+}
+
+/// Pins `vcpus` vCPUs to host CPUs according to `policy`, filling whole
+/// cores so that guest SMT siblings share a host core. CPUs with zero
+/// capability are never used, and a plan that would split a host core
+/// between the guest and the rest of the host is rejected.
+pub fn plan(
+    vcpus: usize,
+    capabilities: &[Capability],
+    topology: &[CpuTopology],
+    policy: Policy,
+) -> io::Result<Plan> {
+    let (usable, cpus) = match policy {
+        Policy::PerfFirst => {
+            let cpus = ranked(capabilities, topology, None, Objective::Performance);
+            (cpus.len(), take_cores(&cpus, topology, vcpus))
+        }
+        Policy::EfficiencyFirst => {
+            let cpus = ranked(capabilities, topology, None, Objective::Efficiency);
+            (cpus.len(), take_cores(&cpus, topology, vcpus))
+        }
+        Policy::Mirror => {
+            let core = ranked(
+                capabilities,
+                topology,
+                Some(CoreType::Core),
+                Objective::Performance,
+            );
+            let atom = ranked(
+                capabilities,
+                topology,
+                Some(CoreType::Atom),
+                Objective::Efficiency,
+            );
+            let total = core.len() + atom.len();
+            let num_core = match total {
+                0 => 0,
+                _ => (vcpus * core.len() + total / 2) / total,
+            };
+            // Atom CPUs make up for a Core core that does not fit.
+            let mut cpus = take_cores(&core, topology, num_core);
+            cpus.extend(take_cores(&atom, topology, vcpus - cpus.len()));
+            (total, cpus)
+        }
+    };
+    if usable < vcpus {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{vcpus} vCPUs requested but only {usable} usable CPUs for {policy}"),
+        ));
+    }
+    if cpus.len() < vcpus {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{vcpus} vCPUs cannot be pinned to whole cores for {policy}; try {} vCPUs",
+                cpus.len()
+            ),
+        ));
+    }
+    let threads = threads(&cpus, topology);
+    Ok(Plan {
+        policy,
+        cpus,
+        threads,
+    })
+}
+
+impl Plan {
+    /// libvirt domain XML elements for the plan
+    pub fn libvirt(&self) -> String {
+        let mut xml = format!(
+            "<vcpu placement='static'>{}</vcpu>\n<cputune>\n",
+            self.cpus.len()
+        );
+        for (vcpu, cpu) in self.cpus.iter().enumerate() {
+            xml += &format!("  <vcpupin vcpu='{vcpu}' cpuset='{cpu}'/>\n");
+        }
+        let mut all = self.cpus.clone();
+        all.sort_unstable();
+        xml += &format!(
+            "  <emulatorpin cpuset='{}'/>\n</cputune>\n",
+            cpulist::format(&all)
+        );
+        xml += &format!(
+            "<cpu mode='host-passthrough'>\n  <topology sockets='1' dies='1' cores='{}' threads='{}'/>\n</cpu>\n",
+            self.cpus.len() / self.threads,
+            self.threads
+        );
+        xml
+    }
+
+    /// QEMU options and the affinity of the vCPU threads for the plan
+    pub fn qemu(&self) -> String {
+        let mut hints = format!(
+            "-smp {},sockets=1,cores={},threads={}\n",
+            self.cpus.len(),
+            self.cpus.len() / self.threads,
+            self.threads
+        );
+        hints += "# Pin the vCPU threads listed by `info cpus` in the QEMU monitor:\n";
+        for (vcpu, cpu) in self.cpus.iter().enumerate() {
+            hints += &format!("taskset -pc {cpu} <thread_id of vCPU {vcpu}>\n");
+        }
+        hints
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities() -> Vec<Capability> {
+        [
+            (255, 100),
+            (250, 100),
+            (200, 110),
+            (190, 110),
+            (120, 200),
+            (110, 210),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(cpu, (perf, ee))| Capability {
+            cpu,
+            perf: *perf,
+            ee: *ee,
+        })
+        .collect()
+    }
+
+    /// CPU 0-3 are two Core cores with two threads each, CPU 4-5 are Atom cores.
+    fn topology() -> Vec<CpuTopology> {
+        (0..6)
+            .map(|cpu| CpuTopology {
+                cpu,
+                package: 0,
+                core: match cpu < 4 {
+                    true => cpu as u32 / 2,
+                    false => cpu as u32,
+                },
+                core_type: match cpu < 4 {
+                    true => CoreType::Core,
+                    false => CoreType::Atom,
+                },
+            })
+            .collect()
+    }
+
+    fn plan_for(vcpus: usize, policy: Policy) -> io::Result<Plan> {
+        plan(vcpus, &capabilities(), &topology(), policy)
+    }
+
+    #[test]
+    fn whole_cores() {
+        let plan = plan_for(4, Policy::PerfFirst).unwrap();
+        assert_eq!((&plan.cpus[..], plan.threads), (&[0, 1, 2, 3][..], 2));
+        assert_eq!(
+            plan.libvirt(),
+            "<vcpu placement='static'>4</vcpu>\n\
+             <cputune>\n  \
+               <vcpupin vcpu='0' cpuset='0'/>\n  \
+               <vcpupin vcpu='1' cpuset='1'/>\n  \
+               <vcpupin vcpu='2' cpuset='2'/>\n  \
+               <vcpupin vcpu='3' cpuset='3'/>\n  \
+               <emulatorpin cpuset='0-3'/>\n\
+             </cputune>\n\
+             <cpu mode='host-passthrough'>\n  \
+               <topology sockets='1' dies='1' cores='2' threads='2'/>\n\
+             </cpu>\n"
+        );
+    }
+
+    #[test]
+    fn skips_split_cores() {
+        // CPU 2 would leave its sibling CPU 3 to the host.
+        let plan = plan_for(3, Policy::PerfFirst).unwrap();
+        assert_eq!((plan.cpus, plan.threads), (vec![0, 1, 4], 1));
+        let plan = plan_for(2, Policy::EfficiencyFirst).unwrap();
+        assert_eq!((plan.cpus, plan.threads), (vec![5, 4], 1));
+    }
+
+    #[test]
+    fn mirror() {
+        let plan = plan_for(3, Policy::Mirror).unwrap();
+        assert_eq!(
+            plan.qemu(),
+            "-smp 3,sockets=1,cores=3,threads=1\n\
+             # Pin the vCPU threads listed by `info cpus` in the QEMU monitor:\n\
+             taskset -pc 0 <thread_id of vCPU 0>\n\
+             taskset -pc 1 <thread_id of vCPU 1>\n\
+             taskset -pc 5 <thread_id of vCPU 2>\n"
+        );
+        // Three Core CPUs would split a core, and two Atom CPUs do not make
+        // up for it.
+        let err = plan_for(5, Policy::Mirror).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("try 4 vCPUs"), "{err}");
+    }
+
+    #[test]
+    fn too_many_vcpus() {
+        let err = plan_for(7, Policy::PerfFirst).unwrap_err();
+        assert!(err.to_string().contains("only 6 usable CPUs"), "{err}");
+    }
+}
@@ -0,0 +1,224 @@
+// SPDX-License-Identifier: MIT
+// Copyright (C) 2023 Akira Moroo
+
+//! HFI table change detection
+
+use std::{fmt, io};
+
+use crate::hfi::{HfiInfo, HfiSignal, HfiTable};
+
+/// Change of the (performance, energy efficiency) capability of a CPU
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuChange {
+    pub cpu: usize,
+    pub old: Option<(u8, u8)>,
+    pub new: (u8, u8),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TableChange {
+    pub previous: Option<u64>,
+    pub timestamp: u64,
+    pub cpus: Vec<CpuChange>,
+    /// Whether the HFI change status bit of the package was set when the
+    /// update was seen
+    pub signalled: bool,
+}
+
+impl fmt::Display for CpuChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (perf, ee) = self.new;
+        match self.old {
+            Some((old_perf, old_ee)) => write!(
+                f,
+                "CPU {} perf {old_perf}->{perf} ee {old_ee}->{ee}",
+                self.cpu
+            ),
+            None => write!(f, "CPU {} perf {perf} ee {ee}", self.cpu),
+        }
+    }
+}
+
+impl TableChange {
+    /// Describes the update without the changed CPUs.
+    pub fn header(&self) -> String {
+        let header = match self.previous {
+            Some(previous) if previous == self.timestamp => {
+                format!("timestamp {previous} unchanged")
+            }
+            Some(previous) => format!("timestamp {previous} -> {}", self.timestamp),
+            None => return format!("initial table (timestamp {})", self.timestamp),
+        };
+        match self.signalled {
+            true => format!("{header}, change status set"),
+            false => header,
+        }
+    }
+}
+
+impl fmt::Display for TableChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.header())?;
+        if self.previous.is_none() {
+            return Ok(());
+        }
+        if self.cpus.is_empty() {
+            return write!(f, ", no capability changed");
+        }
+        for change in &self.cpus {
+            write!(f, ", {change}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Polls the HFI table and reports updates by the hardware.
+pub struct TableWatcher<const NUM_CPUS: usize> {
+    timestamp: Option<u64>,
+    rows: [(u8, u8); NUM_CPUS],
+    signalled: bool,
+}
+
+impl<const NUM_CPUS: usize> TableWatcher<NUM_CPUS> {
+    pub fn new() -> Self {
+        Self {
+            timestamp: None,
+            rows: [(0, 0); NUM_CPUS],
+            signalled: false,
+        }
+    }
+
+    /// Reads the table and returns the change since the last call, or `None`
+    /// if the table has not been updated. The first call always reports the
+    /// initial table.
+    ///
+    /// Besides a new timestamp, the HFI change status bit of the package
+    /// becoming set counts as an update, in case the table is read before the
+    /// timestamp is written. The bit stays set until it is cleared, so only
+    /// its rising edge counts; see [`HfiSignal`].
+    pub fn poll(&mut self, hfi_info: &HfiInfo) -> io::Result<Option<TableChange>> {
+        // Reading the status is best effort, it is not available everywhere.
+        let signalled = HfiSignal::read(hfi_info.cpu).is_ok_and(|signal| signal.change);
+        let mut table = HfiTable::<NUM_CPUS>::new();
+        table.read(hfi_info)?;
+        Ok(self.update(&table, signalled))
+    }
+
+    /// Returns the change from the last table to `table`, or `None` if it
+    /// has the same timestamp and the change status bit did not become set.
+    pub fn update(&mut self, table: &HfiTable<NUM_CPUS>, signalled: bool) -> Option<TableChange> {
+        let raised = signalled && !self.signalled;
+        self.signalled = signalled;
+        let timestamp = table.header.timestamp();
+        if self.timestamp == Some(timestamp) && !raised {
+            return None;
+        }
+
+        let entries = table.entries;
+        let mut cpus = Vec::new();
+        for (cpu, entry) in entries.iter().enumerate() {
+            let new = (entry.perf_cap(), entry.ee_cap());
+            let old = self.timestamp.map(|_| self.rows[cpu]);
+            if old != Some(new) {
+                cpus.push(CpuChange { cpu, old, new });
+            }
+            self.rows[cpu] = new;
+        }
+        let change = TableChange {
+            previous: self.timestamp,
+            timestamp,
+            cpus,
+            signalled,
+        };
+        self.timestamp = Some(timestamp);
+        Some(change)
+    }
+}
+
+impl<const NUM_CPUS: usize> Default for TableWatcher<NUM_CPUS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hfi::{HfiEntry, HfiHeader};
+
+    fn table(timestamp: u64, rows: [(u8, u8); 2]) -> HfiTable<2> {
+        HfiTable {
+            header: HfiHeader::new(timestamp, false, false),
+            entries: rows.map(|(perf, ee)| HfiEntry::new(perf, ee)),
+        }
+    }
+
+    #[test]
+    fn updates() {
+        let mut watcher = TableWatcher::<2>::new();
+        let change = watcher
+            .update(&table(10, [(200, 100), (100, 200)]), false)
+            .unwrap();
+        assert_eq!(change.to_string(), "initial table (timestamp 10)");
+        assert_eq!(change.cpus.len(), 2);
+        assert_eq!(change.cpus[1].old, None);
+
+        let change = watcher
+            .update(&table(11, [(200, 100), (90, 200)]), false)
+            .unwrap();
+        assert_eq!(
+            change.cpus,
+            [CpuChange {
+                cpu: 1,
+                old: Some((100, 200)),
+                new: (90, 200),
+            }]
+        );
+        assert_eq!(
+            change.to_string(),
+            "timestamp 10 -> 11, CPU 1 perf 100->90 ee 200->200"
+        );
+
+        let change = watcher
+            .update(&table(12, [(200, 100), (90, 200)]), true)
+            .unwrap();
+        assert_eq!(
+            change.to_string(),
+            "timestamp 11 -> 12, change status set, no capability changed"
+        );
+    }
+
+    #[test]
+    fn sticky_change_status() {
+        let mut watcher = TableWatcher::<2>::new();
+        watcher.update(&table(10, [(200, 100), (100, 200)]), true);
+        // The change status stays set without a new timestamp.
+        assert_eq!(
+            watcher.update(&table(10, [(200, 100), (100, 200)]), true),
+            None
+        );
+        assert_eq!(
+            watcher.update(&table(10, [(200, 100), (100, 200)]), false),
+            None
+        );
+
+        // The status becoming set is an update before the timestamp changes.
+        let change = watcher
+            .update(&table(10, [(180, 100), (100, 200)]), true)
+            .unwrap();
+        assert_eq!(
+            change.to_string(),
+            "timestamp 10 unchanged, change status set, CPU 0 perf 200->180 ee 100->100"
+        );
+        assert_eq!(
+            watcher.update(&table(10, [(180, 100), (100, 200)]), true),
+            None
+        );
+        // The new timestamp is still reported once it is written.
+        let change = watcher
+            .update(&table(11, [(180, 100), (100, 200)]), true)
+            .unwrap();
+        assert_eq!(change.header(), "timestamp 10 -> 11, change status set");
+        assert!(change.cpus.is_empty());
+    }
+}